@@ -0,0 +1,220 @@
+//! Clocks used by histograms to measure spans.
+//!
+//! Every histogram reads the global clock (see [`set_clock`]) unless it has been created with its own clock
+//! using [`Histogram::with_clock`](crate::Histogram::with_clock). The global clock defaults to [`TscClock`] with the
+//! `rdtsc` feature and to [`InstantClock`] otherwise.
+//!
+//! ## Examples
+//!
+//! Measure spans deterministically in tests.
+//!
+//! ```
+//! use metricus::{Histogram, HistogramOps};
+//! use metricus::clock::{set_clock, MockClock};
+//! use std::time::Duration;
+//!
+//! let clock = MockClock::new();
+//! set_clock(clock.clone());
+//!
+//! let histogram = Histogram::new("task_duration", &[]);
+//! let span = histogram.span();
+//! clock.advance(Duration::from_micros(5));
+//! assert_eq!(5_000, span.stop());
+//! ```
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+/// Source of time for measuring spans. The raw value does not need to be in nanoseconds, as long as the clock
+/// can convert the difference between two raw values into nanoseconds.
+pub trait Clock: Send + Sync + Debug {
+    /// Current raw time of the clock.
+    fn raw(&self) -> u64;
+
+    /// Converts the difference between two raw times into nanoseconds.
+    fn delta_as_nanos(&self, start: u64, end: u64) -> u64 {
+        end.saturating_sub(start)
+    }
+}
+
+#[cfg(feature = "rdtsc")]
+type DefaultClock = TscClock;
+#[cfg(not(feature = "rdtsc"))]
+type DefaultClock = InstantClock;
+
+static DEFAULT_CLOCK: &dyn Clock = &DefaultClock {};
+
+/// Clock set with [`set_clock`], null while the default clock is used so that it can be read without dynamic
+/// dispatch.
+static CLOCK: AtomicPtr<Option<&'static dyn Clock>> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Set the clock used by all histograms that have not been created with their own clock.
+///
+/// The clock is leaked, as other threads may still be reading the previous one, so this is meant to be called once
+/// at startup rather than repeatedly.
+pub fn set_clock<C: Clock + 'static>(clock: C) {
+    // the clock and the reference to it share a single allocation
+    let (installed, clock) = Box::leak(Box::new((None, clock)));
+    *installed = Some(clock as &'static dyn Clock);
+    CLOCK.store(installed, Ordering::Release);
+}
+
+/// Get the global clock.
+#[inline]
+pub fn get_clock() -> &'static dyn Clock {
+    custom_clock().unwrap_or(DEFAULT_CLOCK)
+}
+
+#[inline]
+fn custom_clock() -> Option<&'static dyn Clock> {
+    unsafe { CLOCK.load(Ordering::Acquire).as_ref() }.copied().flatten()
+}
+
+/// Current raw time of the global clock.
+#[inline]
+pub(crate) fn now() -> u64 {
+    match custom_clock() {
+        Some(clock) => clock.raw(),
+        None => DefaultClock {}.raw(),
+    }
+}
+
+/// Nanoseconds elapsed on the global clock since the given raw time.
+#[inline]
+pub(crate) fn elapsed_ns(start: u64) -> u64 {
+    match custom_clock() {
+        Some(clock) => clock.delta_as_nanos(start, clock.raw()),
+        None => DefaultClock {}.delta_as_nanos(start, DefaultClock {}.raw()),
+    }
+}
+
+/// Point in time captured on one thread, that can be passed along with a message (it is `Copy` and `Send`) and used
+/// to measure latency on another thread with [`HistogramOps::record_since`](crate::HistogramOps::record_since).
+/// The timestamp remembers the clock it was captured with, so that the elapsed time is always converted correctly.
+///
+/// ## Examples
+///
+/// ```no_run
+/// use metricus::{Histogram, HistogramOps};
+/// use metricus::clock::Timestamp;
+///
+/// let received_at = Timestamp::now();
+/// std::thread::spawn(move || {
+///     let histogram = Histogram::new("md_to_order", &[]);
+///     // ...
+///     histogram.record_since(received_at);
+/// });
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp {
+    raw: u64,
+    clock: &'static dyn Clock,
+}
+
+impl Timestamp {
+    /// Captures the current time of the global clock.
+    #[inline]
+    pub fn now() -> Self {
+        Self::with_clock(get_clock())
+    }
+
+    /// Captures the current time of the given clock.
+    #[inline]
+    pub fn with_clock(clock: &'static dyn Clock) -> Self {
+        Self {
+            raw: clock.raw(),
+            clock,
+        }
+    }
+
+    /// Nanoseconds elapsed since the timestamp has been captured.
+    #[inline]
+    pub fn elapsed_ns(&self) -> u64 {
+        self.clock.delta_as_nanos(self.raw, self.clock.raw())
+    }
+
+    /// Raw time of the clock the timestamp has been captured with.
+    pub const fn raw(&self) -> u64 {
+        self.raw
+    }
+}
+
+/// Time stamp counter based clock. All instances share a single calibration.
+#[cfg(feature = "rdtsc")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TscClock;
+
+#[cfg(feature = "rdtsc")]
+static TSC: LazyLock<quanta::Clock> = LazyLock::new(quanta::Clock::new);
+
+#[cfg(feature = "rdtsc")]
+impl Clock for TscClock {
+    #[inline]
+    fn raw(&self) -> u64 {
+        TSC.raw()
+    }
+
+    #[inline]
+    fn delta_as_nanos(&self, start: u64, end: u64) -> u64 {
+        TSC.delta_as_nanos(start, end)
+    }
+}
+
+/// Clock based on [`std::time::Instant`], in nanoseconds since the first time it was read.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InstantClock;
+
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+impl Clock for InstantClock {
+    #[inline]
+    fn raw(&self) -> u64 {
+        EPOCH.elapsed().as_nanos() as u64
+    }
+}
+
+/// Clock based on `CLOCK_MONOTONIC_RAW`, which is not subject to NTP adjustments.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MonotonicRawClock;
+
+#[cfg(target_os = "linux")]
+impl Clock for MonotonicRawClock {
+    #[inline]
+    fn raw(&self) -> u64 {
+        let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_RAW, &mut ts) };
+        ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+    }
+}
+
+/// Manually advanced clock, in nanoseconds. Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    now: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Moves the clock forward by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        self.now.fetch_add(duration.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    /// Sets the current time of the clock in nanoseconds.
+    pub fn set(&self, nanos: u64) {
+        self.now.store(nanos, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    #[inline]
+    fn raw(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
@@ -1,9 +1,10 @@
 //! A `Counter` proxy struct for managing a metrics counter.
 
-use crate::access::get_metrics_mut;
-use crate::{Id, Tags};
+use crate::access::{self, Scope};
+use crate::{Id, MetricKind, Tags, registry};
 use std::cell::{LazyCell, UnsafeCell};
 use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 /// Provides methods to create a new counter, increment it, and
 /// increment it by a specified amount. It automatically deletes the counter
@@ -39,6 +40,17 @@ use std::sync::LazyLock;
 #[derive(Debug)]
 pub struct Counter {
     id: Id,
+    scope: Scope,
+    /// Incremented directly, without calling the backend, while the backend it belongs to is installed.
+    slot: Option<CounterSlot>,
+}
+
+/// Slot holding the running total of a counter in memory shared with the backend, see [`crate::Metrics::counter_slot`].
+#[derive(Debug)]
+pub(crate) struct CounterSlot {
+    pub(crate) value: &'static AtomicU64,
+    /// Address of the global backend the slot belongs to.
+    pub(crate) backend: usize,
 }
 
 impl Counter {
@@ -61,8 +73,16 @@ impl Counter {
     /// let counter = Counter::new("user_count", empty_tags());
     /// ```
     pub fn new(name: &str, tags: Tags) -> Self {
-        let counter_id = get_metrics_mut().new_counter(name, tags);
-        Self { id: counter_id }
+        let (counter_id, scope) = registry::register(MetricKind::Counter, name, tags, 1);
+        let slot = match scope {
+            Scope::Global => registry::counter_slot(counter_id),
+            Scope::Thread(_) => None,
+        };
+        Self {
+            id: counter_id,
+            scope,
+            slot,
+        }
     }
 
     /// Create a counter object without registering it.
@@ -73,18 +93,22 @@ impl Counter {
     /// Create a counter with specific id.
     ///
     /// ```no_run
-    /// use metricus::Counter;
+    /// use metricus::{Counter, RESERVED_IDS};
     ///
-    /// let counter = Counter::new_with_id(1);
+    /// let counter = Counter::new_with_id(RESERVED_IDS.start() + 1);
     /// ```
     pub fn new_with_id(id: Id) -> Self {
-        Self { id }
+        Self {
+            id,
+            scope: Scope::Global,
+            slot: None,
+        }
     }
 }
 
 impl Drop for Counter {
     fn drop(&mut self) {
-        get_metrics_mut().delete_counter(self.id);
+        registry::unregister(MetricKind::Counter, self.id, self.scope);
     }
 }
 
@@ -116,12 +140,24 @@ pub trait CounterOps {
 }
 
 impl CounterOps for Counter {
+    #[inline]
     fn increment(&self) {
-        get_metrics_mut().increment_counter(self.id);
+        match &self.slot {
+            Some(slot) if slot.backend == access::global_metrics_addr() => {
+                slot.value.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => access::with_metrics(self.scope, |metrics| metrics.increment_counter(self.id)),
+        }
     }
 
+    #[inline]
     fn increment_by(&self, delta: u64) {
-        get_metrics_mut().increment_counter_by(self.id, delta);
+        match &self.slot {
+            Some(slot) if slot.backend == access::global_metrics_addr() => {
+                slot.value.fetch_add(delta, Ordering::Relaxed);
+            }
+            _ => access::with_metrics(self.scope, |metrics| metrics.increment_counter_by(self.id, delta)),
+        }
     }
 }
 
@@ -0,0 +1,327 @@
+//! Bridge between metricus and the [`metrics`] crate facade.
+//!
+//! [`MetricusRecorder`] is a [`metrics::Recorder`] that routes the `counter!`, `gauge!` and `histogram!` macros
+//! used by third party libraries through the active metricus backend. The metric key name becomes the measurement
+//! name and its labels become the tags. Handles are cached per key, so each distinct key is registered only once.
+//! Metricus has no gauges, so the recorder reports each gauge as a pair of counters counting its increases and
+//! decreases.
+//!
+//! [`MetricsFacade`] goes the other way, it is a metricus backend that forwards all counters and histograms to the
+//! installed [`metrics`] recorder. The two must not be installed at the same time, as every update would loop
+//! between them.
+//!
+//! ## Examples
+//!
+//! ```no_run
+//! use metricus::facade::MetricusRecorder;
+//!
+//! // report durations given in seconds as nanoseconds
+//! metrics::set_global_recorder(MetricusRecorder::new().with_scale(1e9)).unwrap();
+//!
+//! metrics::counter!("http_requests", "method" => "GET").increment(1);
+//! metrics::histogram!("http_request_duration").record(0.0042);
+//! ```
+
+use crate::{Counter, CounterOps, Histogram, HistogramOps, Id, Metrics, Tags};
+use metrics::{CounterFn, GaugeFn, HistogramFn, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// [`metrics::Recorder`] that records into the active metricus backend.
+///
+/// Metricus only supports unsigned integer values, so gauge and histogram values are multiplied by the scale
+/// (1 by default) and truncated. Gauges are reported as two counters tagged `direction=up` and `direction=down`,
+/// whose difference is the current value of the gauge.
+///
+/// The handles returned by the recorder can be shared across threads while metricus backends expect a single
+/// producer, so all registrations and updates made through the recorder are serialised with a lock.
+#[derive(Debug)]
+pub struct MetricusRecorder {
+    scale: f64,
+    lock: Arc<Mutex<()>>,
+    counters: Mutex<HashMap<Key, Arc<CounterBridge>>>,
+    gauges: Mutex<HashMap<Key, Arc<GaugeBridge>>>,
+    histograms: Mutex<HashMap<Key, Arc<HistogramBridge>>>,
+}
+
+impl Default for MetricusRecorder {
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            lock: Default::default(),
+            counters: Default::default(),
+            gauges: Default::default(),
+            histograms: Default::default(),
+        }
+    }
+}
+
+impl MetricusRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Multiply gauge and histogram values by the given scale before they are recorded.
+    pub fn with_scale(self, scale: f64) -> Self {
+        Self { scale, ..self }
+    }
+}
+
+impl Recorder for MetricusRecorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {
+        // no-op
+    }
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {
+        // no-op
+    }
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {
+        // no-op
+    }
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> metrics::Counter {
+        let counter = lock(&self.counters)
+            .entry(key.clone())
+            .or_insert_with(|| {
+                let _guard = lock(&self.lock);
+                Arc::new(CounterBridge {
+                    counter: with_tags(key, &[], Counter::new),
+                    absolute: AtomicU64::new(0),
+                    lock: self.lock.clone(),
+                })
+            })
+            .clone();
+        metrics::Counter::from_arc(counter)
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> metrics::Gauge {
+        let gauge = lock(&self.gauges)
+            .entry(key.clone())
+            .or_insert_with(|| {
+                let _guard = lock(&self.lock);
+                Arc::new(GaugeBridge {
+                    up: with_tags(key, &[("direction", "up")], Counter::new),
+                    down: with_tags(key, &[("direction", "down")], Counter::new),
+                    value: Mutex::new(0.0),
+                    scale: self.scale,
+                    lock: self.lock.clone(),
+                })
+            })
+            .clone();
+        metrics::Gauge::from_arc(gauge)
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> metrics::Histogram {
+        let histogram = lock(&self.histograms)
+            .entry(key.clone())
+            .or_insert_with(|| {
+                let _guard = lock(&self.lock);
+                Arc::new(HistogramBridge {
+                    histogram: with_tags(key, &[], Histogram::new),
+                    scale: self.scale,
+                    lock: self.lock.clone(),
+                })
+            })
+            .clone();
+        metrics::Histogram::from_arc(histogram)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn with_tags<T>(key: &Key, extra_tags: Tags, f: impl FnOnce(&str, Tags) -> T) -> T {
+    let tags = key
+        .labels()
+        .map(|label| (label.key(), label.value()))
+        .chain(extra_tags.iter().copied())
+        .collect::<Vec<_>>();
+    f(key.name(), &tags)
+}
+
+#[inline]
+fn to_value(value: f64, scale: f64) -> u64 {
+    (value * scale) as u64
+}
+
+#[derive(Debug)]
+struct CounterBridge {
+    counter: Counter,
+    /// Last absolute value, used to turn absolute updates into increments.
+    absolute: AtomicU64,
+    lock: Arc<Mutex<()>>,
+}
+
+impl CounterFn for CounterBridge {
+    fn increment(&self, value: u64) {
+        let _guard = lock(&self.lock);
+        self.counter.increment_by(value)
+    }
+
+    fn absolute(&self, value: u64) {
+        let previous = self.absolute.fetch_max(value, Ordering::AcqRel);
+        if value > previous {
+            let _guard = lock(&self.lock);
+            self.counter.increment_by(value - previous)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct GaugeBridge {
+    /// Total of the increases of the scaled value.
+    up: Counter,
+    /// Total of the decreases of the scaled value.
+    down: Counter,
+    value: Mutex<f64>,
+    scale: f64,
+    lock: Arc<Mutex<()>>,
+}
+
+impl GaugeBridge {
+    fn update(&self, f: impl Fn(f64) -> f64) {
+        let mut value = lock(&self.value);
+        let previous = (*value * self.scale) as i64;
+        *value = f(*value);
+        let current = (*value * self.scale) as i64;
+        let _guard = lock(&self.lock);
+        match current.cmp(&previous) {
+            std::cmp::Ordering::Greater => self.up.increment_by(current.abs_diff(previous)),
+            std::cmp::Ordering::Less => self.down.increment_by(current.abs_diff(previous)),
+            std::cmp::Ordering::Equal => {}
+        }
+    }
+}
+
+impl GaugeFn for GaugeBridge {
+    fn increment(&self, value: f64) {
+        self.update(|current| current + value)
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update(|current| current - value)
+    }
+
+    fn set(&self, value: f64) {
+        self.update(|_| value)
+    }
+}
+
+#[derive(Debug)]
+struct HistogramBridge {
+    histogram: Histogram,
+    scale: f64,
+    lock: Arc<Mutex<()>>,
+}
+
+impl HistogramFn for HistogramBridge {
+    fn record(&self, value: f64) {
+        let _guard = lock(&self.lock);
+        self.histogram.record(to_value(value, self.scale))
+    }
+}
+
+/// Metricus backend that forwards all counters and histograms to the installed [`metrics`] recorder.
+///
+/// ## Examples
+///
+/// ```no_run
+/// use metricus::facade::MetricsFacade;
+///
+/// metricus::set_metrics(MetricsFacade::new());
+/// ```
+#[derive(Debug, Default)]
+pub struct MetricsFacade {
+    /// Counters and histograms share the id space, deleted metrics leave an empty slot.
+    handles: Vec<Handle>,
+    /// Empty slots, reused by the next metrics.
+    free: Vec<Id>,
+}
+
+#[derive(Debug)]
+enum Handle {
+    Counter(metrics::Counter),
+    Histogram(metrics::Histogram),
+    Deleted,
+}
+
+impl MetricsFacade {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, handle: Handle) -> Id {
+        match self.free.pop() {
+            Some(id) => {
+                self.handles[id as usize] = handle;
+                id
+            }
+            None => {
+                self.handles.push(handle);
+                (self.handles.len() - 1) as Id
+            }
+        }
+    }
+
+    fn delete(&mut self, id: Id) {
+        if let Some(handle) = self.handles.get_mut(id as usize) {
+            if !matches!(handle, Handle::Deleted) {
+                *handle = Handle::Deleted;
+                self.free.push(id);
+            }
+        }
+    }
+}
+
+fn to_key(name: &str, tags: Tags) -> Key {
+    let labels = tags
+        .iter()
+        .map(|(k, v)| metrics::Label::new(k.to_string(), v.to_string()))
+        .collect::<Vec<_>>();
+    Key::from_parts(name.to_owned(), labels)
+}
+
+fn metadata() -> Metadata<'static> {
+    Metadata::new(module_path!(), metrics::Level::INFO, Some(module_path!()))
+}
+
+impl Metrics for MetricsFacade {
+    fn name(&self) -> &'static str {
+        "metrics-facade"
+    }
+
+    fn new_counter(&mut self, name: &str, tags: Tags) -> Id {
+        let key = to_key(name, tags);
+        let counter = metrics::with_recorder(|recorder| recorder.register_counter(&key, &metadata()));
+        self.push(Handle::Counter(counter))
+    }
+
+    fn delete_counter(&mut self, id: Id) {
+        self.delete(id)
+    }
+
+    fn increment_counter_by(&mut self, id: Id, delta: u64) {
+        if let Some(Handle::Counter(counter)) = self.handles.get(id as usize) {
+            counter.increment(delta)
+        }
+    }
+
+    fn new_histogram(&mut self, name: &str, tags: Tags) -> Id {
+        let key = to_key(name, tags);
+        let histogram = metrics::with_recorder(|recorder| recorder.register_histogram(&key, &metadata()));
+        self.push(Handle::Histogram(histogram))
+    }
+
+    fn delete_histogram(&mut self, id: Id) {
+        self.delete(id)
+    }
+
+    fn record(&mut self, id: Id, value: u64) {
+        if let Some(Handle::Histogram(histogram)) = self.handles.get(id as usize) {
+            histogram.record(value as f64)
+        }
+    }
+}
@@ -1,12 +1,16 @@
 //! A `Histogram` proxy struct for managing a metrics histogram.
 
-use crate::access::get_metrics_mut;
-use crate::{Id, Tags};
-#[cfg(feature = "rdtsc")]
-use quanta::Clock;
-use std::cell::{LazyCell, UnsafeCell};
-#[cfg(not(feature = "rdtsc"))]
-use std::time::Instant;
+use crate::access::{self, Scope};
+use crate::clock::{self, Clock, Timestamp};
+use crate::{Id, MetricKind, Tags, registry};
+use std::cell::{Cell, LazyCell, UnsafeCell};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::task::{Context, Poll};
+
+/// Point in time as captured by the histogram clock.
+type Start = u64;
 
 /// Facilitates the creation of a new histogram, recording of values, and
 /// generation of spans for timing operations.
@@ -44,8 +48,11 @@ use std::time::Instant;
 #[derive(Debug)]
 pub struct Histogram {
     id: Id,
-    #[cfg(feature = "rdtsc")]
-    clock: Clock,
+    scope: Scope,
+    /// Clock of this histogram, the global clock is used if not set.
+    clock: Option<&'static dyn Clock>,
+    /// Only 1 in `sample_rate` values is recorded.
+    sample_rate: u32,
 }
 
 impl Histogram {
@@ -70,11 +77,76 @@ impl Histogram {
     /// let histogram = Histogram::new("login_duration", empty_tags());
     /// ```
     pub fn new(name: &str, tags: Tags) -> Self {
-        let histogram_id = get_metrics_mut().new_histogram(name, tags);
+        Self::register(name, tags, None, 1)
+    }
+
+    /// Creates a new histogram that records only 1 in `sample_rate` values (or spans), picked at random on each
+    /// thread, for paths too hot to record every call. The sample rate is passed to the backend so that it can
+    /// scale the counts back up when exporting. A rate of `0` or `1` records every value.
+    ///
+    /// ```no_run
+    /// use metricus::{Histogram, HistogramOps};
+    ///
+    /// let histogram = Histogram::with_sample_rate("tick_to_trade", &[], 100);
+    /// histogram.with_span(|| {
+    ///     // Execute operation, timed once in a hundred calls...
+    /// });
+    /// ```
+    pub fn with_sample_rate(name: &str, tags: Tags, sample_rate: u32) -> Self {
+        Self::register(name, tags, None, sample_rate.max(1))
+    }
+
+    /// Creates a new histogram that measures spans with the given clock rather than the global one.
+    ///
+    /// ```no_run
+    /// use metricus::Histogram;
+    /// use metricus::clock::InstantClock;
+    ///
+    /// let histogram = Histogram::with_clock("login_duration", &[], &InstantClock);
+    /// ```
+    pub fn with_clock(name: &str, tags: Tags, clock: &'static dyn Clock) -> Self {
+        Self::register(name, tags, Some(clock), 1)
+    }
+
+    fn register(name: &str, tags: Tags, clock: Option<&'static dyn Clock>, sample_rate: u32) -> Self {
+        let (histogram_id, scope) = registry::register(MetricKind::Histogram, name, tags, sample_rate);
         Self {
             id: histogram_id,
-            #[cfg(feature = "rdtsc")]
-            clock: Clock::new(),
+            scope,
+            clock,
+            sample_rate,
+        }
+    }
+
+    /// Only 1 in `sample_rate` values is recorded, `1` when the histogram is not sampled.
+    pub const fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Decides whether the next value should be recorded.
+    #[inline]
+    fn is_sampled(&self) -> bool {
+        self.sample_rate == 1 || sample(self.sample_rate)
+    }
+
+    #[inline]
+    fn record_unsampled(&self, value: u64) {
+        access::with_metrics(self.scope, |metrics| metrics.record(self.id, value));
+    }
+
+    #[inline]
+    fn now(&self) -> Start {
+        match self.clock {
+            Some(clock) => clock.raw(),
+            None => clock::now(),
+        }
+    }
+
+    #[inline]
+    fn elapsed_ns(&self, start: Start) -> u64 {
+        match self.clock {
+            Some(clock) => clock.delta_as_nanos(start, clock.raw()),
+            None => clock::elapsed_ns(start),
         }
     }
 }
@@ -93,6 +165,22 @@ pub trait HistogramOps {
     /// ```
     fn record(&self, value: u64);
 
+    /// Records the time elapsed (in nanoseconds) since the timestamp has been captured, possibly on another thread.
+    /// The elapsed time is measured with the clock of the timestamp.
+    ///
+    /// ```no_run
+    /// use metricus::{Histogram, HistogramOps};
+    /// use metricus::clock::Timestamp;
+    ///
+    /// let histogram = Histogram::new("queue_latency", &[]);
+    /// let enqueued_at = Timestamp::now();
+    /// // Pass the timestamp along with the message...
+    /// histogram.record_since(enqueued_at);
+    /// ```
+    fn record_since(&self, timestamp: Timestamp) {
+        self.record(timestamp.elapsed_ns())
+    }
+
     /// Starts a span for timing an operation, automatically recording the duration upon completion.
     /// The duration recorded is in nanoseconds.
     ///
@@ -121,20 +209,46 @@ pub trait HistogramOps {
     /// });
     /// ```
     fn with_span<F: FnOnce() -> R, R>(&self, f: F) -> R;
+
+    /// Wraps the future so that its wall time, from the first poll until it completes, is recorded in nanoseconds.
+    /// This includes the time the future spent parked waiting to be woken up. Nothing is recorded if the future
+    /// is dropped before it completes.
+    ///
+    /// ```no_run
+    /// use metricus::{Histogram, HistogramOps};
+    ///
+    /// async fn handle(histogram: &Histogram) {
+    ///     histogram.time_future(async {
+    ///         // Execute operation...
+    ///     }).await
+    /// }
+    /// ```
+    ///
+    /// Use [TimedFuture::new] to record both the wall time and the busy time into separate histograms.
+    fn time_future<F: Future>(&self, future: F) -> TimedFuture<'_, F>;
+
+    /// Wraps the future so that its busy time, i.e. the time spent being polled, is recorded in nanoseconds once
+    /// it completes. Nothing is recorded if the future is dropped before it completes.
+    fn time_future_busy<F: Future>(&self, future: F) -> TimedFuture<'_, F>;
 }
 
 impl HistogramOps for Histogram {
     fn record(&self, value: u64) {
-        get_metrics_mut().record(self.id, value);
+        if self.is_sampled() {
+            self.record_unsampled(value);
+        }
+    }
+
+    fn record_since(&self, timestamp: Timestamp) {
+        if self.is_sampled() {
+            self.record_unsampled(timestamp.elapsed_ns());
+        }
     }
 
     fn span(&self) -> Span<'_> {
         Span {
             histogram: self,
-            #[cfg(feature = "rdtsc")]
-            start_raw: self.clock.raw(),
-            #[cfg(not(feature = "rdtsc"))]
-            start_instant: Instant::now(),
+            start: self.is_sampled().then(|| self.now()),
         }
     }
 
@@ -142,6 +256,14 @@ impl HistogramOps for Histogram {
         let _span = self.span();
         f()
     }
+
+    fn time_future<F: Future>(&self, future: F) -> TimedFuture<'_, F> {
+        TimedFuture::new(future, Some(self), None)
+    }
+
+    fn time_future_busy<F: Future>(&self, future: F) -> TimedFuture<'_, F> {
+        TimedFuture::new(future, None, Some(self))
+    }
 }
 
 impl HistogramOps for LazyCell<UnsafeCell<Histogram>> {
@@ -149,6 +271,10 @@ impl HistogramOps for LazyCell<UnsafeCell<Histogram>> {
         unsafe { &mut *self.get() }.record(value)
     }
 
+    fn record_since(&self, timestamp: Timestamp) {
+        unsafe { &*self.get() }.record_since(timestamp)
+    }
+
     fn span(&self) -> Span<'_> {
         unsafe { &mut *self.get() }.span()
     }
@@ -156,32 +282,415 @@ impl HistogramOps for LazyCell<UnsafeCell<Histogram>> {
     fn with_span<F: FnOnce() -> R, R>(&self, f: F) -> R {
         unsafe { &mut *self.get() }.with_span(f)
     }
+
+    fn time_future<F: Future>(&self, future: F) -> TimedFuture<'_, F> {
+        unsafe { &*self.get() }.time_future(future)
+    }
+
+    fn time_future_busy<F: Future>(&self, future: F) -> TimedFuture<'_, F> {
+        unsafe { &*self.get() }.time_future_busy(future)
+    }
 }
 
 impl Drop for Histogram {
     fn drop(&mut self) {
-        get_metrics_mut().delete_histogram(self.id);
+        registry::unregister(MetricKind::Histogram, self.id, self.scope);
     }
 }
 
 /// Used for measuring how long given operation takes. The duration is recorded in nanoseconds.
 pub struct Span<'a> {
     histogram: &'a Histogram,
-    #[cfg(feature = "rdtsc")]
-    start_raw: u64,
-    #[cfg(not(feature = "rdtsc"))]
-    start_instant: Instant,
+    /// Not captured if the span has not been sampled, so that it costs no clock read.
+    start: Option<Start>,
 }
 
 impl Drop for Span<'_> {
     fn drop(&mut self) {
-        #[cfg(feature = "rdtsc")]
+        if let Some(start) = self.start {
+            self.histogram.record_unsampled(self.histogram.elapsed_ns(start));
+        }
+    }
+}
+
+impl Span<'_> {
+    /// Discards the span without recording anything, e.g. when the operation returned early.
+    ///
+    /// ```no_run
+    /// use metricus::{Histogram, HistogramOps};
+    ///
+    /// let histogram = Histogram::new("task_duration", &[]);
+    /// let span = histogram.span();
+    /// // nothing to do...
+    /// span.cancel();
+    /// ```
+    pub fn cancel(self) {
+        std::mem::forget(self)
+    }
+
+    /// Records the span now rather than when it is dropped and returns the elapsed time in nanoseconds. With a
+    /// sampled histogram, `0` is returned if the span has not been sampled, as its time is not measured.
+    ///
+    /// ```no_run
+    /// use metricus::{Histogram, HistogramOps};
+    ///
+    /// let histogram = Histogram::new("task_duration", &[]);
+    /// let span = histogram.span();
+    /// // Execute operation...
+    /// let elapsed = span.stop();
+    /// ```
+    pub fn stop(self) -> u64 {
+        let elapsed = match self.start {
+            Some(start) => {
+                let elapsed = self.histogram.elapsed_ns(start);
+                self.histogram.record_unsampled(elapsed);
+                elapsed
+            }
+            None => 0,
+        };
+        std::mem::forget(self);
+        elapsed
+    }
+}
+
+/// Pair of sibling histograms, tagged with `outcome=ok` and `outcome=err`, so that the latency of the operations
+/// that failed does not skew the distribution of the ones that succeeded.
+///
+/// ## Examples
+///
+/// ```no_run
+/// use metricus::OutcomeHistogram;
+///
+/// let histogram = OutcomeHistogram::new("order_to_ack", &[("venue", "xnas")]);
+/// let result: Result<(), ()> = histogram.with_span(|| {
+///     // Execute operation...
+///     Ok(())
+/// });
+/// ```
+#[derive(Debug)]
+pub struct OutcomeHistogram {
+    ok: Histogram,
+    err: Histogram,
+}
+
+impl OutcomeHistogram {
+    /// Creates the `ok` and `err` histograms with the specified name and tags.
+    pub fn new(name: &str, tags: Tags) -> Self {
+        Self::with_sample_rate(name, tags, 1)
+    }
+
+    /// Creates the `ok` and `err` histograms, both recording only 1 in `sample_rate` spans.
+    pub fn with_sample_rate(name: &str, tags: Tags, sample_rate: u32) -> Self {
+        let with_outcome = |outcome| {
+            let mut tags = tags.to_vec();
+            tags.push(("outcome", outcome));
+            tags
+        };
+        Self {
+            ok: Histogram::with_sample_rate(name, &with_outcome("ok"), sample_rate),
+            err: Histogram::with_sample_rate(name, &with_outcome("err"), sample_rate),
+        }
+    }
+
+    /// Histogram of the successful operations.
+    pub fn ok(&self) -> &Histogram {
+        &self.ok
+    }
+
+    /// Histogram of the failed operations.
+    pub fn err(&self) -> &Histogram {
+        &self.err
+    }
+
+    /// Starts a span that is recorded into one of the histograms once the outcome is known.
+    pub fn span(&self) -> OutcomeSpan<'_> {
+        OutcomeSpan {
+            histogram: self,
+            start: self.ok.is_sampled().then(|| self.ok.now()),
+        }
+    }
+
+    /// Accepts a closure whose duration will be measured and recorded depending on the result it returns.
+    pub fn with_span<T, E, F: FnOnce() -> Result<T, E>>(&self, f: F) -> Result<T, E> {
+        let span = self.span();
+        let result = f();
+        span.finish(&result);
+        result
+    }
+}
+
+/// Span whose duration is recorded into the `ok` or `err` histogram of the [OutcomeHistogram]. Nothing is recorded
+/// if the span is dropped before its outcome is known. With sampled histograms, the elapsed time of a span that has
+/// not been sampled is not measured and returned as `0`.
+pub struct OutcomeSpan<'a> {
+    histogram: &'a OutcomeHistogram,
+    /// Not captured if the span has not been sampled, so that it costs no clock read.
+    start: Option<Start>,
+}
+
+impl OutcomeSpan<'_> {
+    /// Records the span as successful and returns the elapsed time in nanoseconds.
+    pub fn ok(self) -> u64 {
+        self.stop_into(&self.histogram.ok)
+    }
+
+    /// Records the span as failed and returns the elapsed time in nanoseconds.
+    pub fn err(self) -> u64 {
+        self.stop_into(&self.histogram.err)
+    }
+
+    /// Records the span depending on the result and returns the elapsed time in nanoseconds.
+    pub fn finish<T, E>(self, result: &Result<T, E>) -> u64 {
+        match result {
+            Ok(_) => self.ok(),
+            Err(_) => self.err(),
+        }
+    }
+
+    /// Discards the span without recording anything.
+    pub fn cancel(self) {}
+
+    fn stop_into(&self, histogram: &Histogram) -> u64 {
+        let Some(start) = self.start else {
+            return 0;
+        };
+        let elapsed = histogram.elapsed_ns(start);
+        histogram.record_unsampled(elapsed);
+        elapsed
+    }
+}
+
+/// Future that records how long the inner future took to complete. The wall time (from the first poll until
+/// completion) and the busy time (the sum of the time spent in each poll) are recorded in nanoseconds into
+/// their respective histograms, if provided.
+pub struct TimedFuture<'a, F> {
+    inner: F,
+    wall: Option<&'a Histogram>,
+    busy: Option<&'a Histogram>,
+    first_poll: Option<Start>,
+    busy_ns: u64,
+}
+
+impl<'a, F: Future> TimedFuture<'a, F> {
+    /// Wraps the future recording its wall time and/or busy time.
+    ///
+    /// ```no_run
+    /// use metricus::{Histogram, TimedFuture};
+    ///
+    /// async fn handle(wall: &Histogram, busy: &Histogram) {
+    ///     TimedFuture::new(async {
+    ///         // Execute operation...
+    ///     }, Some(wall), Some(busy)).await
+    /// }
+    /// ```
+    pub fn new(future: F, wall: Option<&'a Histogram>, busy: Option<&'a Histogram>) -> Self {
+        // the future is not timed at all by the histograms that do not sample it
+        Self {
+            inner: future,
+            wall: wall.filter(|wall| wall.is_sampled()),
+            busy: busy.filter(|busy| busy.is_sampled()),
+            first_poll: None,
+            busy_ns: 0,
+        }
+    }
+}
+
+impl<F: Future> Future for TimedFuture<'_, F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: the inner future is never moved out of the pinned wrapper
+        let this = unsafe { self.get_unchecked_mut() };
+        let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+
+        let poll_start = this.busy.map(Histogram::now);
+        if let Some(wall) = this.wall {
+            this.first_poll.get_or_insert_with(|| wall.now());
+        }
+        let poll = inner.poll(cx);
+        if let (Some(busy), Some(poll_start)) = (this.busy, poll_start) {
+            this.busy_ns += busy.elapsed_ns(poll_start);
+        }
+
+        if poll.is_ready() {
+            if let (Some(wall), Some(first_poll)) = (this.wall, this.first_poll) {
+                wall.record_unsampled(wall.elapsed_ns(first_poll));
+            }
+            if let Some(busy) = this.busy {
+                busy.record_unsampled(this.busy_ns);
+            }
+        }
+        poll
+    }
+}
+
+/// Seeds the sampling state of each thread differently, so that threads do not sample in lockstep.
+static SAMPLER_SEED: AtomicU32 = AtomicU32::new(0x9E37_79B9);
+
+thread_local! {
+    /// Xorshift state shared by all the sampled histograms on this thread. Unlike a plain call counter, it does not
+    /// alias when several histograms sampled at different rates are recorded in a fixed order.
+    static SAMPLER: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Returns `true` for 1 in `rate` calls on average.
+#[inline]
+pub(crate) fn sample(rate: u32) -> bool {
+    SAMPLER.with(|state| {
+        let mut x = state.get();
+        if x == 0 {
+            x = SAMPLER_SEED.fetch_add(0x9E37_79B9, Ordering::Relaxed) | 1;
+        }
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        state.set(x);
+        x % rate == 0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::{Metrics, set_thread_metrics};
+    use std::sync::{Arc, Mutex};
+    use std::task::Waker;
+    use std::time::Duration;
+
+    /// Keeps the values recorded into each histogram, identified by its name and tags, e.g. `latency,outcome=ok`.
+    #[derive(Clone, Default)]
+    struct Values {
+        histograms: Vec<String>,
+        values: Arc<Mutex<Vec<(String, u64)>>>,
+    }
+
+    impl Values {
+        fn of(&self, histogram: &str) -> Vec<u64> {
+            let values = self.values.lock().unwrap();
+            values
+                .iter()
+                .filter(|(name, _)| name == histogram)
+                .map(|(_, value)| *value)
+                .collect()
+        }
+    }
+
+    impl Metrics for Values {
+        fn name(&self) -> &'static str {
+            "values"
+        }
+
+        fn new_counter(&mut self, _name: &str, _tags: Tags) -> Id {
+            unreachable!()
+        }
+
+        fn delete_counter(&mut self, _id: Id) {}
+
+        fn increment_counter_by(&mut self, _id: Id, _delta: u64) {}
+
+        fn new_histogram(&mut self, name: &str, tags: Tags) -> Id {
+            let tags = tags.iter().map(|(k, v)| format!(",{k}={v}")).collect::<String>();
+            self.histograms.push(format!("{name}{tags}"));
+            self.histograms.len() as Id
+        }
+
+        fn delete_histogram(&mut self, _id: Id) {}
+
+        fn record(&mut self, id: Id, value: u64) {
+            let name = self.histograms[id as usize - 1].clone();
+            self.values.lock().unwrap().push((name, value));
+        }
+    }
+
+    /// Future that is pending once, so that the clock can move while it is parked.
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+            match std::mem::replace(&mut self.0, true) {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        }
+    }
+
+    fn poll<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+        future.poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    #[test]
+    fn should_record_span_unless_cancelled() {
+        let values = Values::default();
+        let _guard = set_thread_metrics(values.clone());
+        let clock: &'static MockClock = Box::leak(Box::new(MockClock::new()));
+        let histogram = Histogram::with_clock("latency", &[], clock);
+
+        let span = histogram.span();
+        clock.advance(Duration::from_micros(4));
+        span.cancel();
+        assert!(values.of("latency").is_empty());
+
+        let span = histogram.span();
+        clock.advance(Duration::from_micros(3));
+        assert_eq!(3_000, span.stop());
         {
-            let end_raw = self.histogram.clock.raw();
-            let elapsed = self.histogram.clock.delta_as_nanos(self.start_raw, end_raw);
-            self.histogram.record(elapsed);
+            let _span = histogram.span();
+            clock.advance(Duration::from_micros(5));
         }
-        #[cfg(not(feature = "rdtsc"))]
-        self.histogram.record(self.start_instant.elapsed().as_nanos() as u64);
+        assert_eq!(vec![3_000, 5_000], values.of("latency"));
+    }
+
+    #[test]
+    fn should_record_outcome_span_into_histogram_of_its_outcome() {
+        let values = Values::default();
+        let _guard = set_thread_metrics(values.clone());
+        let histogram = OutcomeHistogram::new("latency", &[("venue", "xnas")]);
+
+        assert_eq!(Ok(1), histogram.with_span(|| Ok::<_, ()>(1)));
+        assert_eq!(Err(2), histogram.with_span(|| Err::<(), _>(2)));
+        histogram.span().ok();
+        histogram.span().finish(&Err::<(), _>(3));
+        // nothing is recorded without an outcome
+        histogram.span().cancel();
+        {
+            let _span = histogram.span();
+        }
+
+        assert_eq!(2, values.of("latency,venue=xnas,outcome=ok").len());
+        assert_eq!(2, values.of("latency,venue=xnas,outcome=err").len());
+    }
+
+    #[test]
+    fn should_record_wall_and_busy_time_of_future() {
+        let values = Values::default();
+        let _guard = set_thread_metrics(values.clone());
+        let clock = MockClock::new();
+        let static_clock: &'static MockClock = Box::leak(Box::new(clock.clone()));
+        let wall = Histogram::with_clock("wall", &[], static_clock);
+        let busy = Histogram::with_clock("busy", &[], static_clock);
+
+        let operation = || async {
+            clock.advance(Duration::from_micros(2));
+            YieldNow(false).await;
+            clock.advance(Duration::from_micros(3));
+            7
+        };
+        let mut both = std::pin::pin!(TimedFuture::new(operation(), Some(&wall), Some(&busy)));
+        let mut busy_only = std::pin::pin!(TimedFuture::new(operation(), None, Some(&busy)));
+        for future in [both.as_mut(), busy_only.as_mut()] {
+            // nothing is recorded until the future completes
+            let _ = poll(future);
+        }
+        assert!(values.of("wall").is_empty() && values.of("busy").is_empty());
+
+        // the time parked counts towards the wall time only
+        clock.advance(Duration::from_micros(10));
+        assert_eq!(Poll::Ready(7), poll(both));
+        assert_eq!(Poll::Ready(7), poll(busy_only));
+        assert_eq!(vec![17_000], values.of("wall"));
+        assert_eq!(vec![5_000, 5_000], values.of("busy"));
     }
 }
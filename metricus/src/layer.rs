@@ -0,0 +1,547 @@
+//! Composable wrappers that implement [`Metrics`] on top of another backend.
+//!
+//! Each wrapper can be constructed directly or through the [`MetricsExt`] extension trait, which allows the stack
+//! to be built fluently before being passed to [`set_metrics`](crate::set_metrics).
+//!
+//! Metrics with pre-allocated ids (see [`RESERVED_IDS`]) never go through registration, so the wrappers forward
+//! their updates unchanged.
+//!
+//! ## Examples
+//!
+//! ```
+//! use metricus::Metrics;
+//! use metricus::layer::MetricsExt;
+//!
+//! fn install(live: impl Metrics, audit: impl Metrics) {
+//!     let stack = live
+//!         .fanout(audit.filter_prefix("orders"))
+//!         .with_tags(&[("service", "oms")])
+//!         .sample(10);
+//!     metricus::set_metrics(stack);
+//! }
+//! ```
+
+use crate::histogram::sample;
+use crate::{Id, Metrics, RESERVED_IDS, Tag, Tags};
+use std::sync::atomic::AtomicU64;
+
+/// Id returned for metrics that have been dropped by a [`Filter`], updates to it are discarded.
+pub const DROPPED_ID: Id = RESERVED_IDS.end();
+
+/// Extension trait to compose the wrappers on top of any backend.
+pub trait MetricsExt: Metrics + Sized {
+    /// Send all metrics to both this and the other backend.
+    fn fanout<B: Metrics>(self, other: B) -> Fanout<Self, B> {
+        Fanout::new(self, other)
+    }
+
+    /// Only register metrics for which the predicate returns `true`.
+    fn filter<F: FnMut(&str, Tags) -> bool>(self, predicate: F) -> Filter<Self, F> {
+        Filter::new(self, predicate)
+    }
+
+    /// Only register metrics whose measurement name starts with the given prefix.
+    fn filter_prefix(self, prefix: &'static str) -> Filter<Self, impl FnMut(&str, Tags) -> bool> {
+        Filter::new(self, move |name: &str, _: Tags| name.starts_with(prefix))
+    }
+
+    /// Append the given tags to every metric at registration.
+    fn with_tags(self, tags: Tags) -> TagInjector<Self> {
+        TagInjector::new(self, tags)
+    }
+
+    /// Forward only one in every `rate` histogram records.
+    fn sample(self, rate: u64) -> Sampler<Self> {
+        Sampler::new(self, rate)
+    }
+}
+
+impl<M: Metrics> MetricsExt for M {}
+
+/// Sends all metrics to two backends. Each backend assigns its own ids, which are mapped from the id returned
+/// by the fanout. The ids of the deleted metrics are reused by the next registrations.
+#[derive(Debug)]
+pub struct Fanout<A, B> {
+    first: A,
+    second: B,
+    ids: Vec<Option<(Id, Id)>>,
+    free: Vec<Id>,
+}
+
+impl<A: Metrics, B: Metrics> Fanout<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self {
+            first,
+            second,
+            ids: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    /// Returns the ids assigned by each backend, or `None` if the metric has been deleted.
+    #[inline]
+    fn resolve(&self, id: Id) -> Option<(Id, Id)> {
+        match RESERVED_IDS.contains(id) {
+            true => Some((id, id)),
+            false => self.ids.get(id as usize).copied().flatten(),
+        }
+    }
+
+    fn insert(&mut self, ids: (Id, Id)) -> Id {
+        match self.free.pop() {
+            Some(id) => {
+                self.ids[id as usize] = Some(ids);
+                id
+            }
+            None => {
+                self.ids.push(Some(ids));
+                (self.ids.len() - 1) as Id
+            }
+        }
+    }
+
+    fn remove(&mut self, id: Id) -> Option<(Id, Id)> {
+        if RESERVED_IDS.contains(id) {
+            return Some((id, id));
+        }
+        let ids = self.ids.get_mut(id as usize)?.take()?;
+        self.free.push(id);
+        Some(ids)
+    }
+}
+
+impl<A: Metrics, B: Metrics> Metrics for Fanout<A, B> {
+    fn name(&self) -> &'static str {
+        self.first.name()
+    }
+
+    fn new_counter(&mut self, name: &str, tags: Tags) -> Id {
+        let ids = (self.first.new_counter(name, tags), self.second.new_counter(name, tags));
+        self.insert(ids)
+    }
+
+    fn delete_counter(&mut self, id: Id) {
+        if let Some((first, second)) = self.remove(id) {
+            self.first.delete_counter(first);
+            self.second.delete_counter(second);
+        }
+    }
+
+    fn increment_counter_by(&mut self, id: Id, delta: u64) {
+        if let Some((first, second)) = self.resolve(id) {
+            self.first.increment_counter_by(first, delta);
+            self.second.increment_counter_by(second, delta);
+        }
+    }
+
+    fn increment_counter(&mut self, id: Id) {
+        if let Some((first, second)) = self.resolve(id) {
+            self.first.increment_counter(first);
+            self.second.increment_counter(second);
+        }
+    }
+
+    fn new_histogram(&mut self, name: &str, tags: Tags) -> Id {
+        let ids = (self.first.new_histogram(name, tags), self.second.new_histogram(name, tags));
+        self.insert(ids)
+    }
+
+    fn new_histogram_sampled(&mut self, name: &str, tags: Tags, sample_rate: u32) -> Id {
+        let ids = (
+            self.first.new_histogram_sampled(name, tags, sample_rate),
+            self.second.new_histogram_sampled(name, tags, sample_rate),
+        );
+        self.insert(ids)
+    }
+
+    fn delete_histogram(&mut self, id: Id) {
+        if let Some((first, second)) = self.remove(id) {
+            self.first.delete_histogram(first);
+            self.second.delete_histogram(second);
+        }
+    }
+
+    fn record(&mut self, id: Id, value: u64) {
+        if let Some((first, second)) = self.resolve(id) {
+            self.first.record(first, value);
+            self.second.record(second, value);
+        }
+    }
+
+    fn flush(&mut self) {
+        self.first.flush();
+        self.second.flush();
+    }
+}
+
+/// Drops metrics at registration for which the predicate returns `false`. Dropped metrics are assigned
+/// [`DROPPED_ID`] and their updates never reach the inner backend.
+#[derive(Debug)]
+pub struct Filter<M, F> {
+    inner: M,
+    predicate: F,
+}
+
+impl<M: Metrics, F: FnMut(&str, Tags) -> bool> Filter<M, F> {
+    pub fn new(inner: M, predicate: F) -> Self {
+        Self { inner, predicate }
+    }
+}
+
+impl<M: Metrics, F: FnMut(&str, Tags) -> bool> Metrics for Filter<M, F> {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn new_counter(&mut self, name: &str, tags: Tags) -> Id {
+        match (self.predicate)(name, tags) {
+            true => self.inner.new_counter(name, tags),
+            false => DROPPED_ID,
+        }
+    }
+
+    fn delete_counter(&mut self, id: Id) {
+        if id != DROPPED_ID {
+            self.inner.delete_counter(id)
+        }
+    }
+
+    fn increment_counter_by(&mut self, id: Id, delta: u64) {
+        if id != DROPPED_ID {
+            self.inner.increment_counter_by(id, delta)
+        }
+    }
+
+    fn increment_counter(&mut self, id: Id) {
+        if id != DROPPED_ID {
+            self.inner.increment_counter(id)
+        }
+    }
+
+    fn counter_slot(&mut self, id: Id) -> Option<&'static AtomicU64> {
+        match id {
+            DROPPED_ID => None,
+            _ => self.inner.counter_slot(id),
+        }
+    }
+
+    fn new_histogram(&mut self, name: &str, tags: Tags) -> Id {
+        match (self.predicate)(name, tags) {
+            true => self.inner.new_histogram(name, tags),
+            false => DROPPED_ID,
+        }
+    }
+
+    fn new_histogram_sampled(&mut self, name: &str, tags: Tags, sample_rate: u32) -> Id {
+        match (self.predicate)(name, tags) {
+            true => self.inner.new_histogram_sampled(name, tags, sample_rate),
+            false => DROPPED_ID,
+        }
+    }
+
+    fn delete_histogram(&mut self, id: Id) {
+        if id != DROPPED_ID {
+            self.inner.delete_histogram(id)
+        }
+    }
+
+    fn record(&mut self, id: Id, value: u64) {
+        if id != DROPPED_ID {
+            self.inner.record(id, value)
+        }
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush()
+    }
+}
+
+/// Appends a fixed set of tags to every metric at registration. Updates are forwarded as is.
+#[derive(Debug)]
+pub struct TagInjector<M> {
+    inner: M,
+    tags: Vec<(String, String)>,
+}
+
+impl<M: Metrics> TagInjector<M> {
+    pub fn new(inner: M, tags: Tags) -> Self {
+        Self {
+            inner,
+            tags: tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        }
+    }
+}
+
+fn merge_tags<'a>(tags: Tags<'a>, injected: &'a [(String, String)]) -> Vec<Tag<'a>> {
+    tags.iter()
+        .copied()
+        .chain(injected.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+        .collect()
+}
+
+impl<M: Metrics> Metrics for TagInjector<M> {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn new_counter(&mut self, name: &str, tags: Tags) -> Id {
+        self.inner.new_counter(name, &merge_tags(tags, &self.tags))
+    }
+
+    fn delete_counter(&mut self, id: Id) {
+        self.inner.delete_counter(id)
+    }
+
+    fn increment_counter_by(&mut self, id: Id, delta: u64) {
+        self.inner.increment_counter_by(id, delta)
+    }
+
+    fn increment_counter(&mut self, id: Id) {
+        self.inner.increment_counter(id)
+    }
+
+    fn counter_slot(&mut self, id: Id) -> Option<&'static AtomicU64> {
+        self.inner.counter_slot(id)
+    }
+
+    fn new_histogram(&mut self, name: &str, tags: Tags) -> Id {
+        self.inner.new_histogram(name, &merge_tags(tags, &self.tags))
+    }
+
+    fn new_histogram_sampled(&mut self, name: &str, tags: Tags, sample_rate: u32) -> Id {
+        self.inner
+            .new_histogram_sampled(name, &merge_tags(tags, &self.tags), sample_rate)
+    }
+
+    fn delete_histogram(&mut self, id: Id) {
+        self.inner.delete_histogram(id)
+    }
+
+    fn record(&mut self, id: Id, value: u64) {
+        self.inner.record(id, value)
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush()
+    }
+}
+
+/// Forwards one in every `rate` histogram records on average, picked at random so that histograms recorded in a
+/// fixed order are sampled independently. Counters are not sampled. Histograms are created as sampled at `rate`
+/// (multiplied by their own sample rate, if any), so the backend can scale them back.
+#[derive(Debug)]
+pub struct Sampler<M> {
+    inner: M,
+    rate: u32,
+}
+
+impl<M: Metrics> Sampler<M> {
+    pub fn new(inner: M, rate: u64) -> Self {
+        assert!(rate > 0, "sample rate must be greater than zero");
+        Self {
+            inner,
+            rate: u32::try_from(rate).unwrap_or(u32::MAX),
+        }
+    }
+}
+
+impl<M: Metrics> Metrics for Sampler<M> {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn new_counter(&mut self, name: &str, tags: Tags) -> Id {
+        self.inner.new_counter(name, tags)
+    }
+
+    fn delete_counter(&mut self, id: Id) {
+        self.inner.delete_counter(id)
+    }
+
+    fn increment_counter_by(&mut self, id: Id, delta: u64) {
+        self.inner.increment_counter_by(id, delta)
+    }
+
+    fn increment_counter(&mut self, id: Id) {
+        self.inner.increment_counter(id)
+    }
+
+    fn counter_slot(&mut self, id: Id) -> Option<&'static AtomicU64> {
+        self.inner.counter_slot(id)
+    }
+
+    fn new_histogram(&mut self, name: &str, tags: Tags) -> Id {
+        self.new_histogram_sampled(name, tags, 1)
+    }
+
+    fn new_histogram_sampled(&mut self, name: &str, tags: Tags, sample_rate: u32) -> Id {
+        self.inner
+            .new_histogram_sampled(name, tags, sample_rate.saturating_mul(self.rate))
+    }
+
+    fn delete_histogram(&mut self, id: Id) {
+        self.inner.delete_histogram(id)
+    }
+
+    fn record(&mut self, id: Id, value: u64) {
+        if self.rate == 1 || sample(self.rate) {
+            self.inner.record(id, value);
+        }
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Keeps the registrations along with the updates of each metric.
+    #[derive(Default)]
+    struct Records {
+        next_id: Id,
+        metrics: HashMap<Id, (String, Vec<(String, String)>)>,
+        deleted: Vec<Id>,
+        increments: HashMap<Id, u64>,
+        records: HashMap<Id, u64>,
+    }
+
+    impl Records {
+        fn starting_at(next_id: Id) -> Self {
+            Self {
+                next_id,
+                ..Self::default()
+            }
+        }
+
+        fn register(&mut self, name: &str, tags: Tags) -> Id {
+            self.next_id += 1;
+            let tags = tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            self.metrics.insert(self.next_id, (name.to_owned(), tags));
+            self.next_id
+        }
+
+        fn increments(&self, id: Id) -> u64 {
+            self.increments.get(&id).copied().unwrap_or_default()
+        }
+    }
+
+    impl Metrics for Records {
+        fn name(&self) -> &'static str {
+            "records"
+        }
+
+        fn new_counter(&mut self, name: &str, tags: Tags) -> Id {
+            self.register(name, tags)
+        }
+
+        fn delete_counter(&mut self, id: Id) {
+            self.deleted.push(id);
+        }
+
+        fn increment_counter_by(&mut self, id: Id, delta: u64) {
+            *self.increments.entry(id).or_default() += delta;
+        }
+
+        fn new_histogram(&mut self, name: &str, tags: Tags) -> Id {
+            self.register(name, tags)
+        }
+
+        fn delete_histogram(&mut self, id: Id) {
+            self.deleted.push(id);
+        }
+
+        fn record(&mut self, id: Id, _value: u64) {
+            *self.records.entry(id).or_default() += 1;
+        }
+    }
+
+    #[test]
+    fn should_map_fanout_ids_to_each_backend_and_reuse_deleted_ones() {
+        let mut fanout = Records::starting_at(0).fanout(Records::starting_at(100));
+        assert_eq!("records", fanout.name());
+        let orders = fanout.new_counter("orders", &[]);
+        let fills = fanout.new_counter("fills", &[]);
+        fanout.increment_counter_by(orders, 2);
+        fanout.increment_counter(fills);
+        assert_eq!((2, 1), (fanout.first.increments(1), fanout.first.increments(2)));
+        assert_eq!((2, 1), (fanout.second.increments(101), fanout.second.increments(102)));
+
+        fanout.delete_counter(orders);
+        assert_eq!((vec![1], vec![101]), (fanout.first.deleted.clone(), fanout.second.deleted.clone()));
+        // the updates and deletes of a deleted metric are not forwarded
+        fanout.increment_counter(orders);
+        fanout.delete_counter(orders);
+        assert_eq!(2, fanout.first.increments(1));
+        assert_eq!(1, fanout.first.deleted.len());
+
+        let latency = fanout.new_histogram("latency", &[]);
+        assert_eq!(orders, latency);
+        fanout.record(latency, 10);
+        assert_eq!(Some(&1), fanout.first.records.get(&3));
+        assert_eq!(Some(&1), fanout.second.records.get(&103));
+
+        // pre-allocated metrics are forwarded unchanged
+        fanout.increment_counter(RESERVED_IDS.start());
+        assert_eq!(1, fanout.first.increments(RESERVED_IDS.start()));
+        assert_eq!(1, fanout.second.increments(RESERVED_IDS.start()));
+    }
+
+    #[test]
+    fn should_drop_filtered_metrics() {
+        let mut filter = Records::default().filter_prefix("orders");
+        assert_eq!("records", filter.name());
+        let orders = filter.new_counter("orders", &[]);
+        let fills = filter.new_counter("fills", &[]);
+        let latency = filter.new_histogram("fills_latency", &[]);
+        assert_ne!(DROPPED_ID, orders);
+        assert_eq!((DROPPED_ID, DROPPED_ID), (fills, latency));
+        assert_eq!(1, filter.inner.metrics.len());
+
+        filter.increment_counter(orders);
+        filter.increment_counter(fills);
+        filter.record(latency, 10);
+        filter.delete_counter(fills);
+        assert_eq!(1, filter.inner.increments(orders));
+        assert_eq!(0, filter.inner.increments(DROPPED_ID));
+        assert!(filter.inner.records.is_empty());
+        assert!(filter.inner.deleted.is_empty());
+        assert!(filter.counter_slot(fills).is_none());
+    }
+
+    #[test]
+    fn should_append_injected_tags() {
+        let mut injector = Records::default().with_tags(&[("service", "oms")]);
+        assert_eq!("records", injector.name());
+        let orders = injector.new_counter("orders", &[("venue", "xnas")]);
+        let latency = injector.new_histogram_sampled("latency", &[], 10);
+        let tags = |id| injector.inner.metrics[&id].1.clone();
+        assert_eq!(
+            vec![
+                ("venue".to_owned(), "xnas".to_owned()),
+                ("service".to_owned(), "oms".to_owned())
+            ],
+            tags(orders)
+        );
+        assert_eq!(vec![("service".to_owned(), "oms".to_owned())], tags(latency));
+    }
+
+    #[test]
+    fn should_sample_interleaved_histograms_independently() {
+        let mut sampler = Records::default().sample(2);
+        let first = sampler.new_histogram("first", &[]);
+        let second = sampler.new_histogram("second", &[]);
+        for value in 0..10_000 {
+            sampler.record(first, value);
+            sampler.record(second, value);
+        }
+
+        for id in [first, second] {
+            let recorded = sampler.inner.records.get(&id).copied().unwrap_or_default();
+            assert!((4_000..6_000).contains(&recorded), "histogram {id} recorded {recorded} values");
+        }
+    }
+}
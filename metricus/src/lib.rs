@@ -1,16 +1,26 @@
 #![doc = include_str!("../README.md")]
 
+pub mod clock;
 mod counter;
+#[cfg(feature = "metrics")]
+pub mod facade;
 mod histogram;
+pub mod layer;
+mod registry;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "tracing")]
+pub mod tracing_layer;
 
-use crate::access::get_metrics;
 // re-exports
 pub use counter::{Counter, CounterOps};
-pub use histogram::{Histogram, HistogramOps, Span};
+pub use histogram::{Histogram, HistogramOps, OutcomeHistogram, OutcomeSpan, Span, TimedFuture};
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::marker::PhantomData;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
 
 /// Metric id.
 pub type Id = u64;
@@ -24,6 +34,124 @@ pub const fn empty_tags() -> Tags<'static> {
     &[]
 }
 
+/// Inclusive range of metric ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IdRange {
+    start: Id,
+    end: Id,
+}
+
+impl IdRange {
+    /// Creates a new inclusive id range. Panics if `start` is greater than `end`.
+    pub const fn new(start: Id, end: Id) -> Self {
+        assert!(start <= end, "id range start must not be greater than its end");
+        Self { start, end }
+    }
+
+    /// First id in the range.
+    pub const fn start(&self) -> Id {
+        self.start
+    }
+
+    /// Last id in the range.
+    pub const fn end(&self) -> Id {
+        self.end
+    }
+
+    /// Checks if the `id` belongs to this range.
+    pub const fn contains(&self, id: Id) -> bool {
+        self.start <= id && id <= self.end
+    }
+
+    /// Checks if the two ranges have at least one id in common.
+    pub const fn overlaps(&self, other: &IdRange) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+}
+
+/// Ids reserved for pre-allocated metrics. Backends must never assign these ids dynamically, so any metric created
+/// with [Counter::new_with_id] or registered as [PreAllocatedMetric] should use an id from this range. Libraries
+/// shipping pre-allocated metrics should claim their ids with [reserve_ids]. The last id is assigned by backends to
+/// the metrics they drop, so it must not be used by any pre-allocated metric.
+pub const RESERVED_IDS: IdRange = IdRange::new(Id::MAX - u32::MAX as Id, Id::MAX);
+
+/// Ids available to backends for dynamically created metrics.
+pub const DYNAMIC_IDS: IdRange = IdRange::new(0, RESERVED_IDS.start() - 1);
+
+/// Ranges claimed with [reserve_ids] along with their owner.
+static RESERVATIONS: Mutex<Vec<(&'static str, IdRange)>> = Mutex::new(Vec::new());
+
+/// Claims a range of [RESERVED_IDS] for the pre-allocated metrics of a library (e.g. the allocator counters), so
+/// that two libraries cannot silently share ids. Fails if the range is not within [RESERVED_IDS] or overlaps a
+/// range claimed by another owner. Claiming the same range again for the same owner has no effect.
+///
+/// ## Examples
+///
+/// ```
+/// use metricus::{reserve_ids, IdRange, RESERVED_IDS};
+///
+/// let start = RESERVED_IDS.start();
+/// reserve_ids("orders", IdRange::new(start, start + 9)).unwrap();
+/// assert!(reserve_ids("payments", IdRange::new(start + 9, start + 19)).is_err());
+/// ```
+pub fn reserve_ids(owner: &'static str, range: IdRange) -> Result<(), IdRangeError> {
+    if !RESERVED_IDS.contains(range.start) || !RESERVED_IDS.contains(range.end) {
+        return Err(IdRangeError::NotReserved { owner, range });
+    }
+    let mut reservations = RESERVATIONS.lock().unwrap_or_else(|e| e.into_inner());
+    if reservations.contains(&(owner, range)) {
+        return Ok(());
+    }
+    if let Some((existing_owner, existing)) = reservations.iter().find(|(_, existing)| existing.overlaps(&range)) {
+        return Err(IdRangeError::Overlapping {
+            owner,
+            range,
+            existing_owner,
+            existing: *existing,
+        });
+    }
+    reservations.push((owner, range));
+    Ok(())
+}
+
+/// Error returned when a range of ids cannot be claimed with [reserve_ids].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdRangeError {
+    /// Range is not within [RESERVED_IDS].
+    NotReserved { owner: &'static str, range: IdRange },
+    /// Range overlaps a range already claimed by another owner.
+    Overlapping {
+        owner: &'static str,
+        range: IdRange,
+        existing_owner: &'static str,
+        existing: IdRange,
+    },
+}
+
+impl std::fmt::Display for IdRangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdRangeError::NotReserved { owner, range } => write!(
+                f,
+                "ids [{}, {}] of '{owner}' are outside of the reserved id range [{}, {}]",
+                range.start, range.end, RESERVED_IDS.start, RESERVED_IDS.end
+            ),
+            IdRangeError::Overlapping {
+                owner,
+                range,
+                existing_owner,
+                existing,
+            } => write!(
+                f,
+                "ids [{}, {}] of '{owner}' overlap ids [{}, {}] already reserved by '{existing_owner}'",
+                range.start, range.end, existing.start, existing.end
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IdRangeError {}
+
 /// Common interface for metrics backend. Each new backend must implement this trait.
 pub trait Metrics {
     fn name(&self) -> &'static str;
@@ -40,9 +168,26 @@ pub trait Metrics {
 
     fn new_histogram(&mut self, name: &str, tags: Tags) -> Id;
 
+    /// Creates a histogram that only receives 1 in `sample_rate` of the recorded values, so that the backend can
+    /// scale the counts back up when exporting. Backends unaware of sampling create a regular histogram.
+    fn new_histogram_sampled(&mut self, name: &str, tags: Tags, _sample_rate: u32) -> Id {
+        self.new_histogram(name, tags)
+    }
+
     fn delete_histogram(&mut self, id: Id);
 
     fn record(&mut self, id: Id, value: u64);
+
+    /// Sends the updates buffered by the backend on the calling thread, if any. See [`flush_local`].
+    fn flush(&mut self) {}
+
+    /// Atomic slot holding the running total of the counter, for backends that keep their counters in memory shared
+    /// with their aggregator. The counter is then incremented by adding to its slot directly rather than calling
+    /// the backend, for as long as the backend stays installed. The slot must not be reused for another counter
+    /// before the counter has been deleted.
+    fn counter_slot(&mut self, _id: Id) -> Option<&'static AtomicU64> {
+        None
+    }
 }
 
 trait IntoHandle {
@@ -60,10 +205,19 @@ impl<T: Metrics + Sized> IntoHandle for T {
             increment_counter: increment_counter_raw::<Self>,
             increment_counter_by: increment_counter_by_raw::<Self>,
             new_histogram: new_histogram_raw::<Self>,
+            new_histogram_sampled: new_histogram_sampled_raw::<Self>,
             delete_histogram: delete_histogram_raw::<Self>,
             record: record_raw::<Self>,
+            flush: flush_raw::<Self>,
+            counter_slot: counter_slot_raw::<Self>,
+            drop: drop_raw::<Self>,
         };
-        MetricsHandle { ptr, vtable, name }
+        MetricsHandle {
+            ptr,
+            vtable,
+            name,
+            owned: true,
+        }
     }
 }
 
@@ -96,6 +250,12 @@ fn new_histogram_raw<T: Metrics>(ptr: *mut u8, name: &str, tags: Tags) -> Id {
     metrics.new_histogram(name, tags)
 }
 
+#[inline]
+fn new_histogram_sampled_raw<T: Metrics>(ptr: *mut u8, name: &str, tags: Tags, sample_rate: u32) -> Id {
+    let metrics = unsafe { &mut *(ptr as *mut T) };
+    metrics.new_histogram_sampled(name, tags, sample_rate)
+}
+
 #[inline]
 fn delete_histogram_raw<T: Metrics>(ptr: *mut u8, id: Id) {
     let metrics = unsafe { &mut *(ptr as *mut T) };
@@ -108,6 +268,23 @@ fn record_raw<T: Metrics>(ptr: *mut u8, id: Id, value: u64) {
     metrics.record(id, value)
 }
 
+#[inline]
+fn flush_raw<T: Metrics>(ptr: *mut u8) {
+    let metrics = unsafe { &mut *(ptr as *mut T) };
+    metrics.flush()
+}
+
+#[inline]
+fn counter_slot_raw<T: Metrics>(ptr: *mut u8, id: Id) -> Option<&'static AtomicU64> {
+    let metrics = unsafe { &mut *(ptr as *mut T) };
+    metrics.counter_slot(id)
+}
+
+#[inline]
+fn drop_raw<T: Metrics>(ptr: *mut u8) {
+    drop(unsafe { Box::from_raw(ptr as *mut T) })
+}
+
 /// Pre-allocated metric consists of name, id and tags.
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -146,8 +323,35 @@ impl PreAllocatedMetric {
             tags: tags.iter().map(|tag| (tag.0.to_owned(), tag.1.to_owned())).collect(),
         }
     }
+
+    /// Id of the pre-allocated metric.
+    pub const fn id(&self) -> Id {
+        match self {
+            PreAllocatedMetric::Counter { id, .. } => *id,
+            PreAllocatedMetric::Histogram { id, .. } => *id,
+        }
+    }
+
+    /// Name of the pre-allocated metric.
+    pub fn name(&self) -> &str {
+        match self {
+            PreAllocatedMetric::Counter { name, .. } => name,
+            PreAllocatedMetric::Histogram { name, .. } => name,
+        }
+    }
+}
+
+/// Kind of the metric registered with the backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MetricKind {
+    Counter,
+    Histogram,
 }
 
+/// Next id handed out by the no-op backend. Ids are unique so that the metrics created before the backend is set
+/// can be told apart when they are replayed against it.
+static NO_OP_NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
 /// A trivial no-op backend for the "uninitialized" state.
 struct NoOpMetrics;
 
@@ -157,7 +361,7 @@ impl Metrics for NoOpMetrics {
     }
 
     fn new_counter(&mut self, _name: &str, _tags: Tags) -> Id {
-        Id::default()
+        NO_OP_NEXT_ID.fetch_add(1, Ordering::Relaxed)
     }
 
     fn delete_counter(&mut self, _id: Id) {
@@ -169,7 +373,7 @@ impl Metrics for NoOpMetrics {
     }
 
     fn new_histogram(&mut self, _name: &str, _tags: Tags) -> Id {
-        Id::default()
+        NO_OP_NEXT_ID.fetch_add(1, Ordering::Relaxed)
     }
 
     fn delete_histogram(&mut self, _id: Id) {
@@ -189,14 +393,19 @@ const NO_OP_METRICS_VTABLE: MetricsVTable = MetricsVTable {
     increment_counter: increment_counter_raw::<NoOpMetrics>,
     increment_counter_by: increment_counter_by_raw::<NoOpMetrics>,
     new_histogram: new_histogram_raw::<NoOpMetrics>,
+    new_histogram_sampled: new_histogram_sampled_raw::<NoOpMetrics>,
     delete_histogram: delete_histogram_raw::<NoOpMetrics>,
     record: record_raw::<NoOpMetrics>,
+    flush: flush_raw::<NoOpMetrics>,
+    counter_slot: counter_slot_raw::<NoOpMetrics>,
+    drop: |_| {}, // never allocated
 };
 
 const NO_OP_METRICS_HANDLE: MetricsHandle = MetricsHandle {
     ptr: &NO_OP_METRICS as *const NoOpMetrics as *mut u8,
     vtable: NO_OP_METRICS_VTABLE,
     name: "no-op",
+    owned: false,
 };
 
 struct MetricsHolder {
@@ -210,16 +419,154 @@ static mut METRICS: MetricsHolder = MetricsHolder {
 
 /// Set a new metrics backend. This should be called as early as possible. Otherwise,
 /// all metrics calls will delegate to the `NoOpMetrics`.
+///
+/// Metrics that are still alive when the backend is replaced are registered again with the new backend, and
+/// their updates are forwarded to it under the new ids. This comes at the cost of an id lookup on every update,
+/// which is avoided if the backend is set before any metric is created. The live metrics are registered with the new
+/// backend while holding the registry lock, so its registration methods must not create metrics themselves. The
+/// previous backend is leaked, use [`take_metrics`] to drop it instead.
 pub fn set_metrics(metrics: impl Metrics) {
+    // previous backend is intentionally leaked as other threads may still be using it
+    std::mem::forget(replace_metrics(metrics));
+}
+
+/// Replace the active backend with the no-op backend and return the previous one, so that it can be dropped.
+/// Returns `None` if no backend has been set. As with [`set_metrics`], live metrics are carried over to the no-op
+/// backend and will be registered again with the next backend that is set.
+///
+/// # Safety
+///
+/// No other thread may be using the previous backend when it is dropped, i.e. the caller must ensure that no
+/// metric is created, updated or deleted concurrently with this call.
+pub unsafe fn take_metrics() -> Option<OwnedMetrics> {
+    replace_metrics(NoOpMetrics)
+}
+
+fn replace_metrics(metrics: impl Metrics) -> Option<OwnedMetrics> {
+    // hold the registry lock so that no metric can be registered with the previous backend during replacement
+    let registry = registry::lock();
+    let handle = match registry.is_empty() {
+        true => metrics.into_handle(),
+        false => registry.replay(metrics).into_handle(),
+    };
     #[allow(static_mut_refs)]
-    unsafe { &mut METRICS }
+    let previous = unsafe { &mut METRICS }
         .handle
-        .set(Box::leak(Box::new(metrics.into_handle())), Ordering::SeqCst);
+        .swap(Box::leak(Box::new(handle)), Ordering::SeqCst);
+    match unsafe { &*previous }.owned {
+        true => Some(OwnedMetrics {
+            handle: unsafe { Box::from_raw(previous) },
+        }),
+        false => None,
+    }
+}
+
+/// Metrics backend that has been removed with [`take_metrics`]. The backend is dropped together with this value.
+pub struct OwnedMetrics {
+    handle: Box<MetricsHandle>,
+}
+
+impl OwnedMetrics {
+    /// Name of the backend.
+    pub fn name(&self) -> &'static str {
+        self.handle.name
+    }
+}
+
+impl Drop for OwnedMetrics {
+    fn drop(&mut self) {
+        unsafe { self.handle.drop_backend() }
+    }
+}
+
+/// Set a metrics backend for the current thread only. It takes precedence over the global backend set with
+/// [`set_metrics`] until the returned guard is dropped, at which point the previously installed thread backend
+/// (if any) is restored and this backend is dropped.
+///
+/// Metrics created while the backend is installed are bound to it and, as their ids are only known to this backend,
+/// should not outlive the guard. Their updates are discarded once it has been uninstalled, even if another thread
+/// backend is still installed. Metrics created with the global backend never look up the thread backend, so their
+/// cost is unchanged.
+///
+/// ## Examples
+///
+/// ```
+/// use metricus::{Counter, CounterOps, Metrics, Id, Tags};
+///
+/// struct ReplayMetrics;
+///
+/// impl Metrics for ReplayMetrics {
+///     fn name(&self) -> &'static str { "replay" }
+///     fn new_counter(&mut self, _name: &str, _tags: Tags) -> Id { 0 }
+///     fn delete_counter(&mut self, _id: Id) {}
+///     fn increment_counter_by(&mut self, _id: Id, _delta: u64) {}
+///     fn new_histogram(&mut self, _name: &str, _tags: Tags) -> Id { 0 }
+///     fn delete_histogram(&mut self, _id: Id) {}
+///     fn record(&mut self, _id: Id, _value: u64) {}
+/// }
+///
+/// std::thread::spawn(|| {
+///     let _guard = metricus::set_thread_metrics(ReplayMetrics);
+///     assert_eq!("replay", metricus::get_metrics_backend_name());
+///     Counter::new("replayed", &[]).increment();
+/// })
+/// .join()
+/// .unwrap();
+///
+/// assert_eq!("no-op", metricus::get_metrics_backend_name());
+/// ```
+#[must_use = "the backend is uninstalled when the guard is dropped"]
+pub fn set_thread_metrics(metrics: impl Metrics) -> ThreadMetricsGuard {
+    let handle = Box::into_raw(Box::new(metrics.into_handle()));
+    let token = access::push_thread_metrics(handle);
+    ThreadMetricsGuard {
+        handle,
+        token,
+        _not_send: PhantomData,
+    }
+}
+
+/// Run the closure with the given metrics backend installed for the current thread. See [`set_thread_metrics`].
+pub fn with_metrics<R>(metrics: impl Metrics, f: impl FnOnce() -> R) -> R {
+    let _guard = set_thread_metrics(metrics);
+    f()
+}
+
+/// Uninstalls the thread backend set with [`set_thread_metrics`] when dropped.
+#[derive(Debug)]
+pub struct ThreadMetricsGuard {
+    handle: *mut MetricsHandle,
+    token: u64,
+    // the backend is installed for the thread that created the guard
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl Drop for ThreadMetricsGuard {
+    fn drop(&mut self) {
+        access::remove_thread_metrics(self.token);
+        let mut handle = unsafe { Box::from_raw(self.handle) };
+        unsafe { handle.drop_backend() };
+    }
+}
+
+/// Sends the updates buffered by the active backend on the calling thread, for backends that pre-aggregate the
+/// updates locally before sending them (such as the agent with local aggregation enabled). Call it before a thread
+/// goes idle or exits, so that its latest updates are not held back.
+///
+/// ```no_run
+/// use metricus::{Counter, CounterOps};
+///
+/// let counter = Counter::new("requests", &[]);
+/// counter.increment();
+/// metricus::flush_local();
+/// ```
+pub fn flush_local() {
+    access::with_current_metrics(|metrics| metrics.flush())
 }
 
 /// Get name of the active metrics backend.
 pub fn get_metrics_backend_name() -> &'static str {
-    get_metrics().name
+    access::with_current_metrics(|metrics| metrics.name)
 }
 
 struct MetricsVTable {
@@ -228,8 +575,12 @@ struct MetricsVTable {
     increment_counter: fn(*mut u8, Id),
     increment_counter_by: fn(*mut u8, Id, u64),
     new_histogram: fn(*mut u8, &str, Tags) -> Id,
+    new_histogram_sampled: fn(*mut u8, &str, Tags, u32) -> Id,
     delete_histogram: fn(*mut u8, Id),
     record: fn(*mut u8, Id, u64),
+    flush: fn(*mut u8),
+    counter_slot: fn(*mut u8, Id) -> Option<&'static AtomicU64>,
+    drop: fn(*mut u8),
 }
 
 /// Metrics backend handle.
@@ -237,6 +588,8 @@ pub struct MetricsHandle {
     ptr: *mut u8,
     vtable: MetricsVTable,
     name: &'static str,
+    /// Whether the backend has been allocated and can be dropped.
+    owned: bool,
 }
 
 impl MetricsHandle {
@@ -265,6 +618,11 @@ impl MetricsHandle {
         (self.vtable.new_histogram)(self.ptr, name, tags)
     }
 
+    #[inline]
+    fn new_histogram_sampled(&mut self, name: &str, tags: Tags, sample_rate: u32) -> Id {
+        (self.vtable.new_histogram_sampled)(self.ptr, name, tags, sample_rate)
+    }
+
     #[inline]
     fn delete_histogram(&mut self, id: Id) {
         (self.vtable.delete_histogram)(self.ptr, id)
@@ -274,6 +632,21 @@ impl MetricsHandle {
     fn record(&mut self, id: Id, value: u64) {
         (self.vtable.record)(self.ptr, id, value)
     }
+
+    #[inline]
+    fn flush(&mut self) {
+        (self.vtable.flush)(self.ptr)
+    }
+
+    #[inline]
+    fn counter_slot(&mut self, id: Id) -> Option<&'static AtomicU64> {
+        (self.vtable.counter_slot)(self.ptr, id)
+    }
+
+    /// Drops the backend behind this handle. The handle must not be used afterwards.
+    unsafe fn drop_backend(&mut self) {
+        (self.vtable.drop)(self.ptr)
+    }
 }
 
 struct AtomicRef<T> {
@@ -287,33 +660,206 @@ impl<T> AtomicRef<T> {
         }
     }
 
-    #[inline]
-    pub fn get(&self, order: Ordering) -> &T {
-        unsafe { &*self.ptr.load(order) }
-    }
-
     #[inline]
     pub fn get_mut(&mut self, order: Ordering) -> &mut T {
         unsafe { &mut *self.ptr.load(order) }
     }
 
     #[inline]
-    pub fn set(&self, new_ref: &T, order: Ordering) {
-        self.ptr.store(new_ref as *const T as *mut T, order);
+    pub fn swap(&self, new_ref: &T, order: Ordering) -> *mut T {
+        self.ptr.swap(new_ref as *const T as *mut T, order)
     }
 }
 
 mod access {
     use crate::{METRICS, MetricsHandle};
-    use std::sync::atomic::Ordering;
+    use std::cell::{Cell, RefCell};
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    /// Backend a metric has been created with, which receives all its updates.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Scope {
+        Global,
+        /// Backend installed for the thread that created the metric, identified by its token.
+        Thread(u64),
+    }
+
+    /// Number of threads that currently have a scoped backend installed. While zero, the thread-local
+    /// lookup is skipped when creating metrics.
+    static THREAD_OVERRIDES: AtomicUsize = AtomicUsize::new(0);
+    /// Token of the next thread backend, never reused so that a metric cannot reach a backend installed after its
+    /// own has been dropped.
+    static NEXT_TOKEN: AtomicU64 = AtomicU64::new(1);
+
+    thread_local! {
+        /// Backend installed for the current thread only, i.e. the top of the stack below.
+        static THREAD_METRICS: Cell<(u64, *mut MetricsHandle)> = const { Cell::new((0, std::ptr::null_mut())) };
+        /// Backends installed for the current thread, the last one taking precedence. Each is owned by its guard.
+        static THREAD_STACK: RefCell<Vec<(u64, *mut MetricsHandle)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Installs the backend for the current thread, on top of the ones already installed, and returns its token.
+    pub fn push_thread_metrics(handle: *mut MetricsHandle) -> u64 {
+        let token = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed);
+        THREAD_STACK.with_borrow_mut(|stack| {
+            if stack.is_empty() {
+                THREAD_OVERRIDES.fetch_add(1, Ordering::SeqCst);
+            }
+            stack.push((token, handle));
+        });
+        THREAD_METRICS.set((token, handle));
+        token
+    }
+
+    /// Uninstalls the backend from the current thread, wherever it is in the stack, so that the guards can be
+    /// dropped in any order.
+    pub fn remove_thread_metrics(token: u64) {
+        let top = THREAD_STACK.with_borrow_mut(|stack| {
+            stack.retain(|(installed, _)| *installed != token);
+            if stack.is_empty() {
+                THREAD_OVERRIDES.fetch_sub(1, Ordering::SeqCst);
+            }
+            stack.last().copied().unwrap_or((0, std::ptr::null_mut()))
+        });
+        THREAD_METRICS.set(top);
+    }
+
+    /// Scope of the metrics created now on the current thread.
+    pub fn current_scope() -> Scope {
+        if THREAD_OVERRIDES.load(Ordering::Relaxed) == 0 {
+            return Scope::Global;
+        }
+        match THREAD_METRICS.get() {
+            (_, handle) if handle.is_null() => Scope::Global,
+            (token, _) => Scope::Thread(token),
+        }
+    }
+
+    /// Runs the closure with the backend of the given scope. Updates of thread scoped metrics are discarded if the
+    /// backend they have been created with is no longer installed for the current thread. The global path is a
+    /// single pointer load.
+    #[inline]
+    pub fn with_metrics<R: Default>(scope: Scope, f: impl FnOnce(&mut MetricsHandle) -> R) -> R {
+        match scope {
+            Scope::Global => f(global_metrics()),
+            Scope::Thread(token) => with_thread_metrics(token, f).unwrap_or_default(),
+        }
+    }
+
+    /// Runs the closure with the thread backend of the given token, if it is still installed for the current
+    /// thread. The backend is owned by a guard that cannot be sent to another thread, so it cannot be dropped while
+    /// the closure runs.
+    #[inline]
+    pub fn with_thread_metrics<R>(token: u64, f: impl FnOnce(&mut MetricsHandle) -> R) -> Option<R> {
+        let handle = match THREAD_METRICS.get() {
+            (top, handle) if top == token => handle,
+            _ => THREAD_STACK.with_borrow(|stack| {
+                let (_, handle) = stack.iter().find(|(installed, _)| *installed == token)?;
+                Some(*handle)
+            })?,
+        };
+        unsafe { handle.as_mut() }.map(f)
+    }
 
+    /// Runs the closure with the backend of the metrics created now on the current thread.
+    pub fn with_current_metrics<R>(f: impl FnOnce(&mut MetricsHandle) -> R) -> R {
+        match current_scope() {
+            Scope::Global => f(global_metrics()),
+            Scope::Thread(token) => {
+                with_thread_metrics(token, f).unwrap_or_else(|| unreachable!("thread backend installed"))
+            }
+        }
+    }
+
+    /// Address of the global backend, which changes whenever it is replaced.
     #[allow(static_mut_refs)]
-    pub fn get_metrics_mut() -> &'static mut MetricsHandle {
-        unsafe { &mut METRICS }.handle.get_mut(Ordering::Acquire)
+    #[inline]
+    pub fn global_metrics_addr() -> usize {
+        unsafe { &METRICS }.handle.ptr.load(Ordering::Acquire) as usize
     }
 
+    /// Global backend, which is never dropped while in use (see [`take_metrics`](crate::take_metrics)).
     #[allow(static_mut_refs)]
-    pub fn get_metrics() -> &'static MetricsHandle {
-        unsafe { &METRICS }.handle.get(Ordering::Acquire)
+    #[inline]
+    pub fn global_metrics() -> &'static mut MetricsHandle {
+        unsafe { &mut METRICS }.handle.get_mut(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Logs the updates received by the backend, the ids being assigned from 1 by each backend.
+    #[derive(Clone, Default)]
+    struct Log {
+        next_id: Id,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Log {
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut *self.calls.lock().unwrap())
+        }
+
+        fn push(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+
+    impl Metrics for Log {
+        fn name(&self) -> &'static str {
+            "log"
+        }
+
+        fn new_counter(&mut self, name: &str, _tags: Tags) -> Id {
+            self.next_id += 1;
+            self.push(format!("new {name} {}", self.next_id));
+            self.next_id
+        }
+
+        fn delete_counter(&mut self, id: Id) {
+            self.push(format!("delete {id}"));
+        }
+
+        fn increment_counter_by(&mut self, id: Id, delta: u64) {
+            self.push(format!("increment {id} {delta}"));
+        }
+
+        fn new_histogram(&mut self, _name: &str, _tags: Tags) -> Id {
+            unreachable!()
+        }
+
+        fn delete_histogram(&mut self, _id: Id) {}
+
+        fn record(&mut self, _id: Id, _value: u64) {}
+    }
+
+    #[test]
+    fn should_dispatch_to_the_thread_backend_that_created_the_metric() {
+        let (outer, inner) = (Log::default(), Log::default());
+        let outer_guard = set_thread_metrics(outer.clone());
+        let first = Counter::new("first", &[]);
+        let inner_guard = set_thread_metrics(inner.clone());
+        let second = Counter::new("second", &[]);
+
+        first.increment_by(2);
+        second.increment_by(3);
+        assert_eq!(vec!["new first 1", "increment 1 2"], outer.take());
+        assert_eq!(vec!["new second 1", "increment 1 3"], inner.take());
+
+        // the outer backend is uninstalled first, its metrics must not reach the inner one with the same ids
+        drop(outer_guard);
+        first.increment();
+        drop(first);
+        second.increment();
+        assert!(outer.take().is_empty());
+        assert_eq!(vec!["increment 1 1"], inner.take());
+
+        drop(inner_guard);
+        second.increment();
+        drop(second);
+        assert!(inner.take().is_empty());
     }
 }
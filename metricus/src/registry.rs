@@ -0,0 +1,445 @@
+//! Tracks live metric registrations made against the global backend, so that they can be replayed when
+//! the backend is replaced.
+
+use crate::access::{self, Scope};
+use crate::counter::CounterSlot;
+use crate::{DYNAMIC_IDS, Id, MetricKind, Metrics, MetricsHandle, Tags};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry { live: BTreeMap::new() });
+
+#[derive(Debug)]
+struct Registration {
+    name: String,
+    tags: Vec<(String, String)>,
+    sample_rate: u32,
+    ref_count: usize,
+}
+
+/// Live registrations keyed by the id handed out to the metric, which stays valid across backend replacements.
+#[derive(Debug)]
+pub(crate) struct Registry {
+    live: BTreeMap<(MetricKind, Id), Registration>,
+}
+
+pub(crate) fn lock() -> MutexGuard<'static, Registry> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Registers the metric with the active backend and returns the scope its updates must be dispatched to. Metrics
+/// created with a thread scoped backend are not tracked. The sample rate only applies to histograms, `1` meaning
+/// that every value is recorded.
+///
+/// The backend is called without holding the registry lock, so that it can create metrics of its own. If the global
+/// backend has been replaced in the meantime, the metric is registered again with the new one.
+pub(crate) fn register(kind: MetricKind, name: &str, tags: Tags, sample_rate: u32) -> (Id, Scope) {
+    let scope = access::current_scope();
+    if let Scope::Thread(token) = scope {
+        let id = access::with_thread_metrics(token, |metrics| new_metric(metrics, kind, name, tags, sample_rate));
+        return (id.unwrap_or_default(), scope);
+    }
+    loop {
+        let metrics = access::global_metrics();
+        let id = new_metric(metrics, kind, name, tags, sample_rate);
+        let mut registry = lock();
+        if !std::ptr::eq(metrics, access::global_metrics()) {
+            // the new backend has replayed the registrations without this metric
+            drop(registry);
+            delete_metric(metrics, kind, id);
+            continue;
+        }
+        registry
+            .live
+            .entry((kind, id))
+            .or_insert_with(|| Registration {
+                name: name.to_owned(),
+                tags: tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+                sample_rate,
+                ref_count: 0,
+            })
+            .ref_count += 1;
+        return (id, scope);
+    }
+}
+
+/// Deletes the metric from the backend it has been created with. The backend is called after the registry lock has
+/// been released, with the global backend that was active when the registration was removed.
+pub(crate) fn unregister(kind: MetricKind, id: Id, scope: Scope) {
+    if let Scope::Thread(token) = scope {
+        access::with_thread_metrics(token, |metrics| delete_metric(metrics, kind, id));
+        return;
+    }
+    let metrics = {
+        let mut registry = lock();
+        if let Some(registration) = registry.live.get_mut(&(kind, id)) {
+            registration.ref_count -= 1;
+            if registration.ref_count == 0 {
+                registry.live.remove(&(kind, id));
+            }
+        }
+        access::global_metrics()
+    };
+    delete_metric(metrics, kind, id);
+}
+
+/// Slot of the counter registered with the global backend, if the backend keeps its counters in shared memory.
+pub(crate) fn counter_slot(id: Id) -> Option<CounterSlot> {
+    let metrics = access::global_metrics();
+    let backend = metrics as *const MetricsHandle as usize;
+    // a backend replacing the one the counter has been registered with remaps the ids and has no slots
+    metrics.counter_slot(id).map(|value| CounterSlot { value, backend })
+}
+
+fn new_metric(metrics: &mut MetricsHandle, kind: MetricKind, name: &str, tags: Tags, sample_rate: u32) -> Id {
+    match kind {
+        MetricKind::Counter => metrics.new_counter(name, tags),
+        MetricKind::Histogram if sample_rate > 1 => metrics.new_histogram_sampled(name, tags, sample_rate),
+        MetricKind::Histogram => metrics.new_histogram(name, tags),
+    }
+}
+
+fn delete_metric(metrics: &mut MetricsHandle, kind: MetricKind, id: Id) {
+    match kind {
+        MetricKind::Counter => metrics.delete_counter(id),
+        MetricKind::Histogram => metrics.delete_histogram(id),
+    }
+}
+
+impl Registry {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.live.is_empty()
+    }
+
+    /// Replays the live registrations against the new backend and returns it wrapped so that the ids already
+    /// handed out are mapped to the ones assigned by the new backend. A metric registered several times under the
+    /// same id is registered once with the new backend, and deleted from it when its last handle is dropped.
+    pub(crate) fn replay<M: Metrics>(&self, mut inner: M) -> Remapped<M> {
+        let mut counters = IdMap::default();
+        let mut histograms = IdMap::default();
+        for ((kind, id), registration) in self.live.iter() {
+            let tags = registration
+                .tags
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect::<Vec<_>>();
+            match kind {
+                MetricKind::Counter => {
+                    let inner_id = inner.new_counter(&registration.name, &tags);
+                    counters.insert(*id, inner_id, registration.ref_count);
+                }
+                MetricKind::Histogram => {
+                    let inner_id = match registration.sample_rate {
+                        1 => inner.new_histogram(&registration.name, &tags),
+                        sample_rate => inner.new_histogram_sampled(&registration.name, &tags, sample_rate),
+                    };
+                    histograms.insert(*id, inner_id, registration.ref_count);
+                }
+            }
+        }
+        let next_id = self
+            .live
+            .keys()
+            .filter(|(_, id)| DYNAMIC_IDS.contains(*id))
+            .map(|(_, id)| id + 1)
+            .max()
+            .unwrap_or_default();
+        Remapped {
+            inner,
+            counters,
+            histograms,
+            next_id,
+        }
+    }
+}
+
+/// Ids handed out below this limit are mapped by index rather than hashed.
+const DENSE_IDS: Id = 1 << 20;
+
+/// Maps the ids handed out to the ones assigned by the inner backend, along with the number of metrics holding each
+/// id. Backends assign ids sequentially, so they are looked up by index with a hash map fallback for sparse ids.
+#[derive(Debug, Default)]
+struct IdMap {
+    dense: Vec<Option<(Id, usize)>>,
+    sparse: HashMap<Id, (Id, usize)>,
+}
+
+impl IdMap {
+    #[inline]
+    fn get(&self, id: Id) -> Option<Id> {
+        match id < DENSE_IDS {
+            true => self.dense.get(id as usize).copied().flatten().map(|(id, _)| id),
+            false if self.sparse.is_empty() => None,
+            false => self.sparse.get(&id).map(|(id, _)| *id),
+        }
+    }
+
+    fn insert(&mut self, id: Id, inner_id: Id, ref_count: usize) {
+        match id < DENSE_IDS {
+            true => {
+                let index = id as usize;
+                if index >= self.dense.len() {
+                    self.dense.resize(index + 1, None);
+                }
+                self.dense[index] = Some((inner_id, ref_count));
+            }
+            false => {
+                self.sparse.insert(id, (inner_id, ref_count));
+            }
+        }
+    }
+
+    /// Releases one reference to the id and returns the inner id to delete once its last reference is released, which
+    /// is the id itself if it has not been mapped.
+    fn release(&mut self, id: Id) -> Option<Id> {
+        let entry = match id < DENSE_IDS {
+            true => self.dense.get_mut(id as usize).and_then(Option::take),
+            false => self.sparse.remove(&id),
+        };
+        match entry {
+            Some((inner_id, ref_count)) if ref_count > 1 => {
+                self.insert(id, inner_id, ref_count - 1);
+                None
+            }
+            Some((inner_id, _)) => Some(inner_id),
+            None => Some(id),
+        }
+    }
+}
+
+/// Backend installed in place of a new backend when there were live metrics at the time of replacement. It hands
+/// out its own ids so that they never collide with the ones held by the existing metrics, at the cost of an id
+/// lookup (indexed for sequential ids) on every update. Ids it does not know about (such as pre-allocated ones) are
+/// forwarded unchanged.
+#[derive(Debug)]
+pub(crate) struct Remapped<M> {
+    inner: M,
+    counters: IdMap,
+    histograms: IdMap,
+    next_id: Id,
+}
+
+impl<M: Metrics> Remapped<M> {
+    fn next_id(&mut self) -> Id {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+}
+
+impl<M: Metrics> Metrics for Remapped<M> {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn new_counter(&mut self, name: &str, tags: Tags) -> Id {
+        let inner_id = self.inner.new_counter(name, tags);
+        let id = self.next_id();
+        self.counters.insert(id, inner_id, 1);
+        id
+    }
+
+    fn delete_counter(&mut self, id: Id) {
+        if let Some(inner_id) = self.counters.release(id) {
+            self.inner.delete_counter(inner_id)
+        }
+    }
+
+    fn increment_counter_by(&mut self, id: Id, delta: u64) {
+        self.inner
+            .increment_counter_by(self.counters.get(id).unwrap_or(id), delta)
+    }
+
+    fn increment_counter(&mut self, id: Id) {
+        self.inner.increment_counter(self.counters.get(id).unwrap_or(id))
+    }
+
+    fn new_histogram(&mut self, name: &str, tags: Tags) -> Id {
+        let inner_id = self.inner.new_histogram(name, tags);
+        let id = self.next_id();
+        self.histograms.insert(id, inner_id, 1);
+        id
+    }
+
+    fn new_histogram_sampled(&mut self, name: &str, tags: Tags, sample_rate: u32) -> Id {
+        let inner_id = self.inner.new_histogram_sampled(name, tags, sample_rate);
+        let id = self.next_id();
+        self.histograms.insert(id, inner_id, 1);
+        id
+    }
+
+    fn delete_histogram(&mut self, id: Id) {
+        if let Some(inner_id) = self.histograms.release(id) {
+            self.inner.delete_histogram(inner_id)
+        }
+    }
+
+    fn record(&mut self, id: Id, value: u64) {
+        self.inner.record(self.histograms.get(id).unwrap_or(id), value)
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RESERVED_IDS;
+
+    /// Backend handing out ids from 100, so that they differ from the replayed ones.
+    #[derive(Default)]
+    struct Backend {
+        created: Vec<(Id, String, u32)>,
+        deleted: Vec<Id>,
+        increments: HashMap<Id, u64>,
+        records: Vec<(Id, u64)>,
+    }
+
+    impl Backend {
+        fn create(&mut self, name: &str, sample_rate: u32) -> Id {
+            let id = 100 + self.created.len() as Id;
+            self.created.push((id, name.to_owned(), sample_rate));
+            id
+        }
+    }
+
+    impl Metrics for Backend {
+        fn name(&self) -> &'static str {
+            "backend"
+        }
+
+        fn new_counter(&mut self, name: &str, _tags: Tags) -> Id {
+            self.create(name, 1)
+        }
+
+        fn delete_counter(&mut self, id: Id) {
+            self.deleted.push(id);
+        }
+
+        fn increment_counter_by(&mut self, id: Id, delta: u64) {
+            *self.increments.entry(id).or_default() += delta;
+        }
+
+        fn new_histogram(&mut self, name: &str, _tags: Tags) -> Id {
+            self.create(name, 1)
+        }
+
+        fn new_histogram_sampled(&mut self, name: &str, _tags: Tags, sample_rate: u32) -> Id {
+            self.create(name, sample_rate)
+        }
+
+        fn delete_histogram(&mut self, id: Id) {
+            self.deleted.push(id);
+        }
+
+        fn record(&mut self, id: Id, value: u64) {
+            self.records.push((id, value));
+        }
+
+        fn flush(&mut self) {}
+    }
+
+    fn registry(live: &[(MetricKind, Id, &str, u32, usize)]) -> Registry {
+        Registry {
+            live: live
+                .iter()
+                .map(|(kind, id, name, sample_rate, ref_count)| {
+                    let registration = Registration {
+                        name: name.to_string(),
+                        tags: vec![("venue".to_owned(), "xnas".to_owned())],
+                        sample_rate: *sample_rate,
+                        ref_count: *ref_count,
+                    };
+                    ((*kind, *id), registration)
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn should_replay_live_registrations_and_map_their_ids() {
+        let registry = registry(&[
+            (MetricKind::Counter, 3, "orders", 1, 1),
+            (MetricKind::Histogram, 3, "order_to_ack", 1, 1),
+            (MetricKind::Histogram, 7, "fill_latency", 10, 1),
+        ]);
+        let mut remapped = registry.replay(Backend::default());
+        assert_eq!(
+            vec![
+                (100, "orders".to_owned(), 1),
+                (101, "order_to_ack".to_owned(), 1),
+                (102, "fill_latency".to_owned(), 10)
+            ],
+            remapped.inner.created
+        );
+
+        // counters and histograms sharing an id are mapped separately
+        remapped.increment_counter_by(3, 2);
+        remapped.increment_counter(3);
+        remapped.record(3, 10);
+        remapped.record(7, 20);
+        assert_eq!(Some(&3), remapped.inner.increments.get(&100));
+        assert_eq!(vec![(101, 10), (102, 20)], remapped.inner.records);
+
+        // unknown ids, such as the pre-allocated ones, are forwarded unchanged
+        let reserved = RESERVED_IDS.start();
+        remapped.increment_counter(reserved);
+        assert_eq!(Some(&1), remapped.inner.increments.get(&reserved));
+    }
+
+    #[test]
+    fn should_hand_out_ids_after_the_replayed_ones() {
+        let registry = registry(&[
+            (MetricKind::Counter, 3, "orders", 1, 1),
+            (MetricKind::Counter, RESERVED_IDS.start(), "pre_allocated", 1, 1),
+        ]);
+        let mut remapped = registry.replay(Backend::default());
+
+        // the ids outside the dynamic range do not move the next id
+        let counter = remapped.new_counter("fills", &[]);
+        let histogram = remapped.new_histogram_sampled("fill_latency", &[], 4);
+        assert_eq!((4, 5), (counter, histogram));
+        remapped.increment_counter(counter);
+        remapped.record(histogram, 30);
+        assert_eq!(Some(&1), remapped.inner.increments.get(&102));
+        assert_eq!(vec![(103, 30)], remapped.inner.records);
+        assert_eq!((103, "fill_latency".to_owned(), 4), remapped.inner.created[3]);
+
+        remapped.delete_counter(counter);
+        remapped.delete_histogram(histogram);
+        assert_eq!(vec![102, 103], remapped.inner.deleted);
+    }
+
+    #[test]
+    fn should_delete_replayed_metric_once_released_by_all_handles() {
+        let registry = registry(&[(MetricKind::Counter, 3, "orders", 1, 2)]);
+        let mut remapped = registry.replay(Backend::default());
+
+        remapped.delete_counter(3);
+        assert!(remapped.inner.deleted.is_empty());
+        // the remaining handle still updates the replayed counter
+        remapped.increment_counter(3);
+        assert_eq!(Some(&1), remapped.inner.increments.get(&100));
+
+        remapped.delete_counter(3);
+        assert_eq!(vec![100], remapped.inner.deleted);
+        // ids that are no longer mapped are forwarded unchanged
+        remapped.delete_counter(3);
+        assert_eq!(vec![100, 3], remapped.inner.deleted);
+    }
+
+    #[test]
+    fn should_map_sparse_ids() {
+        let sparse = DENSE_IDS + 5;
+        let registry = registry(&[(MetricKind::Counter, sparse, "orders", 1, 1)]);
+        let mut remapped = registry.replay(Backend::default());
+
+        remapped.increment_counter_by(sparse, 4);
+        assert_eq!(Some(&4), remapped.inner.increments.get(&100));
+        assert_eq!(sparse + 1, remapped.new_counter("fills", &[]));
+        remapped.delete_counter(sparse);
+        assert_eq!(vec![100], remapped.inner.deleted);
+    }
+}
@@ -0,0 +1,303 @@
+//! Metrics backend that records every call so that instrumentation can be asserted on in tests.
+//!
+//! The [`RecordingMetrics`] backend can either be installed process wide with [`set_metrics`](crate::set_metrics)
+//! or scoped to the current thread with [`RecordingMetrics::install`] (see [`set_thread_metrics`]), which allows
+//! tests to run in parallel.
+//!
+//! Note that the metrics generated by the `#[counter]` and `#[span]` macros are static and register themselves
+//! only once per process, against whichever backend is active when they are first used. Prefer creating
+//! [`Counter`](crate::Counter) and [`Histogram`](crate::Histogram) instances directly in the code under test, or
+//! make sure the static metrics are first used while the recorder is installed.
+//!
+//! ## Examples
+//!
+//! ```
+//! use metricus::{Counter, CounterOps};
+//! use metricus::testing::RecordingMetrics;
+//!
+//! let metrics = RecordingMetrics::new();
+//! let _guard = metrics.install();
+//!
+//! let counter = Counter::new("orders", &[("venue", "xnas")]);
+//! counter.increment();
+//! counter.increment_by(2);
+//!
+//! metrics.assert_counter_incremented("orders", &[("venue", "xnas")], 2);
+//! metrics.assert_counter_value("orders", &[("venue", "xnas")], 3);
+//! ```
+
+pub use crate::MetricKind;
+use crate::{Id, Metrics, Tags, ThreadMetricsGuard, set_thread_metrics};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Owned metric tag.
+pub type OwnedTag = (String, String);
+
+/// Metric registered with the recording backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registration {
+    pub id: Id,
+    pub kind: MetricKind,
+    pub name: String,
+    pub tags: Vec<OwnedTag>,
+    /// Only 1 in `sample_rate` values is recorded, always `1` for counters.
+    pub sample_rate: u32,
+    /// Number of times the metric has been created and not deleted yet, as the same name and tags share an id.
+    pub ref_count: usize,
+    /// Every creation of the metric has been deleted.
+    pub deleted: bool,
+}
+
+/// Single call made against the recording backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Create { id: Id, kind: MetricKind },
+    Delete { id: Id, kind: MetricKind },
+    Increment { id: Id, delta: u64 },
+    Record { id: Id, value: u64 },
+}
+
+#[derive(Debug, Default)]
+struct Recording {
+    registrations: Vec<Registration>,
+    events: Vec<Event>,
+    /// Ids are never reused, even across [RecordingMetrics::clear], so that the events of a metric cannot be
+    /// attributed to another one.
+    next_id: Id,
+}
+
+impl Recording {
+    fn register(&mut self, kind: MetricKind, name: &str, tags: Tags, sample_rate: u32) -> Id {
+        let tags = to_owned_tags(tags);
+        let id = match self
+            .registrations
+            .iter_mut()
+            .find(|r| r.kind == kind && r.name == name && r.tags == tags)
+        {
+            Some(registration) => {
+                registration.ref_count += 1;
+                registration.deleted = false;
+                registration.id
+            }
+            None => {
+                let id = self.next_id;
+                self.next_id += 1;
+                self.registrations.push(Registration {
+                    id,
+                    kind,
+                    name: name.to_owned(),
+                    tags,
+                    sample_rate,
+                    ref_count: 1,
+                    deleted: false,
+                });
+                id
+            }
+        };
+        self.events.push(Event::Create { id, kind });
+        id
+    }
+
+    fn delete(&mut self, kind: MetricKind, id: Id) {
+        if let Some(registration) = self.registrations.iter_mut().find(|r| r.kind == kind && r.id == id) {
+            registration.ref_count = registration.ref_count.saturating_sub(1);
+            registration.deleted = registration.ref_count == 0;
+        }
+        self.events.push(Event::Delete { id, kind });
+    }
+
+    fn find(&self, kind: MetricKind, name: &str, tags: Tags) -> Option<Id> {
+        let tags = to_owned_tags(tags);
+        self.registrations
+            .iter()
+            .find(|r| r.kind == kind && r.name == name && r.tags == tags)
+            .map(|r| r.id)
+    }
+
+    fn increments(&self, id: Option<Id>) -> impl Iterator<Item = u64> + '_ {
+        self.events.iter().filter_map(move |event| match event {
+            Event::Increment { id: event_id, delta } if Some(*event_id) == id => Some(*delta),
+            _ => None,
+        })
+    }
+
+    fn records(&self, id: Option<Id>) -> impl Iterator<Item = u64> + '_ {
+        self.events.iter().filter_map(move |event| match event {
+            Event::Record { id: event_id, value } if Some(*event_id) == id => Some(*value),
+            _ => None,
+        })
+    }
+}
+
+/// Metrics backend that records all registrations, increments and recorded values. Cloning the backend
+/// returns a handle to the same recording, so one clone can be installed while the other is used for assertions.
+/// Tags are compared irrespective of their order.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingMetrics {
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl RecordingMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Installs the backend for the current thread only, taking precedence over the global backend until the
+    /// returned guard is dropped.
+    #[must_use = "the backend is uninstalled when the guard is dropped"]
+    pub fn install(&self) -> ThreadMetricsGuard {
+        set_thread_metrics(self.clone())
+    }
+
+    /// Returns all metrics registered so far.
+    pub fn registrations(&self) -> Vec<Registration> {
+        self.lock().registrations.clone()
+    }
+
+    /// Returns all calls made against the backend in the order they happened.
+    pub fn events(&self) -> Vec<Event> {
+        self.lock().events.clone()
+    }
+
+    /// Returns the individual increments of the counter with the given name and tags.
+    pub fn counter_increments(&self, name: &str, tags: Tags) -> Vec<u64> {
+        let recording = self.lock();
+        let id = recording.find(MetricKind::Counter, name, tags);
+        recording.increments(id).collect()
+    }
+
+    /// Returns the sum of all increments of the counter with the given name and tags.
+    pub fn counter_value(&self, name: &str, tags: Tags) -> u64 {
+        self.counter_increments(name, tags).iter().sum()
+    }
+
+    /// Returns the values recorded by the histogram with the given name and tags.
+    pub fn histogram_values(&self, name: &str, tags: Tags) -> Vec<u64> {
+        let recording = self.lock();
+        let id = recording.find(MetricKind::Histogram, name, tags);
+        recording.records(id).collect()
+    }
+
+    /// Asserts that the counter with the given name and tags was incremented exactly `times` times.
+    #[track_caller]
+    pub fn assert_counter_incremented(&self, name: &str, tags: Tags, times: usize) {
+        let increments = self.counter_increments(name, tags).len();
+        assert_eq!(
+            increments, times,
+            "expected counter {name} {tags:?} to be incremented {times} times but was incremented {increments} times"
+        );
+    }
+
+    /// Asserts that the increments of the counter with the given name and tags add up to `value`.
+    #[track_caller]
+    pub fn assert_counter_value(&self, name: &str, tags: Tags, value: u64) {
+        let actual = self.counter_value(name, tags);
+        assert_eq!(actual, value, "expected counter {name} {tags:?} to have value {value} but was {actual}");
+    }
+
+    /// Asserts that the histogram with the given name and tags recorded exactly the given values.
+    #[track_caller]
+    pub fn assert_histogram_recorded(&self, name: &str, tags: Tags, values: &[u64]) {
+        let actual = self.histogram_values(name, tags);
+        assert_eq!(actual, values, "expected histogram {name} {tags:?} to record {values:?} but recorded {actual:?}");
+    }
+
+    /// Discards all recorded events along with the registrations of the deleted metrics. The metrics still alive
+    /// keep their registration, so that their subsequent updates can still be asserted on.
+    ///
+    /// ```
+    /// use metricus::{Counter, CounterOps};
+    /// use metricus::testing::RecordingMetrics;
+    ///
+    /// let metrics = RecordingMetrics::new();
+    /// let _guard = metrics.install();
+    ///
+    /// let orders = Counter::new("orders", &[]);
+    /// orders.increment();
+    /// metrics.clear();
+    ///
+    /// let fills = Counter::new("fills", &[]);
+    /// orders.increment_by(2);
+    /// metrics.assert_counter_value("orders", &[], 2);
+    /// metrics.assert_counter_value("fills", &[], 0);
+    /// ```
+    pub fn clear(&self) {
+        let mut recording = self.lock();
+        recording.registrations.retain(|r| !r.deleted);
+        recording.events.clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Recording> {
+        self.recording.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Metrics for RecordingMetrics {
+    fn name(&self) -> &'static str {
+        "recording"
+    }
+
+    fn new_counter(&mut self, name: &str, tags: Tags) -> Id {
+        self.lock().register(MetricKind::Counter, name, tags, 1)
+    }
+
+    fn delete_counter(&mut self, id: Id) {
+        self.lock().delete(MetricKind::Counter, id)
+    }
+
+    fn increment_counter_by(&mut self, id: Id, delta: u64) {
+        self.lock().events.push(Event::Increment { id, delta })
+    }
+
+    fn new_histogram(&mut self, name: &str, tags: Tags) -> Id {
+        self.lock().register(MetricKind::Histogram, name, tags, 1)
+    }
+
+    fn new_histogram_sampled(&mut self, name: &str, tags: Tags, sample_rate: u32) -> Id {
+        self.lock().register(MetricKind::Histogram, name, tags, sample_rate)
+    }
+
+    fn delete_histogram(&mut self, id: Id) {
+        self.lock().delete(MetricKind::Histogram, id)
+    }
+
+    fn record(&mut self, id: Id, value: u64) {
+        self.lock().events.push(Event::Record { id, value })
+    }
+}
+
+fn to_owned_tags(tags: Tags) -> Vec<OwnedTag> {
+    let mut tags: Vec<OwnedTag> = tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    tags.sort();
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_keep_registration_until_last_creation_is_deleted() {
+        let mut metrics = RecordingMetrics::new();
+        let first = metrics.new_counter("orders", &[("venue", "xnas")]);
+        let second = metrics.new_counter("orders", &[("venue", "xnas")]);
+        assert_eq!(first, second);
+        assert_eq!(2, metrics.registrations()[0].ref_count);
+
+        // the other creation is still alive
+        metrics.delete_counter(first);
+        metrics.clear();
+        assert!(!metrics.registrations()[0].deleted);
+        metrics.increment_counter_by(second, 2);
+        metrics.assert_counter_value("orders", &[("venue", "xnas")], 2);
+
+        metrics.delete_counter(second);
+        assert!(metrics.registrations()[0].deleted);
+        metrics.clear();
+        assert!(metrics.registrations().is_empty());
+
+        // ids are not reused once the registration is gone
+        let third = metrics.new_counter("orders", &[("venue", "xnas")]);
+        assert_ne!(first, third);
+    }
+}
@@ -0,0 +1,212 @@
+//! Integration with [`tracing`](https://docs.rs/tracing) through a [`tracing_subscriber::Layer`].
+//!
+//! [`MetricsLayer`] records the duration of every span, from its creation until it is closed, into a histogram
+//! named after the span, with the selected span fields as tags. Durations are measured with the global clock (see
+//! [`set_clock`](crate::clock::set_clock)), like the spans of the histograms. Events are counted by level and
+//! target. Histograms and counters are registered once per callsite (and distinct field values), so there is no
+//! registration cost on the hot path. Spans without any selected field resolve their histogram with a single lookup
+//! by callsite, while only the selected fields of the other spans are formatted to find the histogram of their
+//! values.
+//!
+//! ## Examples
+//!
+//! ```no_run
+//! use metricus::tracing_layer::MetricsLayer;
+//! use tracing_subscriber::layer::SubscriberExt;
+//!
+//! let subscriber = tracing_subscriber::registry().with(MetricsLayer::new().with_fields(&["venue"]));
+//! tracing::subscriber::set_global_default(subscriber).unwrap();
+//!
+//! let span = tracing::info_span!("order_to_ack", venue = "xnas");
+//! drop(span); // duration recorded into `order_to_ack,venue=xnas`
+//! ```
+
+use crate::clock::Timestamp;
+use crate::{Counter, CounterOps, Histogram, HistogramOps};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+use tracing_core::callsite::Identifier;
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id};
+use tracing_core::subscriber::Interest;
+use tracing_core::{Event, Metadata, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+const DEFAULT_EVENTS_MEASUREMENT: &str = "events";
+
+/// Callsite along with its selected fields and their values, in the order they are declared.
+type SpanKey = (Identifier, Vec<(&'static str, String)>);
+
+/// Layer that records span durations (in nanoseconds) into histograms and counts events.
+#[derive(Debug)]
+pub struct MetricsLayer {
+    fields: Vec<&'static str>,
+    events_measurement: &'static str,
+    /// Histograms of the span callsites without any selected field, resolved when the callsite is registered.
+    callsites: RwLock<HashMap<Identifier, Arc<Histogram>>>,
+    /// Histograms of the span callsites with selected fields, per distinct field values.
+    histograms: RwLock<HashMap<SpanKey, Arc<Histogram>>>,
+    counters: RwLock<HashMap<Identifier, Counter>>,
+}
+
+impl Default for MetricsLayer {
+    fn default() -> Self {
+        Self {
+            fields: Vec::new(),
+            events_measurement: DEFAULT_EVENTS_MEASUREMENT,
+            callsites: Default::default(),
+            histograms: Default::default(),
+            counters: Default::default(),
+        }
+    }
+}
+
+impl MetricsLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Span fields to use as histogram tags. Other fields are ignored to keep the cardinality bounded.
+    pub fn with_fields(self, fields: &[&'static str]) -> Self {
+        Self {
+            fields: fields.to_vec(),
+            ..self
+        }
+    }
+
+    /// Measurement name of the event counters (`events` by default).
+    pub fn with_events_measurement(self, measurement: &'static str) -> Self {
+        Self {
+            events_measurement: measurement,
+            ..self
+        }
+    }
+
+    /// Whether the span has any of the selected fields, in which case its tags depend on the field values.
+    fn is_tagged(&self, metadata: &Metadata<'_>) -> bool {
+        metadata
+            .fields()
+            .iter()
+            .any(|field| self.fields.contains(&field.name()))
+    }
+
+    fn register_span(&self, metadata: &'static Metadata<'static>) {
+        if !self.is_tagged(metadata) {
+            self.callsites
+                .write()
+                .unwrap()
+                .entry(metadata.callsite())
+                .or_insert_with(|| Arc::new(Histogram::new(metadata.name(), &[])));
+        }
+    }
+
+    fn register_event(&self, metadata: &'static Metadata<'static>) -> &Self {
+        self.counters
+            .write()
+            .unwrap()
+            .entry(metadata.callsite())
+            .or_insert_with(|| {
+                let tags = [("level", metadata.level().as_str()), ("target", metadata.target())];
+                Counter::new(self.events_measurement, &tags)
+            });
+        self
+    }
+
+    fn histogram(&self, attrs: &Attributes<'_>) -> Arc<Histogram> {
+        let metadata = attrs.metadata();
+        if let Some(histogram) = self.callsites.read().unwrap().get(&metadata.callsite()) {
+            return histogram.clone();
+        }
+        if !self.is_tagged(metadata) {
+            // the callsite has been registered before the layer
+            self.register_span(metadata);
+            return self.histogram(attrs);
+        }
+
+        let mut visitor = FieldVisitor {
+            fields: &self.fields,
+            values: Vec::new(),
+        };
+        attrs.record(&mut visitor);
+        let key = (metadata.callsite(), visitor.values);
+        if let Some(histogram) = self.histograms.read().unwrap().get(&key) {
+            return histogram.clone();
+        }
+        let tags = key.1.iter().map(|(k, v)| (*k, v.as_str())).collect::<Vec<_>>();
+        let histogram = Arc::new(Histogram::new(metadata.name(), &tags));
+        self.histograms.write().unwrap().entry(key).or_insert(histogram).clone()
+    }
+
+    fn count(&self, event: &Event<'_>) {
+        let metadata = event.metadata();
+        if let Some(counter) = self.counters.read().unwrap().get(&metadata.callsite()) {
+            return counter.increment();
+        }
+        // the callsite has been registered before the layer
+        self.register_event(metadata).count(event);
+    }
+}
+
+/// Stored in the span extensions until the span is closed.
+struct Timing {
+    histogram: Arc<Histogram>,
+    start: Timestamp,
+}
+
+impl<S> Layer<S> for MetricsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if metadata.is_span() {
+            self.register_span(metadata);
+        } else if metadata.is_event() {
+            self.register_event(metadata);
+        }
+        Interest::always()
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Timing {
+                histogram: self.histogram(attrs),
+                start: Timestamp::now(),
+            });
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        self.count(event);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            if let Some(timing) = span.extensions().get::<Timing>() {
+                timing.histogram.record_since(timing.start);
+            }
+        }
+    }
+}
+
+/// Collects the values of the selected fields, which are visited in the order they are declared.
+struct FieldVisitor<'a> {
+    fields: &'a [&'static str],
+    values: Vec<(&'static str, String)>,
+}
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if self.fields.contains(&field.name()) {
+            self.values.push((field.name(), value.to_owned()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if self.fields.contains(&field.name()) {
+            self.values.push((field.name(), format!("{value:?}")));
+        }
+    }
+}
@@ -0,0 +1,78 @@
+#![cfg(all(feature = "metrics", feature = "testing"))]
+
+use metrics::{Key, Label, Metadata, Recorder};
+use metricus::Metrics;
+use metricus::facade::{MetricsFacade, MetricusRecorder};
+use metricus::testing::RecordingMetrics;
+
+fn key(name: &'static str) -> Key {
+    Key::from_parts(name, vec![Label::new("method", "GET")])
+}
+
+fn metadata() -> Metadata<'static> {
+    Metadata::new(module_path!(), metrics::Level::INFO, None)
+}
+
+#[test]
+fn should_route_counters() {
+    let metrics = RecordingMetrics::new();
+    let _guard = metrics.install();
+    let recorder = MetricusRecorder::new();
+
+    let counter = recorder.register_counter(&key("requests"), &metadata());
+    counter.increment(2);
+    counter.increment(3);
+    let absolute = recorder.register_counter(&key("bytes"), &metadata());
+    absolute.absolute(5);
+    absolute.absolute(4);
+    absolute.absolute(7);
+
+    metrics.assert_counter_value("requests", &[("method", "GET")], 5);
+    metrics.assert_counter_value("bytes", &[("method", "GET")], 7);
+    assert!(metrics.histogram_values("requests", &[("method", "GET")]).is_empty());
+}
+
+#[test]
+fn should_route_histograms() {
+    let metrics = RecordingMetrics::new();
+    let _guard = metrics.install();
+    let recorder = MetricusRecorder::new().with_scale(1e3);
+
+    let histogram = recorder.register_histogram(&key("latency"), &metadata());
+    histogram.record(0.5);
+    histogram.record(2.0);
+
+    metrics.assert_histogram_recorded("latency", &[("method", "GET")], &[500, 2000]);
+}
+
+#[test]
+fn should_route_gauges_as_up_and_down_counters() {
+    let metrics = RecordingMetrics::new();
+    let _guard = metrics.install();
+    let recorder = MetricusRecorder::new();
+
+    let gauge = recorder.register_gauge(&key("connections"), &metadata());
+    gauge.set(10.0);
+    gauge.decrement(4.0);
+    gauge.increment(1.0);
+    gauge.set(3.0);
+
+    let up = metrics.counter_value("connections", &[("method", "GET"), ("direction", "up")]);
+    let down = metrics.counter_value("connections", &[("method", "GET"), ("direction", "down")]);
+    assert_eq!((11, 8), (up, down));
+    assert!(metrics.histogram_values("connections", &[("method", "GET")]).is_empty());
+}
+
+#[test]
+fn should_reuse_facade_ids_of_deleted_metrics() {
+    let mut facade = MetricsFacade::new();
+    let requests = facade.new_counter("requests", &[]);
+    let latency = facade.new_histogram("latency", &[]);
+    assert_ne!(requests, latency);
+
+    facade.delete_counter(requests);
+    // deleting twice must not hand out the same id twice
+    facade.delete_counter(requests);
+    assert_eq!(requests, facade.new_histogram("latency_p99", &[]));
+    assert_eq!(latency + 1, facade.new_counter("bytes", &[]));
+}
@@ -0,0 +1,89 @@
+#![cfg(feature = "testing")]
+
+use metricus::testing::RecordingMetrics;
+use metricus_macros::span;
+use std::pin::{Pin, pin};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// Future that is pending once, so that the caller can wait while it is parked.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+        match std::mem::replace(&mut self.0, true) {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    }
+}
+
+/// Polls the future to completion, parking it for the given duration each time it is pending.
+fn block_on<F: Future>(future: F, parked: Duration) -> F::Output {
+    let mut future = pin!(future);
+    loop {
+        match future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::sleep(parked),
+        }
+    }
+}
+
+#[span(measurement = "latencies", tags(venue = "xnas"), async_mode = "both")]
+async fn timed_both(value: u64) -> u64 {
+    YieldNow(false).await;
+    value
+}
+
+#[span(measurement = "latencies", async_mode = "busy")]
+async fn timed_busy() {
+    YieldNow(false).await;
+}
+
+#[span(measurement = "latencies", split_on_result)]
+fn fallible(fail: bool) -> Result<impl Into<u64>, String> {
+    if fail {
+        return Err("rejected".to_owned());
+    }
+    let value: u32 = "5".parse().map_err(|_| "invalid".to_owned())?;
+    Ok(value)
+}
+
+#[test]
+fn should_record_wall_and_busy_time_of_async_fn() {
+    let metrics = RecordingMetrics::new();
+    let _guard = metrics.install();
+
+    assert_eq!(7, block_on(timed_both(7), Duration::from_millis(5)));
+    block_on(timed_busy(), Duration::from_millis(5));
+
+    let wall =
+        metrics.histogram_values("latencies", &[("fn_name", "timed_both"), ("venue", "xnas"), ("timing", "wall")]);
+    let busy =
+        metrics.histogram_values("latencies", &[("fn_name", "timed_both"), ("venue", "xnas"), ("timing", "busy")]);
+    assert_eq!((1, 1), (wall.len(), busy.len()));
+    // the time parked only counts towards the wall time
+    assert!(wall[0] >= busy[0] + 5_000_000, "wall {wall:?} busy {busy:?}");
+
+    let busy = metrics.histogram_values("latencies", &[("fn_name", "timed_busy"), ("timing", "busy")]);
+    assert_eq!(1, busy.len());
+    assert!(busy[0] < 5_000_000, "busy {busy:?}");
+    assert_eq!(3, metrics.registrations().len());
+}
+
+#[test]
+fn should_record_fallible_fn_by_outcome() {
+    let metrics = RecordingMetrics::new();
+    let _guard = metrics.install();
+
+    assert_eq!(5, fallible(false).unwrap().into());
+    assert_eq!(Err("rejected".to_owned()), fallible(true).map(Into::into));
+    assert_eq!(Err("rejected".to_owned()), fallible(true).map(Into::into));
+
+    let ok = metrics.histogram_values("latencies", &[("fn_name", "fallible"), ("outcome", "ok")]);
+    let err = metrics.histogram_values("latencies", &[("fn_name", "fallible"), ("outcome", "err")]);
+    // the early return is recorded as a failure
+    assert_eq!((1, 2), (ok.len(), err.len()));
+}
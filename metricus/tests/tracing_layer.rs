@@ -0,0 +1,48 @@
+#![cfg(all(feature = "tracing", feature = "testing"))]
+
+use metricus::clock::{MockClock, set_clock};
+use metricus::testing::RecordingMetrics;
+use metricus::tracing_layer::MetricsLayer;
+use std::time::Duration;
+use tracing_subscriber::layer::SubscriberExt;
+
+#[test]
+fn should_record_span_durations_with_global_clock() {
+    let clock = MockClock::new();
+    set_clock(clock.clone());
+    let metrics = RecordingMetrics::new();
+    let _guard = metrics.install();
+    let subscriber = tracing_subscriber::registry().with(MetricsLayer::new().with_fields(&["venue"]));
+
+    tracing::subscriber::with_default(subscriber, || {
+        for (venue, micros) in [("xnas", 5), ("arcx", 7), ("xnas", 3)] {
+            let span = tracing::info_span!("order_to_ack", venue, order_id = 42);
+            clock.advance(Duration::from_micros(micros));
+            drop(span);
+        }
+        let span = tracing::info_span!("untagged");
+        clock.advance(Duration::from_micros(1));
+        drop(span);
+    });
+
+    metrics.assert_histogram_recorded("order_to_ack", &[("venue", "xnas")], &[5_000, 3_000]);
+    metrics.assert_histogram_recorded("order_to_ack", &[("venue", "arcx")], &[7_000]);
+    metrics.assert_histogram_recorded("untagged", &[], &[1_000]);
+}
+
+#[test]
+fn should_count_events_by_level_and_target() {
+    let metrics = RecordingMetrics::new();
+    let _guard = metrics.install();
+    let subscriber = tracing_subscriber::registry().with(MetricsLayer::new().with_events_measurement("logs"));
+
+    tracing::subscriber::with_default(subscriber, || {
+        for _ in 0..3 {
+            tracing::info!(target: "orders", "order sent");
+        }
+        tracing::warn!(target: "orders", "order rejected");
+    });
+
+    metrics.assert_counter_value("logs", &[("level", "INFO"), ("target", "orders")], 3);
+    metrics.assert_counter_value("logs", &[("level", "WARN"), ("target", "orders")], 1);
+}
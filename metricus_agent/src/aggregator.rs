@@ -1,14 +1,21 @@
 use crate::affinity::Affinity;
-use crate::config::MetricsConfig;
+use crate::arena::CounterArena;
+use crate::config::{HistogramMode, MetricsConfig, TimestampMode};
+use crate::decoder::{CounterPoint, HistogramPoint, Point};
 use crate::exporter::Exporter;
-use crate::{ControlEvent, Error, OwnedTags, UpdateEvent};
-use log::error;
-use metricus::Id;
+use crate::local;
+use crate::query::{CounterSnapshot, HistogramSnapshot, HistogramStats, QueryReceiver, Selector, Snapshot};
+use crate::rules::{HistogramModes, Rules};
+use crate::{CardinalityHits, ControlEvent, Error, OwnedTags, ToOwnedTag, ToOwnedTags, UpdateEvent};
+use log::{error, warn};
+use metricus::{Id, Tags, empty_tags};
 #[cfg(feature = "rtrb")]
 use rtrb::Consumer;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::Write;
+use std::sync::Arc;
 #[cfg(not(feature = "rtrb"))]
 use std::sync::mpsc::Receiver;
 use std::thread::JoinHandle;
@@ -26,11 +33,25 @@ pub struct MetricsAggregator {
     rx_upd: Receiver<UpdateEvent>,
     #[cfg(not(feature = "rtrb"))]
     rx_cnc: Receiver<ControlEvent>,
+    rx_query: QueryReceiver,
     exporter: Exporter,
     counters: Counters,
     histograms: Histograms,
+    /// Metrics evicted after expiring, until they are updated again or deleted.
+    evicted: Evicted,
     next_flush_time_ns: u64,
     flush_interval_ns: u64,
+    align_flush_to_interval: bool,
+    timestamp_mode: TimestampMode,
+    metric_ttl_intervals: Option<u32>,
+    self_metrics: SelfMetrics,
+    /// Exporter specific rules applied when the metric is created.
+    rules: ExporterRules,
+    histogram_modes: HistogramModes,
+    /// Slots holding the values of the counters in the arena, read when flushing.
+    counter_arena: Option<Arc<CounterArena>>,
+    /// Registrations rejected by the agent due to the cardinality limits, reported when flushing.
+    cardinality_hits: CardinalityHits,
 }
 
 impl MetricsAggregator {
@@ -39,17 +60,45 @@ impl MetricsAggregator {
         #[cfg(feature = "rtrb")] rx_cnc: Consumer<ControlEvent>,
         #[cfg(not(feature = "rtrb"))] rx_upd: Receiver<UpdateEvent>,
         #[cfg(not(feature = "rtrb"))] rx_cnc: Receiver<ControlEvent>,
+        rx_query: QueryReceiver,
         exporter: Exporter,
-        flush_interval: Duration,
+        rules: Rules,
+        histogram_modes: HistogramModes,
+        config: &MetricsConfig,
     ) -> Self {
-        Self {
+        let mut aggregator = Self {
             rx_upd,
             rx_cnc,
+            rx_query,
             exporter,
             counters: Default::default(),
             histograms: Default::default(),
-            flush_interval_ns: flush_interval.as_nanos() as u64,
-            next_flush_time_ns: current_time_ns() + flush_interval.as_nanos() as u64,
+            evicted: Default::default(),
+            flush_interval_ns: config.flush_interval.as_nanos() as u64,
+            next_flush_time_ns: 0,
+            align_flush_to_interval: config.align_flush_to_interval,
+            timestamp_mode: config.timestamp_mode,
+            metric_ttl_intervals: config.metric_ttl_intervals,
+            self_metrics: SelfMetrics::new(config),
+            rules: ExporterRules::new(rules),
+            histogram_modes,
+            counter_arena: None,
+            cardinality_hits: Default::default(),
+        };
+        aggregator.next_flush_time_ns = aggregator.get_next_flush_time_ns(current_time_ns());
+        aggregator
+    }
+
+    /// Reads the values of the counters with a slot in the arena.
+    fn with_counter_arena(self, counter_arena: Option<Arc<CounterArena>>) -> Self {
+        Self { counter_arena, ..self }
+    }
+
+    /// Reports the cardinality limit hits counted by the agent.
+    fn with_cardinality_hits(self, cardinality_hits: CardinalityHits) -> Self {
+        Self {
+            cardinality_hits,
+            ..self
         }
     }
 
@@ -58,6 +107,9 @@ impl MetricsAggregator {
         #[cfg(feature = "rtrb")] rx_cnc: Consumer<ControlEvent>,
         #[cfg(not(feature = "rtrb"))] rx_upd: Receiver<UpdateEvent>,
         #[cfg(not(feature = "rtrb"))] rx_cnc: Receiver<ControlEvent>,
+        rx_query: QueryReceiver,
+        counter_arena: Option<Arc<CounterArena>>,
+        cardinality_hits: CardinalityHits,
         config: MetricsConfig,
     ) -> JoinHandle<()> {
         std::thread::Builder::new()
@@ -68,10 +120,20 @@ impl MetricsAggregator {
 
                 let exporter = config
                     .exporter
+                    .clone()
                     .try_into()
                     .inspect_err(|e| error!("unable to create exporter: {e}"))
                     .unwrap();
-                let mut aggregator = MetricsAggregator::new(rx_upd, rx_cnc, exporter, config.flush_interval);
+                let rules = Rules::try_from(config.exporter_rules.clone())
+                    .inspect_err(|e| error!("unable to create exporter rules: {e}"))
+                    .unwrap();
+                let histogram_modes = HistogramModes::new(config.histograms.clone(), config.flush_interval)
+                    .inspect_err(|e| error!("unable to create histogram modes: {e}"))
+                    .unwrap();
+                let mut aggregator =
+                    MetricsAggregator::new(rx_upd, rx_cnc, rx_query, exporter, rules, histogram_modes, &config)
+                        .with_counter_arena(counter_arena)
+                        .with_cardinality_hits(cardinality_hits);
                 loop {
                     aggregator
                         .poll()
@@ -86,25 +148,99 @@ impl MetricsAggregator {
     #[inline]
     fn poll(&mut self) -> crate::Result<()> {
         self.process_events()?;
+        self.process_queries();
         let now = current_time_ns();
         if now > self.next_flush_time_ns {
-            self.flush_metrics(now)?;
-            self.next_flush_time_ns = now + self.flush_interval_ns;
+            self.flush_metrics(self.get_flush_timestamp(now))?;
+            self.next_flush_time_ns = self.get_next_flush_time_ns(now);
         }
         Ok(())
     }
 
+    fn process_queries(&mut self) {
+        let requests = self.rx_query.try_iter().collect::<Vec<_>>();
+        if !requests.is_empty() {
+            self.read_counter_arena();
+        }
+        for request in requests {
+            // the caller might have timed out in the meantime
+            let _ = request.tx_reply.try_send(self.snapshot(&request.selector));
+        }
+    }
+
+    fn snapshot(&self, selector: &Selector) -> Snapshot {
+        let counters = self
+            .counters
+            .iter()
+            .filter(|(_, counter)| selector.matches(&counter.meta_data.name, &counter.meta_data.tags))
+            .map(|(id, counter)| CounterSnapshot {
+                id: *id,
+                name: counter.meta_data.name.clone(),
+                tags: counter.meta_data.tags.clone(),
+                value: counter.value,
+            })
+            .collect();
+        let histograms = self
+            .histograms
+            .iter()
+            .filter(|(_, histogram)| selector.matches(&histogram.meta_data.name, &histogram.meta_data.tags))
+            .flat_map(|(id, histogram)| {
+                histogram.windows().map(|(window, inner)| HistogramSnapshot {
+                    id: *id,
+                    name: histogram.meta_data.name.clone(),
+                    tags: histogram.meta_data.tags.clone(),
+                    window: window.map(str::to_owned),
+                    sample_rate: histogram.sample_rate,
+                    stats: HistogramStats::from(inner),
+                })
+            })
+            .collect();
+        Snapshot {
+            timestamp: current_time_ns(),
+            counters,
+            histograms,
+        }
+    }
+
+    /// Returns the time of the next flush, which is either one interval from `now` or the next
+    /// multiple of the interval since the unix epoch when flush alignment is enabled.
+    #[inline]
+    fn get_next_flush_time_ns(&self, now: u64) -> u64 {
+        if self.align_flush_to_interval && self.flush_interval_ns > 0 {
+            (now / self.flush_interval_ns + 1) * self.flush_interval_ns
+        } else {
+            now + self.flush_interval_ns
+        }
+    }
+
+    #[inline]
+    fn get_flush_timestamp(&self, now: u64) -> u64 {
+        match self.timestamp_mode {
+            TimestampMode::FlushTime => now,
+            TimestampMode::IntervalStart => self.next_flush_time_ns - self.flush_interval_ns,
+            TimestampMode::IntervalEnd => self.next_flush_time_ns,
+        }
+    }
+
     #[cfg(feature = "rtrb")]
     #[inline]
     fn process_events(&mut self) -> crate::Result<()> {
         if let Ok(chunk) = self.rx_cnc.read_chunk(self.rx_cnc.slots()) {
             for event in chunk {
-                Self::handle_control_event(&mut self.counters, &mut self.histograms, event)?;
+                Self::handle_control_event(
+                    &mut self.counters,
+                    &mut self.histograms,
+                    &mut self.evicted,
+                    &mut self.self_metrics,
+                    &mut self.rules,
+                    &self.histogram_modes,
+                    event,
+                )?;
             }
         }
         if let Ok(chunk) = self.rx_upd.read_chunk(self.rx_upd.slots()) {
             for event in chunk {
-                Self::handle_update_event(&mut self.counters, &mut self.histograms, event)?;
+                Self::handle_update_event(&mut self.counters, &mut self.histograms, &mut self.evicted, event)?;
             }
         }
         Ok(())
@@ -114,10 +250,18 @@ impl MetricsAggregator {
     #[inline]
     fn process_events(&mut self) -> crate::Result<()> {
         for event in self.rx_cnc.try_iter() {
-            Self::handle_control_event(&mut self.counters, &mut self.histograms, event)?;
+            Self::handle_control_event(
+                &mut self.counters,
+                &mut self.histograms,
+                &mut self.evicted,
+                &mut self.self_metrics,
+                &mut self.rules,
+                &self.histogram_modes,
+                event,
+            )?;
         }
         for event in self.rx_upd.try_iter() {
-            Self::handle_update_event(&mut self.counters, &mut self.histograms, event)?;
+            Self::handle_update_event(&mut self.counters, &mut self.histograms, &mut self.evicted, event)?;
         }
         Ok(())
     }
@@ -126,20 +270,70 @@ impl MetricsAggregator {
     fn handle_control_event(
         counters: &mut Counters,
         histograms: &mut Histograms,
+        evicted: &mut Evicted,
+        self_metrics: &mut SelfMetrics,
+        rules: &mut ExporterRules,
+        histogram_modes: &HistogramModes,
         event: ControlEvent,
     ) -> crate::Result<()> {
         match event {
-            ControlEvent::CounterCreate(id, name, tags) => {
-                counters.entry(id).or_insert_with(|| Counter::new(name, tags));
-            }
+            ControlEvent::CounterCreate(id, name, tags) => match counters.entry(id) {
+                Entry::Occupied(mut entry) => entry.get_mut().meta_data.retain(),
+                Entry::Vacant(_) if evicted.counters.contains_key(&id) => {
+                    evicted
+                        .counters
+                        .entry(id)
+                        .and_modify(|counter| counter.meta_data.retain());
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(Counter::new(rules.meta_data(name, tags)));
+                    self_metrics.increment(SelfMetric::CounterCreate, empty_tags());
+                }
+            },
             ControlEvent::CounterDelete(id) => {
-                counters.remove(&id);
-            }
-            ControlEvent::HistogramCreate(id, name, tags) => {
-                histograms.entry(id).or_insert_with(|| Histogram::new(name, tags));
+                // the counter is removed only after its final state has been flushed
+                if let Some(counter) = counters.get_mut(&id) {
+                    if counter.meta_data.release() {
+                        self_metrics.increment(SelfMetric::CounterDelete, empty_tags());
+                    }
+                } else if let Entry::Occupied(mut entry) = evicted.counters.entry(id) {
+                    // nothing left to flush for an evicted counter
+                    if entry.get_mut().meta_data.release() {
+                        rules.release(&entry.remove().meta_data);
+                        self_metrics.increment(SelfMetric::CounterDelete, empty_tags());
+                    }
+                }
             }
+            ControlEvent::HistogramCreate(id, name, tags, sample_rate) => match histograms.entry(id) {
+                Entry::Occupied(mut entry) => entry.get_mut().meta_data.retain(),
+                Entry::Vacant(_) if evicted.histograms.contains_key(&id) => {
+                    evicted
+                        .histograms
+                        .entry(id)
+                        .and_modify(|histogram| histogram.meta_data.retain());
+                }
+                Entry::Vacant(entry) => {
+                    // modes are matched against the series as exported, i.e. after the exporter rules
+                    let meta_data = rules.meta_data(name, tags);
+                    let modes = histogram_modes.get(&meta_data.name, &meta_data.tags);
+                    let flush_interval = histogram_modes.flush_interval();
+                    entry.insert(Histogram::new(meta_data, modes, flush_interval, sample_rate));
+                    self_metrics.increment(SelfMetric::HistogramCreate, empty_tags());
+                }
+            },
             ControlEvent::HistogramDelete(id) => {
-                histograms.remove(&id);
+                // the histogram is removed only after its final state has been flushed
+                if let Some(histogram) = histograms.get_mut(&id) {
+                    if histogram.meta_data.release() {
+                        self_metrics.increment(SelfMetric::HistogramDelete, empty_tags());
+                    }
+                } else if let Entry::Occupied(mut entry) = evicted.histograms.entry(id) {
+                    // nothing left to flush for an evicted histogram
+                    if entry.get_mut().meta_data.release() {
+                        rules.release(&entry.remove().meta_data);
+                        self_metrics.increment(SelfMetric::HistogramDelete, empty_tags());
+                    }
+                }
             }
         }
         Ok(())
@@ -149,33 +343,301 @@ impl MetricsAggregator {
     fn handle_update_event(
         counters: &mut Counters,
         histograms: &mut Histograms,
+        evicted: &mut Evicted,
         event: UpdateEvent,
     ) -> crate::Result<()> {
         match event {
             UpdateEvent::CounterIncrement(id, delta) => {
-                if let Some(counter) = counters.get_mut(&id) {
+                if let Some(counter) = evicted.counter(counters, id) {
                     counter.increment(delta);
+                    counter.meta_data.idle_intervals = 0;
                 }
             }
             UpdateEvent::HistogramRecord(id, value) => {
-                if let Some(histogram) = histograms.get_mut(&id) {
+                if let Some(histogram) = evicted.histogram(histograms, id) {
                     histogram.inner.record(value).map_err(Error::other)?;
+                    histogram.meta_data.idle_intervals = 0;
+                }
+            }
+            UpdateEvent::HistogramMerge(id, values) => {
+                if let Some(histogram) = evicted.histogram(histograms, id) {
+                    histogram.inner.add(&*values).map_err(Error::other)?;
+                    histogram.meta_data.idle_intervals = 0;
                 }
+                local::recycle(values);
             }
         }
         Ok(())
     }
 
+    /// Updates the counters with a slot in the arena with the current value of their slot.
+    fn read_counter_arena(&mut self) {
+        if let Some(arena) = self.counter_arena.as_deref() {
+            for (id, counter) in self.counters.iter_mut() {
+                if let Some(value) = arena.value(*id) {
+                    if value != counter.value {
+                        counter.value = value;
+                        counter.meta_data.idle_intervals = 0;
+                    }
+                }
+            }
+        }
+    }
+
     #[inline]
     fn flush_metrics(&mut self, timestamp: u64) -> crate::Result<()> {
+        self.read_counter_arena();
+        // expire metrics that have not been updated within the ttl
+        let ttl = self.metric_ttl_intervals;
+        self.counters
+            .values_mut()
+            .for_each(|counter| counter.meta_data.update_expiry(ttl));
+        self.histograms
+            .values_mut()
+            .for_each(|histogram| histogram.meta_data.update_expiry(ttl));
+        for histogram in self.histograms.values_mut() {
+            histogram.rotate()?;
+        }
+
+        let cardinality_hits = std::mem::take(&mut *self.cardinality_hits.lock().unwrap_or_else(|e| e.into_inner()));
+        for (name, hits) in cardinality_hits {
+            self.self_metrics
+                .increment_by(SelfMetric::CardinalityLimitHit, &[("measurement", &name)], hits);
+        }
+
         self.exporter.publish_counters(&self.counters, timestamp)?;
         self.exporter.publish_histograms(&self.histograms, timestamp)?;
+        self.exporter.publish_counters(&self.self_metrics.counters, timestamp)?;
         // clear histograms
         self.histograms
             .iter_mut()
-            .for_each(|(_, histogram)| histogram.inner.clear());
+            .for_each(|(_, histogram)| histogram.inner.reset());
+        // remove deleted metrics now that their final state has been flushed
+        let counter_arena = self.counter_arena.as_deref();
+        // the slots retired on the previous flush have had a full interval for their last updates to land
+        if let Some(arena) = counter_arena {
+            arena.recycle();
+        }
+        let rules = &mut self.rules;
+        self.counters.retain(|id, counter| {
+            if counter.meta_data.deleted {
+                if let Some(arena) = counter_arena {
+                    arena.release(*id);
+                }
+                rules.release(&counter.meta_data);
+            }
+            !counter.meta_data.deleted
+        });
+        self.histograms.retain(|_, histogram| {
+            if histogram.meta_data.deleted {
+                rules.release(&histogram.meta_data);
+            }
+            !histogram.meta_data.deleted
+        });
+        self.evict_expired();
         Ok(())
     }
+
+    /// Evicts the expired metrics, releasing the aggregated state of the histograms. The counters keep their
+    /// running total, so that they carry on from it if they are updated again. The counters with a slot in the arena
+    /// are kept as their value lives in the arena anyway.
+    fn evict_expired(&mut self) {
+        let counter_arena = self.counter_arena.as_deref();
+        let evicted = &mut self.evicted;
+        self.counters.retain(|id, counter| {
+            let evict = counter.meta_data.expired && counter_arena.is_none_or(|arena| arena.value(*id).is_none());
+            if evict {
+                let counter = std::mem::replace(counter, Counter::new(MetaData::default()));
+                evicted.counters.insert(*id, counter);
+            }
+            !evict
+        });
+        self.histograms.retain(|id, histogram| {
+            let evict = histogram.meta_data.expired;
+            if evict {
+                evicted.histograms.insert(
+                    *id,
+                    EvictedHistogram {
+                        meta_data: std::mem::take(&mut histogram.meta_data),
+                        modes: std::mem::take(&mut histogram.modes),
+                        flush_interval: histogram.flush_interval,
+                        sample_rate: histogram.sample_rate,
+                    },
+                );
+            }
+            !evict
+        });
+    }
+}
+
+/// Exporter rules applied when the metrics are created. The series of the exported metrics are tracked when the
+/// rules relabel metrics, so that a metric relabeled onto the series of another metric is not exported twice
+/// under the same series.
+struct ExporterRules {
+    rules: Rules,
+    series: HashSet<(String, OwnedTags)>,
+}
+
+impl ExporterRules {
+    fn new(rules: Rules) -> Self {
+        Self {
+            rules,
+            series: HashSet::new(),
+        }
+    }
+
+    /// Creates metadata with the exporter rules applied to the name and tags.
+    fn meta_data(&mut self, name: String, tags: OwnedTags) -> MetaData {
+        let Some((exported_name, exported_tags)) = self.rules.apply(&name, &tags) else {
+            return MetaData {
+                filtered: true,
+                ..MetaData::new(name, tags)
+            };
+        };
+        if self.rules.relabels() && !self.series.insert((exported_name.clone(), exported_tags.clone())) {
+            warn!(
+                "exporter rules relabel {name} {tags:?} onto the series {exported_name} {exported_tags:?} of another \
+                 metric, it will not be exported"
+            );
+            return MetaData {
+                filtered: true,
+                ..MetaData::new(name, tags)
+            };
+        }
+        MetaData::new(exported_name, exported_tags)
+    }
+
+    /// Releases the series of a removed metric.
+    fn release(&mut self, meta_data: &MetaData) {
+        if self.rules.relabels() && !meta_data.filtered {
+            self.series.remove(&(meta_data.name.clone(), meta_data.tags.clone()));
+        }
+    }
+}
+
+/// Metrics evicted after expiring, until they are updated again or deleted. An evicted counter is kept along with
+/// its running total, which is small and must not go backwards, whereas only the metadata of an evicted histogram
+/// is kept and the histogram is recreated from scratch when it is updated again.
+#[derive(Default)]
+struct Evicted {
+    counters: HashMap<Id, Counter>,
+    histograms: HashMap<Id, EvictedHistogram>,
+}
+
+struct EvictedHistogram {
+    meta_data: MetaData,
+    modes: Vec<HistogramMode>,
+    flush_interval: Duration,
+    sample_rate: u32,
+}
+
+impl Evicted {
+    /// Returns the counter to update, recreating it if it has been evicted.
+    #[inline]
+    fn counter<'a>(&mut self, counters: &'a mut Counters, id: Id) -> Option<&'a mut Counter> {
+        match counters.entry(id) {
+            Entry::Occupied(entry) => Some(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let counter = self.counters.remove(&id)?;
+                Some(entry.insert(Counter {
+                    meta_data: counter.meta_data.revive(),
+                    ..counter
+                }))
+            }
+        }
+    }
+
+    /// Returns the histogram to update, recreating it if it has been evicted.
+    #[inline]
+    fn histogram<'a>(&mut self, histograms: &'a mut Histograms, id: Id) -> Option<&'a mut Histogram> {
+        match histograms.entry(id) {
+            Entry::Occupied(entry) => Some(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let evicted = self.histograms.remove(&id)?;
+                Some(entry.insert(Histogram::new(
+                    evicted.meta_data.revive(),
+                    &evicted.modes,
+                    evicted.flush_interval,
+                    evicted.sample_rate,
+                )))
+            }
+        }
+    }
+}
+
+/// Metrics about the agent itself, reported under the `metricus` measurement.
+struct SelfMetrics {
+    counters: Counters,
+    ids: HashMap<(SelfMetric, OwnedTags), Id>,
+    default_tags: OwnedTags,
+    report_metric_lifecycle: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum SelfMetric {
+    CounterCreate,
+    CounterDelete,
+    HistogramCreate,
+    HistogramDelete,
+    CardinalityLimitHit,
+}
+
+impl SelfMetric {
+    const fn event(&self) -> &'static str {
+        match self {
+            SelfMetric::CounterCreate => "counter_create",
+            SelfMetric::CounterDelete => "counter_delete",
+            SelfMetric::HistogramCreate => "histogram_create",
+            SelfMetric::HistogramDelete => "histogram_delete",
+            SelfMetric::CardinalityLimitHit => "cardinality_limit_hit",
+        }
+    }
+
+    const fn is_enabled(&self, self_metrics: &SelfMetrics) -> bool {
+        match self {
+            SelfMetric::CounterCreate
+            | SelfMetric::CounterDelete
+            | SelfMetric::HistogramCreate
+            | SelfMetric::HistogramDelete => self_metrics.report_metric_lifecycle,
+            SelfMetric::CardinalityLimitHit => true,
+        }
+    }
+}
+
+impl SelfMetrics {
+    fn new(config: &MetricsConfig) -> Self {
+        Self {
+            counters: Default::default(),
+            ids: Default::default(),
+            default_tags: config.default_tags.clone(),
+            report_metric_lifecycle: config.report_metric_lifecycle,
+        }
+    }
+
+    fn increment(&mut self, metric: SelfMetric, tags: Tags) {
+        self.increment_by(metric, tags, 1);
+    }
+
+    fn increment_by(&mut self, metric: SelfMetric, tags: Tags, delta: u64) {
+        if !metric.is_enabled(self) {
+            return;
+        }
+        let mut tags = tags.to_owned_tags();
+        tags.push(("event", metric.event()).to_owned_tag());
+        tags.push(("type", "counter").to_owned_tag());
+        tags.extend(self.default_tags.clone());
+        tags.sort();
+        tags.dedup();
+
+        let next_id = self.ids.len() as Id;
+        let id = *self.ids.entry((metric, tags.clone())).or_insert(next_id);
+        let counter = self
+            .counters
+            .entry(id)
+            .or_insert_with(|| Counter::new(MetaData::new("metricus".to_owned(), tags)));
+        counter.increment(delta);
+        counter.meta_data.idle_intervals = 0;
+    }
 }
 
 #[derive(Serialize)]
@@ -186,11 +648,8 @@ pub struct Counter {
 }
 
 impl Counter {
-    fn new(name: String, tags: OwnedTags) -> Self {
-        Self {
-            value: 0,
-            meta_data: MetaData::new(name, tags),
-        }
+    fn new(meta_data: MetaData) -> Self {
+        Self { value: 0, meta_data }
     }
 
     fn increment(&mut self, delta: u64) {
@@ -201,26 +660,194 @@ impl Counter {
 pub struct Histogram {
     inner: hdrhistogram::Histogram<u64>,
     meta_data: MetaData,
+    windows: Vec<Window>,
+    /// Modes the windows have been created from, kept to recreate the histogram after it has been evicted.
+    modes: Vec<HistogramMode>,
+    flush_interval: Duration,
+    /// Only 1 in `sample_rate` values has been recorded, so the counts must be scaled up by this factor.
+    sample_rate: u32,
 }
 
 impl Histogram {
-    fn new(name: String, tags: OwnedTags) -> Self {
+    fn new(meta_data: MetaData, modes: &[HistogramMode], flush_interval: Duration, sample_rate: u32) -> Self {
         Self {
             inner: hdrhistogram::Histogram::<u64>::new(3).unwrap(), // will never fail
-            meta_data: MetaData::new(name, tags),
+            meta_data,
+            windows: modes.iter().map(|mode| Window::new(mode, flush_interval)).collect(),
+            modes: modes.to_vec(),
+            flush_interval,
+            sample_rate: sample_rate.max(1),
         }
     }
+
+    /// Folds the values recorded during the current interval into the cumulative and sliding windows.
+    fn rotate(&mut self) -> crate::Result<()> {
+        for window in self.windows.iter_mut() {
+            window.rotate(&self.inner)?;
+        }
+        Ok(())
+    }
+
+    /// Distributions to be exported for this histogram, each labelled with the window it represents.
+    /// The label is absent for the interval window.
+    pub fn windows(&self) -> impl Iterator<Item = (Option<&str>, &hdrhistogram::Histogram<u64>)> {
+        self.windows.iter().map(|window| match window {
+            Window::Interval => (None, &self.inner),
+            Window::Cumulative(inner) => (Some("cumulative"), inner),
+            Window::Sliding { label, merged, .. } => (Some(label.as_str()), merged),
+        })
+    }
 }
 
-#[derive(Serialize)]
-struct MetaData {
+/// Distribution exported for the histogram according to its [HistogramMode].
+enum Window {
+    Interval,
+    Cumulative(hdrhistogram::Histogram<u64>),
+    Sliding {
+        label: String,
+        /// One sub-histogram per flush interval, oldest first.
+        slots: VecDeque<hdrhistogram::Histogram<u64>>,
+        capacity: usize,
+        merged: hdrhistogram::Histogram<u64>,
+    },
+}
+
+impl Window {
+    fn new(mode: &HistogramMode, flush_interval: Duration) -> Self {
+        match mode {
+            HistogramMode::Interval => Window::Interval,
+            HistogramMode::Cumulative => Window::Cumulative(hdrhistogram::Histogram::<u64>::new(3).unwrap()),
+            HistogramMode::Window { length } => {
+                let capacity = (length.as_nanos().div_ceil(flush_interval.as_nanos().max(1)) as usize).max(1);
+                Window::Sliding {
+                    label: format_window_length(*length),
+                    slots: VecDeque::with_capacity(capacity),
+                    capacity,
+                    merged: hdrhistogram::Histogram::<u64>::new(3).unwrap(),
+                }
+            }
+        }
+    }
+
+    fn rotate(&mut self, interval: &hdrhistogram::Histogram<u64>) -> crate::Result<()> {
+        match self {
+            Window::Interval => {}
+            Window::Cumulative(inner) => inner.add(interval).map_err(Error::other)?,
+            Window::Sliding {
+                slots,
+                capacity,
+                merged,
+                ..
+            } => {
+                // reuse the oldest sub-histogram once the window is full
+                let mut slot = match slots.len() == *capacity {
+                    true => slots.pop_front().unwrap(),
+                    false => hdrhistogram::Histogram::<u64>::new(3).unwrap(),
+                };
+                slot.reset();
+                slot.add(interval).map_err(Error::other)?;
+                slots.push_back(slot);
+
+                merged.reset();
+                for slot in slots.iter() {
+                    merged.add(slot).map_err(Error::other)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats the window length in the largest whole unit, e.g. `5m`.
+fn format_window_length(length: Duration) -> String {
+    let millis = length.as_millis();
+    match millis {
+        _ if millis > 0 && millis % 3_600_000 == 0 => format!("{}h", millis / 3_600_000),
+        _ if millis > 0 && millis % 60_000 == 0 => format!("{}m", millis / 60_000),
+        _ if millis > 0 && millis % 1_000 == 0 => format!("{}s", millis / 1_000),
+        _ => format!("{millis}ms"),
+    }
+}
+
+/// Common interface of the aggregated metrics.
+pub trait Metric {
+    fn meta_data(&self) -> &MetaData;
+}
+
+impl Metric for Counter {
+    fn meta_data(&self) -> &MetaData {
+        &self.meta_data
+    }
+}
+
+impl Metric for Histogram {
+    fn meta_data(&self) -> &MetaData {
+        &self.meta_data
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct MetaData {
     name: String,
     tags: OwnedTags,
+    /// Number of flush intervals since the last update.
+    #[serde(skip)]
+    idle_intervals: u32,
+    #[serde(skip)]
+    expired: bool,
+    /// Number of create events not yet matched by a delete event.
+    #[serde(skip)]
+    ref_count: usize,
+    #[serde(skip)]
+    deleted: bool,
+    /// Metric has been filtered out by the exporter rules.
+    #[serde(skip)]
+    filtered: bool,
 }
 
 impl MetaData {
     fn new(name: String, tags: OwnedTags) -> Self {
-        Self { name, tags }
+        Self {
+            name,
+            tags,
+            idle_intervals: 0,
+            expired: false,
+            ref_count: 1,
+            deleted: false,
+            filtered: false,
+        }
+    }
+
+    fn retain(&mut self) {
+        self.ref_count += 1;
+        self.deleted = false;
+    }
+
+    /// Returns true if the last reference has been released and the metric has been marked as deleted.
+    fn release(&mut self) -> bool {
+        self.ref_count = self.ref_count.saturating_sub(1);
+        let deleted = self.ref_count == 0 && !self.deleted;
+        self.deleted |= deleted;
+        deleted
+    }
+
+    /// Metric is exported unless it has been filtered out or has not been updated within the ttl.
+    pub const fn is_exported(&self) -> bool {
+        !self.expired && !self.filtered
+    }
+
+    /// Resets the expiry of a metric recreated after it has been evicted.
+    fn revive(self) -> Self {
+        Self {
+            idle_intervals: 0,
+            expired: false,
+            ..self
+        }
+    }
+
+    fn update_expiry(&mut self, ttl_intervals: Option<u32>) {
+        self.expired = matches!(ttl_intervals, Some(ttl) if self.idle_intervals >= ttl);
+        self.idle_intervals = self.idle_intervals.saturating_add(1);
     }
 }
 
@@ -229,141 +856,639 @@ impl MetaData {
 pub enum Encoder {
     LineProtocol,
     Json,
+    /// StatsD with DogStatsD style tags.
+    Statsd,
 }
 
 impl Encoder {
     pub fn encode_counter(&self, counter: &Counter, timestamp: u64, dst: &mut impl Write) -> std::io::Result<()> {
+        self.encode_counter_record(&CounterRecord::new(counter, timestamp), dst)
+    }
+
+    pub fn encode_histogram(&self, histogram: &Histogram, timestamp: u64, dst: &mut impl Write) -> std::io::Result<()> {
+        for (window, inner) in histogram.windows() {
+            self.encode_histogram_record(&HistogramRecord::new(histogram, window, inner, timestamp), dst)?;
+        }
+        Ok(())
+    }
+
+    /// Encodes a point decoded by the [`decoder`](crate::decoder), e.g. to convert between the formats.
+    pub fn encode_point(&self, point: &Point, dst: &mut impl Write) -> std::io::Result<()> {
+        match point {
+            Point::Counter(counter) => self.encode_counter_record(&CounterRecord::from(counter), dst),
+            Point::Histogram(histogram) => self.encode_histogram_record(&HistogramRecord::from(histogram), dst),
+        }
+    }
+
+    fn encode_counter_record(&self, counter: &CounterRecord, dst: &mut impl Write) -> std::io::Result<()> {
         match self {
-            Encoder::LineProtocol => LineProtocol::encode_counter(counter, timestamp, dst),
-            Encoder::Json => Json::encode_counter(counter, timestamp, dst),
+            Encoder::LineProtocol => LineProtocol::encode_counter(counter, dst),
+            Encoder::Json => Json::encode(counter, dst),
+            Encoder::Statsd => Statsd::encode_counter(counter, dst),
         }
     }
 
-    pub fn encode_histogram(&self, histogram: &Histogram, timestamp: u64, dst: &mut impl Write) -> std::io::Result<()> {
+    fn encode_histogram_record(&self, histogram: &HistogramRecord, dst: &mut impl Write) -> std::io::Result<()> {
         match self {
-            Encoder::LineProtocol => LineProtocol::encode_histogram(histogram, timestamp, dst),
-            Encoder::Json => Ok(()),
+            Encoder::LineProtocol => LineProtocol::encode_histogram(histogram, dst),
+            Encoder::Json => Json::encode(histogram, dst),
+            Encoder::Statsd => Statsd::encode_histogram(histogram, dst),
         }
     }
 }
 
+/// Measurement name and tags of an encoded metric.
+#[derive(Serialize)]
+struct Series<'a> {
+    name: &'a str,
+    tags: &'a OwnedTags,
+}
+
+/// Counter as written by the encoders.
+#[derive(Serialize)]
+struct CounterRecord<'a> {
+    timestamp: u64,
+    value: u64,
+    #[serde(flatten)]
+    series: Series<'a>,
+}
+
+impl<'a> CounterRecord<'a> {
+    fn new(counter: &'a Counter, timestamp: u64) -> Self {
+        Self {
+            timestamp,
+            value: counter.value,
+            series: Series {
+                name: &counter.meta_data.name,
+                tags: &counter.meta_data.tags,
+            },
+        }
+    }
+}
+
+impl<'a> From<&'a CounterPoint> for CounterRecord<'a> {
+    fn from(counter: &'a CounterPoint) -> Self {
+        Self {
+            timestamp: counter.timestamp,
+            value: counter.value,
+            series: Series {
+                name: &counter.name,
+                tags: &counter.tags,
+            },
+        }
+    }
+}
+
+/// Distribution of one of the histogram windows as written by the encoders.
+#[derive(Serialize)]
+struct HistogramRecord<'a> {
+    timestamp: u64,
+    #[serde(flatten)]
+    series: Series<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    window: Option<&'a str>,
+    /// Only 1 in `sample_rate` values has been recorded, exported as the fraction of the values recorded.
+    #[serde(skip_serializing_if = "is_unsampled", serialize_with = "serialize_sample_rate")]
+    sample_rate: u32,
+    #[serde(flatten)]
+    stats: HistogramStats,
+}
+
+impl<'a> HistogramRecord<'a> {
+    fn new(
+        histogram: &'a Histogram,
+        window: Option<&'a str>,
+        inner: &hdrhistogram::Histogram<u64>,
+        timestamp: u64,
+    ) -> Self {
+        Self {
+            timestamp,
+            series: Series {
+                name: &histogram.meta_data.name,
+                tags: &histogram.meta_data.tags,
+            },
+            window,
+            sample_rate: histogram.sample_rate,
+            stats: HistogramStats::from(inner),
+        }
+    }
+
+    /// Fraction of the values that have been recorded, or `None` if the histogram is not sampled.
+    fn sampled_fraction(&self) -> Option<f64> {
+        (!is_unsampled(&self.sample_rate)).then(|| 1.0 / self.sample_rate as f64)
+    }
+}
+
+impl<'a> From<&'a HistogramPoint> for HistogramRecord<'a> {
+    fn from(histogram: &'a HistogramPoint) -> Self {
+        Self {
+            timestamp: histogram.timestamp,
+            series: Series {
+                name: &histogram.name,
+                tags: &histogram.tags,
+            },
+            window: histogram.window.as_deref(),
+            sample_rate: histogram.sample_rate.max(1),
+            stats: histogram.stats,
+        }
+    }
+}
+
+const fn is_unsampled(sample_rate: &u32) -> bool {
+    *sample_rate <= 1
+}
+
+fn serialize_sample_rate<S: Serializer>(sample_rate: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(1.0 / *sample_rate as f64)
+}
+
+/// Measurement names are escaped for commas, spaces and backslashes, tag keys and values also for equal signs, so that
+/// a backslash ending a name or tag cannot escape the following separator.
 struct LineProtocol;
 
 impl LineProtocol {
-    fn encode_counter(counter: &Counter, timestamp: u64, dst: &mut impl Write) -> std::io::Result<()> {
-        // measurement
-        dst.write_all(counter.meta_data.name.as_bytes())?;
-        // tags
-        for tag in counter.meta_data.tags.iter() {
-            dst.write_all(b",")?;
-            dst.write_all(tag.0.as_bytes())?;
-            dst.write_all(b"=")?;
-            dst.write_all(tag.1.as_bytes())?;
-        }
+    fn encode_counter(counter: &CounterRecord, dst: &mut impl Write) -> std::io::Result<()> {
+        // measurement and tags
+        Self::encode_series(&counter.series, None, dst)?;
         // field
         dst.write_all(b" value=")?;
         dst.write_all(itoa::Buffer::new().format(counter.value).as_bytes())?;
         dst.write_all(b"u ")?;
         // timestamp
-        dst.write_all(itoa::Buffer::new().format(timestamp).as_bytes())?;
+        dst.write_all(itoa::Buffer::new().format(counter.timestamp).as_bytes())?;
         // new line
         dst.write_all(b"\n")?;
         Ok(())
     }
 
-    fn encode_histogram(histogram: &Histogram, timestamp: u64, dst: &mut impl Write) -> std::io::Result<()> {
-        // measurement
-        dst.write_all(histogram.meta_data.name.as_bytes())?;
-        // tags
-        for tag in histogram.meta_data.tags.iter() {
-            dst.write_all(b",")?;
-            dst.write_all(tag.0.as_bytes())?;
-            dst.write_all(b"=")?;
-            dst.write_all(tag.1.as_bytes())?;
-        }
+    fn encode_histogram(histogram: &HistogramRecord, dst: &mut impl Write) -> std::io::Result<()> {
+        // measurement and tags
+        Self::encode_series(&histogram.series, histogram.window, dst)?;
         // fields
+        let stats = &histogram.stats;
         dst.write_all(b" count=")?;
-        dst.write_all(itoa::Buffer::new().format(histogram.inner.len()).as_bytes())?;
+        dst.write_all(itoa::Buffer::new().format(stats.count).as_bytes())?;
         dst.write_all(b"u,min=")?;
-        dst.write_all(itoa::Buffer::new().format(histogram.inner.min()).as_bytes())?;
+        dst.write_all(itoa::Buffer::new().format(stats.min).as_bytes())?;
         dst.write_all(b"u,max=")?;
-        dst.write_all(itoa::Buffer::new().format(histogram.inner.max()).as_bytes())?;
+        dst.write_all(itoa::Buffer::new().format(stats.max).as_bytes())?;
         dst.write_all(b"u,mean=")?;
-        dst.write_all(dtoa::Buffer::new().format(histogram.inner.mean()).as_bytes())?;
+        dst.write_all(dtoa::Buffer::new().format(stats.mean).as_bytes())?;
         dst.write_all(b",p50=")?;
-        dst.write_all(
-            itoa::Buffer::new()
-                .format(histogram.inner.value_at_quantile(0.50))
-                .as_bytes(),
-        )?;
+        dst.write_all(itoa::Buffer::new().format(stats.p50).as_bytes())?;
         dst.write_all(b"u,p75=")?;
-        dst.write_all(
-            itoa::Buffer::new()
-                .format(histogram.inner.value_at_quantile(0.75))
-                .as_bytes(),
-        )?;
+        dst.write_all(itoa::Buffer::new().format(stats.p75).as_bytes())?;
         dst.write_all(b"u,p90=")?;
-        dst.write_all(
-            itoa::Buffer::new()
-                .format(histogram.inner.value_at_quantile(0.90))
-                .as_bytes(),
-        )?;
+        dst.write_all(itoa::Buffer::new().format(stats.p90).as_bytes())?;
         dst.write_all(b"u,p95=")?;
-        dst.write_all(
-            itoa::Buffer::new()
-                .format(histogram.inner.value_at_quantile(0.95))
-                .as_bytes(),
-        )?;
+        dst.write_all(itoa::Buffer::new().format(stats.p95).as_bytes())?;
         dst.write_all(b"u,p99=")?;
-        dst.write_all(
-            itoa::Buffer::new()
-                .format(histogram.inner.value_at_quantile(0.99))
-                .as_bytes(),
-        )?;
+        dst.write_all(itoa::Buffer::new().format(stats.p99).as_bytes())?;
         dst.write_all(b"u,p999=")?;
-        dst.write_all(
-            itoa::Buffer::new()
-                .format(histogram.inner.value_at_quantile(0.999))
-                .as_bytes(),
-        )?;
+        dst.write_all(itoa::Buffer::new().format(stats.p999).as_bytes())?;
         dst.write_all(b"u,p9999=")?;
-        dst.write_all(
-            itoa::Buffer::new()
-                .format(histogram.inner.value_at_quantile(0.9999))
-                .as_bytes(),
-        )?;
-        dst.write_all(b"u ")?;
+        dst.write_all(itoa::Buffer::new().format(stats.p9999).as_bytes())?;
+        dst.write_all(b"u")?;
+        if let Some(sample_rate) = histogram.sampled_fraction() {
+            dst.write_all(b",sample_rate=")?;
+            dst.write_all(dtoa::Buffer::new().format(sample_rate).as_bytes())?;
+        }
+        dst.write_all(b" ")?;
         // timestamp
-        dst.write_all(itoa::Buffer::new().format(timestamp).as_bytes())?;
+        dst.write_all(itoa::Buffer::new().format(histogram.timestamp).as_bytes())?;
         // new line
         dst.write_all(b"\n")?;
         Ok(())
     }
+
+    /// Writes `measurement[,key=value...]`, with the histogram window as the last tag.
+    fn encode_series(series: &Series, window: Option<&str>, dst: &mut impl Write) -> std::io::Result<()> {
+        Self::encode_escaped(series.name, MEASUREMENT_SPECIAL_CHARS, dst)?;
+        let window = window.map(|window| ("window", window));
+        let tags = series.tags.iter().map(|(k, v)| (k.as_str(), v.as_str())).chain(window);
+        for (key, value) in tags {
+            dst.write_all(b",")?;
+            Self::encode_escaped(key, TAG_SPECIAL_CHARS, dst)?;
+            dst.write_all(b"=")?;
+            Self::encode_escaped(value, TAG_SPECIAL_CHARS, dst)?;
+        }
+        Ok(())
+    }
+
+    fn encode_escaped(value: &str, special_chars: &[u8], dst: &mut impl Write) -> std::io::Result<()> {
+        let mut unescaped = value.as_bytes();
+        while let Some(index) = unescaped.iter().position(|c| special_chars.contains(c)) {
+            dst.write_all(&unescaped[..index])?;
+            dst.write_all(&[b'\\', unescaped[index]])?;
+            unescaped = &unescaped[index + 1..];
+        }
+        dst.write_all(unescaped)
+    }
 }
 
+/// Characters escaped with a backslash in the measurement name.
+pub(crate) const MEASUREMENT_SPECIAL_CHARS: &[u8] = b", \\";
+/// Characters escaped with a backslash in the tag keys and values.
+pub(crate) const TAG_SPECIAL_CHARS: &[u8] = b",= \\";
+
 struct Json;
 
 impl Json {
-    fn encode_counter(counter: &Counter, timestamp: u64, dst: &mut impl Write) -> std::io::Result<()> {
-        serde_json::to_writer(&mut *dst, &CounterWithTimestamp::new(counter, timestamp))
+    fn encode(record: &impl Serialize, dst: &mut impl Write) -> std::io::Result<()> {
+        serde_json::to_writer(&mut *dst, record)
             .map_err(std::io::Error::other)
             .and_then(|_| dst.write_all(b"\n"))
     }
 }
 
-#[derive(Serialize)]
-struct CounterWithTimestamp<'a> {
-    timestamp: u64,
-    #[serde(flatten)]
-    counter: &'a Counter,
-}
+/// Counters are exported as gauges since they hold the running total rather than the increments since the last
+/// flush. Histograms are exported as one gauge per statistic along with their count, which is a counter with the
+/// sample rate (`|@0.01`) for the interval window and a gauge already scaled up for the other windows.
+struct Statsd;
 
-impl<'a> CounterWithTimestamp<'a> {
-    fn new(counter: &'a Counter, timestamp: u64) -> Self {
-        Self { timestamp, counter }
+impl Statsd {
+    fn encode_counter(counter: &CounterRecord, dst: &mut impl Write) -> std::io::Result<()> {
+        let mut value = itoa::Buffer::new();
+        let value = value.format(counter.value).as_bytes();
+        Self::encode_line(&counter.series, None, None, value, b"g", None, dst)
+    }
+
+    fn encode_histogram(histogram: &HistogramRecord, dst: &mut impl Write) -> std::io::Result<()> {
+        let series = &histogram.series;
+        let window = histogram.window;
+        let stats = &histogram.stats;
+        let mut buffer = itoa::Buffer::new();
+        match window {
+            None => {
+                let count = buffer.format(stats.count).as_bytes();
+                Self::encode_line(series, Some("count"), None, count, b"c", histogram.sampled_fraction(), dst)?;
+            }
+            Some(_) => {
+                let count = buffer.format(stats.count.saturating_mul(histogram.sample_rate as u64));
+                Self::encode_line(series, Some("count"), window, count.as_bytes(), b"g", None, dst)?;
+            }
+        }
+        let mut mean = dtoa::Buffer::new();
+        let mean = mean.format(stats.mean).as_bytes();
+        Self::encode_line(series, Some("mean"), window, mean, b"g", None, dst)?;
+        for (stat, value) in [
+            ("min", stats.min),
+            ("max", stats.max),
+            ("p50", stats.p50),
+            ("p75", stats.p75),
+            ("p90", stats.p90),
+            ("p95", stats.p95),
+            ("p99", stats.p99),
+            ("p999", stats.p999),
+            ("p9999", stats.p9999),
+        ] {
+            let value = buffer.format(value).as_bytes();
+            Self::encode_line(series, Some(stat), window, value, b"g", None, dst)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `name[.stat]:value|type[|@rate][|#tags]`.
+    fn encode_line(
+        series: &Series,
+        stat: Option<&str>,
+        window: Option<&str>,
+        value: &[u8],
+        metric_type: &[u8],
+        sample_rate: Option<f64>,
+        dst: &mut impl Write,
+    ) -> std::io::Result<()> {
+        dst.write_all(series.name.as_bytes())?;
+        if let Some(stat) = stat {
+            dst.write_all(b".")?;
+            dst.write_all(stat.as_bytes())?;
+        }
+        dst.write_all(b":")?;
+        dst.write_all(value)?;
+        dst.write_all(b"|")?;
+        dst.write_all(metric_type)?;
+        if let Some(sample_rate) = sample_rate {
+            dst.write_all(b"|@")?;
+            dst.write_all(dtoa::Buffer::new().format(sample_rate).as_bytes())?;
+        }
+        let window = window.map(|window| ("window", window));
+        let tags = series.tags.iter().map(|(k, v)| (k.as_str(), v.as_str())).chain(window);
+        for (i, (key, value)) in tags.enumerate() {
+            dst.write_all(if i == 0 { b"|#" } else { b"," })?;
+            dst.write_all(key.as_bytes())?;
+            dst.write_all(b":")?;
+            dst.write_all(value.as_bytes())?;
+        }
+        dst.write_all(b"\n")?;
+        Ok(())
     }
 }
 
 fn current_time_ns() -> u64 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HistogramConfig;
+
+    /// Aggregator driven directly by the tests, without an agent or an exporter.
+    fn aggregator(config: MetricsConfig) -> MetricsAggregator {
+        #[cfg(feature = "rtrb")]
+        let ((_, rx_upd), (_, rx_cnc)) = (rtrb::RingBuffer::new(1), rtrb::RingBuffer::new(1));
+        #[cfg(not(feature = "rtrb"))]
+        let ((_, rx_upd), (_, rx_cnc)) = (std::sync::mpsc::channel(), std::sync::mpsc::channel());
+        let (_, rx_query) = std::sync::mpsc::channel();
+        let rules = Rules::try_from(config.exporter_rules.clone()).unwrap();
+        let histogram_modes = HistogramModes::new(config.histograms.clone(), config.flush_interval).unwrap();
+        MetricsAggregator::new(rx_upd, rx_cnc, rx_query, Exporter::NoOp, rules, histogram_modes, &config)
+    }
+
+    impl MetricsAggregator {
+        fn control(&mut self, event: ControlEvent) {
+            Self::handle_control_event(
+                &mut self.counters,
+                &mut self.histograms,
+                &mut self.evicted,
+                &mut self.self_metrics,
+                &mut self.rules,
+                &self.histogram_modes,
+                event,
+            )
+            .unwrap();
+        }
+
+        fn update(&mut self, event: UpdateEvent) {
+            Self::handle_update_event(&mut self.counters, &mut self.histograms, &mut self.evicted, event).unwrap();
+        }
+
+        fn flush(&mut self) {
+            self.flush_metrics(current_time_ns()).unwrap();
+        }
+
+        /// Value of the counter if it is exported.
+        fn exported_counter(&self, id: Id) -> Option<u64> {
+            let counter = self.counters.get(&id)?;
+            counter.meta_data.is_exported().then_some(counter.value)
+        }
+
+        /// Count of the interval window of the histogram if it is exported.
+        fn exported_histogram(&self, id: Id) -> Option<u64> {
+            let histogram = self.histograms.get(&id)?;
+            histogram.meta_data.is_exported().then_some(histogram.inner.len())
+        }
+
+        /// Total of the self metric counters of the event.
+        fn self_metric(&self, event: &str) -> u64 {
+            self.self_metrics
+                .counters
+                .values()
+                .filter(|counter| counter.meta_data.tags.contains(&("event", event).to_owned_tag()))
+                .map(|counter| counter.value)
+                .sum()
+        }
+    }
+
+    #[test]
+    fn should_align_flushes_to_interval() {
+        let config = MetricsConfig {
+            flush_interval: Duration::from_secs(10),
+            align_flush_to_interval: true,
+            ..MetricsConfig::default()
+        };
+        let aggregator = aggregator(config);
+        assert_eq!(20_000_000_000, aggregator.get_next_flush_time_ns(12_345_678_901));
+        // a flush on the boundary schedules the next boundary
+        assert_eq!(30_000_000_000, aggregator.get_next_flush_time_ns(20_000_000_000));
+        assert_eq!(0, aggregator.next_flush_time_ns % 10_000_000_000);
+
+        let aggregator = self::aggregator(MetricsConfig {
+            flush_interval: Duration::from_secs(10),
+            ..MetricsConfig::default()
+        });
+        assert_eq!(22_345_678_901, aggregator.get_next_flush_time_ns(12_345_678_901));
+    }
+
+    #[test]
+    fn should_stamp_flush_with_timestamp_mode() {
+        let timestamp = |timestamp_mode| {
+            let mut aggregator = aggregator(MetricsConfig {
+                flush_interval: Duration::from_secs(10),
+                align_flush_to_interval: true,
+                timestamp_mode,
+                ..MetricsConfig::default()
+            });
+            // flushing just after the end of the interval
+            aggregator.next_flush_time_ns = 20_000_000_000;
+            aggregator.get_flush_timestamp(20_000_123_456)
+        };
+        assert_eq!(20_000_123_456, timestamp(TimestampMode::FlushTime));
+        assert_eq!(10_000_000_000, timestamp(TimestampMode::IntervalStart));
+        assert_eq!(20_000_000_000, timestamp(TimestampMode::IntervalEnd));
+    }
+
+    #[test]
+    fn should_only_delete_metrics_once_released_by_all_creations() {
+        let mut aggregator = aggregator(MetricsConfig::default());
+        aggregator.control(ControlEvent::CounterCreate(1, "counter".to_owned(), vec![]));
+        aggregator.control(ControlEvent::CounterCreate(1, "counter".to_owned(), vec![]));
+        aggregator.update(UpdateEvent::CounterIncrement(1, 3));
+        aggregator.control(ControlEvent::CounterDelete(1));
+        aggregator.flush();
+        assert_eq!(Some(3), aggregator.exported_counter(1));
+
+        // the last delete is exported once more before the counter is removed
+        aggregator.control(ControlEvent::CounterDelete(1));
+        assert_eq!(Some(3), aggregator.exported_counter(1));
+        aggregator.flush();
+        assert!(aggregator.counters.is_empty());
+
+        aggregator.control(ControlEvent::HistogramCreate(2, "histogram".to_owned(), vec![], 1));
+        aggregator.control(ControlEvent::HistogramCreate(2, "histogram".to_owned(), vec![], 1));
+        aggregator.control(ControlEvent::HistogramDelete(2));
+        aggregator.flush();
+        assert!(aggregator.histograms.contains_key(&2));
+        aggregator.control(ControlEvent::HistogramDelete(2));
+        aggregator.flush();
+        assert!(aggregator.histograms.is_empty());
+    }
+
+    #[test]
+    fn should_report_cardinality_limit_hits_per_measurement() {
+        let mut aggregator = aggregator(MetricsConfig::default());
+        let hits = aggregator.cardinality_hits.clone();
+        let hit = |name: &str, count: u64| *hits.lock().unwrap().entry(name.to_owned()).or_default() += count;
+        hit("orders", 2);
+        hit("fills", 1);
+        aggregator.flush();
+        hit("orders", 3);
+        aggregator.flush();
+
+        assert!(hits.lock().unwrap().is_empty());
+        let reported = |measurement: &str| {
+            aggregator
+                .self_metrics
+                .counters
+                .values()
+                .find(|counter| {
+                    counter
+                        .meta_data
+                        .tags
+                        .contains(&("measurement", measurement).to_owned_tag())
+                })
+                .map(|counter| counter.value)
+        };
+        assert_eq!(Some(5), reported("orders"));
+        assert_eq!(Some(1), reported("fills"));
+        assert_eq!(5 + 1, aggregator.self_metric("cardinality_limit_hit"));
+    }
+
+    #[test]
+    fn should_export_interval_cumulative_and_sliding_windows() {
+        let config = MetricsConfig {
+            flush_interval: Duration::from_secs(10),
+            histograms: HistogramConfig {
+                default_modes: vec![
+                    HistogramMode::Interval,
+                    HistogramMode::Cumulative,
+                    HistogramMode::Window {
+                        length: Duration::from_secs(20),
+                    },
+                ],
+                rules: vec![],
+            },
+            ..MetricsConfig::default()
+        };
+        let mut aggregator = aggregator(config);
+        aggregator.control(ControlEvent::HistogramCreate(1, "histogram".to_owned(), vec![], 1));
+        let windows = |aggregator: &MetricsAggregator| {
+            aggregator.histograms[&1]
+                .windows()
+                .map(|(label, inner)| (label.map(str::to_owned), inner.len()))
+                .collect::<Vec<_>>()
+        };
+
+        // the sliding window spans two flush intervals
+        for values in [1, 2, 4] {
+            for value in 0..values {
+                aggregator.update(UpdateEvent::HistogramRecord(1, value + 1));
+            }
+            assert_eq!(values, aggregator.histograms[&1].inner.len());
+            aggregator.flush();
+        }
+        assert_eq!(
+            vec![
+                (None, 0),
+                (Some("cumulative".to_owned()), 7),
+                (Some("20s".to_owned()), 6)
+            ],
+            windows(&aggregator)
+        );
+
+        // idle intervals slide out of the window but are kept by the cumulative one
+        aggregator.flush();
+        aggregator.flush();
+        assert_eq!(
+            vec![
+                (None, 0),
+                (Some("cumulative".to_owned()), 7),
+                (Some("20s".to_owned()), 0)
+            ],
+            windows(&aggregator)
+        );
+    }
+
+    #[test]
+    fn should_format_window_length_in_largest_whole_unit() {
+        assert_eq!("2h", format_window_length(Duration::from_secs(7_200)));
+        assert_eq!("90m", format_window_length(Duration::from_secs(5_400)));
+        assert_eq!("5m", format_window_length(Duration::from_secs(300)));
+        assert_eq!("90s", format_window_length(Duration::from_secs(90)));
+        assert_eq!("1500ms", format_window_length(Duration::from_millis(1_500)));
+        assert_eq!("0ms", format_window_length(Duration::ZERO));
+    }
+
+    fn ttl_config(ttl_intervals: u32) -> MetricsConfig {
+        MetricsConfig {
+            metric_ttl_intervals: Some(ttl_intervals),
+            report_metric_lifecycle: true,
+            ..MetricsConfig::default()
+        }
+    }
+
+    #[test]
+    fn should_stop_exporting_idle_metrics_after_ttl() {
+        let mut aggregator = aggregator(ttl_config(2));
+        aggregator.control(ControlEvent::CounterCreate(1, "counter".to_owned(), vec![]));
+        aggregator.control(ControlEvent::HistogramCreate(2, "histogram".to_owned(), vec![], 1));
+        aggregator.update(UpdateEvent::CounterIncrement(1, 5));
+        aggregator.update(UpdateEvent::HistogramRecord(2, 10));
+
+        aggregator.flush();
+        assert_eq!(Some(5), aggregator.exported_counter(1));
+        assert_eq!(Some(0), aggregator.exported_histogram(2));
+        aggregator.flush();
+        assert_eq!(Some(5), aggregator.exported_counter(1));
+
+        // idle for the whole ttl
+        aggregator.flush();
+        assert_eq!(None, aggregator.exported_counter(1));
+        assert_eq!(None, aggregator.exported_histogram(2));
+        assert!(aggregator.evicted.counters.contains_key(&1));
+        assert!(aggregator.evicted.histograms.contains_key(&2));
+    }
+
+    #[test]
+    fn should_keep_counter_total_when_revived() {
+        let mut aggregator = aggregator(ttl_config(1));
+        aggregator.control(ControlEvent::CounterCreate(1, "counter".to_owned(), vec![]));
+        aggregator.control(ControlEvent::HistogramCreate(2, "histogram".to_owned(), vec![], 1));
+        aggregator.update(UpdateEvent::CounterIncrement(1, 5));
+        aggregator.update(UpdateEvent::HistogramRecord(2, 10));
+        aggregator.flush();
+        aggregator.flush();
+        assert_eq!(None, aggregator.exported_counter(1));
+
+        // the running total carries on rather than going backwards
+        aggregator.update(UpdateEvent::CounterIncrement(1, 2));
+        aggregator.update(UpdateEvent::HistogramRecord(2, 10));
+        assert_eq!(Some(7), aggregator.exported_counter(1));
+        assert_eq!(Some(1), aggregator.exported_histogram(2));
+        assert!(aggregator.evicted.counters.is_empty());
+        assert!(aggregator.evicted.histograms.is_empty());
+    }
+
+    #[test]
+    fn should_report_lifecycle_once_per_metric() {
+        let mut aggregator = aggregator(ttl_config(1));
+        aggregator.control(ControlEvent::CounterCreate(1, "counter".to_owned(), vec![]));
+        aggregator.control(ControlEvent::CounterCreate(1, "counter".to_owned(), vec![]));
+        aggregator.control(ControlEvent::HistogramCreate(2, "histogram".to_owned(), vec![], 1));
+        assert_eq!(1, aggregator.self_metric("counter_create"));
+        assert_eq!(1, aggregator.self_metric("histogram_create"));
+
+        aggregator.control(ControlEvent::CounterDelete(1));
+        assert_eq!(0, aggregator.self_metric("counter_delete"));
+        aggregator.control(ControlEvent::CounterDelete(1));
+        assert_eq!(1, aggregator.self_metric("counter_delete"));
+        // the deleted counter is exported one last time
+        aggregator.flush();
+        assert!(aggregator.counters.is_empty());
+
+        // deleting an evicted metric removes it straight away
+        aggregator.flush();
+        assert!(aggregator.evicted.histograms.contains_key(&2));
+        aggregator.control(ControlEvent::HistogramDelete(2));
+        assert_eq!(1, aggregator.self_metric("histogram_delete"));
+        assert!(aggregator.evicted.histograms.is_empty());
+    }
+
+    #[test]
+    fn should_not_report_lifecycle_unless_enabled() {
+        let mut aggregator = aggregator(MetricsConfig::default());
+        aggregator.control(ControlEvent::CounterCreate(1, "counter".to_owned(), vec![]));
+        aggregator.control(ControlEvent::CounterDelete(1));
+        assert!(aggregator.self_metrics.counters.is_empty());
+    }
+}
@@ -0,0 +1,156 @@
+//! Lock-free counter slots shared between the agent and the aggregator, see [`CounterArenaConfig`].
+
+use crate::config::{ArenaFallback, CounterArenaConfig};
+use crate::{Error, Result};
+use metricus::{DYNAMIC_IDS, Id};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counter value padded to its own cache line, so that counters updated by different threads do not contend.
+#[repr(align(64))]
+#[derive(Default)]
+struct Slot(AtomicU64);
+
+/// Fixed number of counter slots. Counters in the arena are assigned the ids at the end of the [DYNAMIC_IDS]
+/// range, so that the slot of a counter is found from its id alone.
+pub(crate) struct CounterArena {
+    /// Never freed, as the counters keep a reference to their slot (see [`Metrics::counter_slot`]).
+    ///
+    /// [`Metrics::counter_slot`]: metricus::Metrics::counter_slot
+    slots: &'static [Slot],
+    /// Id of the counter in the first slot.
+    base: Id,
+    /// Slots available to new counters.
+    free: Mutex<Vec<usize>>,
+    /// Slots of the deleted counters whose final value has been flushed. They are only made available to new
+    /// counters on the next flush, so that the updates still in flight for the deleted counter are dropped rather
+    /// than attributed to the counter reusing the slot.
+    retired: Mutex<Vec<usize>>,
+    when_full: ArenaFallback,
+}
+
+impl CounterArena {
+    pub(crate) fn new(config: &CounterArenaConfig) -> Result<Self> {
+        if config.capacity == 0 || config.capacity as u64 > u32::MAX as u64 {
+            return Err(Error::other(format!(
+                "counter arena capacity must be between 1 and {}, got {}",
+                u32::MAX,
+                config.capacity
+            )));
+        }
+        Ok(Self {
+            slots: Box::leak((0..config.capacity).map(|_| Slot::default()).collect()),
+            base: DYNAMIC_IDS.end() - config.capacity as Id + 1,
+            free: Mutex::new((0..config.capacity).rev().collect()),
+            retired: Mutex::new(Vec::new()),
+            when_full: config.when_full,
+        })
+    }
+
+    /// Assigns a slot to a new counter and returns the counter id, or `None` if the arena is full.
+    pub(crate) fn allocate(&self) -> Option<Id> {
+        let slot = self.free.lock().unwrap_or_else(|e| e.into_inner()).pop()?;
+        Some(self.base + slot as Id)
+    }
+
+    /// Id of the counter in the first slot, all the ids from this one onwards belong to the arena.
+    pub(crate) const fn base(&self) -> Id {
+        self.base
+    }
+
+    /// What to do with the new counters when the arena is full.
+    pub(crate) const fn when_full(&self) -> ArenaFallback {
+        self.when_full
+    }
+
+    /// Slot of the counter, if it has one in the arena.
+    #[inline]
+    pub(crate) fn slot(&self, id: Id) -> Option<&'static AtomicU64> {
+        let index = id.checked_sub(self.base)?;
+        self.slots.get(index as usize).map(|slot| &slot.0)
+    }
+
+    /// Adds to the counter and returns `true` if it has a slot in the arena.
+    #[inline]
+    pub(crate) fn increment(&self, id: Id, delta: u64) -> bool {
+        match self.slot(id) {
+            Some(slot) => {
+                slot.fetch_add(delta, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Current value of the counter, if it has a slot in the arena.
+    #[inline]
+    pub(crate) fn value(&self, id: Id) -> Option<u64> {
+        self.slot(id).map(|slot| slot.load(Ordering::Relaxed))
+    }
+
+    /// Retires the slot of a deleted counter once its final value has been flushed, see [`CounterArena::recycle`].
+    pub(crate) fn release(&self, id: Id) {
+        if self.slot(id).is_some() {
+            let mut retired = self.retired.lock().unwrap_or_else(|e| e.into_inner());
+            retired.push((id - self.base) as usize);
+        }
+    }
+
+    /// Resets the slots retired on the previous flush and makes them available to new counters.
+    pub(crate) fn recycle(&self) {
+        let retired = std::mem::take(&mut *self.retired.lock().unwrap_or_else(|e| e.into_inner()));
+        if retired.is_empty() {
+            return;
+        }
+        for index in &retired {
+            self.slots[*index].0.store(0, Ordering::Relaxed);
+        }
+        self.free.lock().unwrap_or_else(|e| e.into_inner()).extend(retired);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arena(capacity: usize) -> CounterArena {
+        CounterArena::new(&CounterArenaConfig {
+            capacity,
+            when_full: ArenaFallback::Reject,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn should_not_allocate_when_full() {
+        let arena = arena(2);
+        let first = arena.allocate().unwrap();
+        let second = arena.allocate().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(DYNAMIC_IDS.end(), first.max(second));
+        assert_eq!(None, arena.allocate());
+    }
+
+    #[test]
+    fn should_reuse_released_slot_only_after_next_flush() {
+        let arena = arena(1);
+        let id = arena.allocate().unwrap();
+        assert!(arena.increment(id, 5));
+        arena.release(id);
+        assert_eq!(None, arena.allocate());
+
+        // late update for the deleted counter
+        assert!(arena.increment(id, 1));
+        arena.recycle();
+        assert_eq!(Some(id), arena.allocate());
+        assert_eq!(Some(0), arena.value(id));
+    }
+
+    #[test]
+    fn should_ignore_ids_outside_the_arena() {
+        let arena = arena(1);
+        assert!(!arena.increment(arena.base() - 1, 1));
+        assert_eq!(None, arena.value(arena.base() - 1));
+        assert!(arena.slot(arena.base() - 1).is_none());
+    }
+}
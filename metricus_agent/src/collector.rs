@@ -0,0 +1,458 @@
+//! Receives the metrics exported by other processes with the line protocol or json encoder, as configured by the
+//! collector `format`, and feeds them into an agent, which merges and re-exports them on its own flush interval.
+//! This allows a single collector per host to export the metrics of all processes, with host level tags added
+//! through the `default_tags`.
+//!
+//! Counters are exported with their running total, so the collector tracks the last total of each series per
+//! sender and increments the merged counter by the difference, treating a total going backwards as a restart of the
+//! sender. Once a sender disconnects, or a datagram sender has not sent anything for several flush intervals, its
+//! last totals become the baseline of the next sender of the same series, so that a sender reconnecting from another
+//! address is not counted twice. Histograms are only exported as summary statistics, so the collector
+//! rebuilds an approximate distribution with the same count, min, max and percentiles from each interval point and
+//! merges it into the histogram. The mean of the rebuilt distribution is not preserved and the points of the
+//! cumulative and sliding windows are ignored, as the collector computes its own windows according to its
+//! histogram modes.
+//!
+//! ## Examples
+//!
+//! ```no_run
+//! use metricus_agent::collector::Collector;
+//! use metricus_agent::config::MetricsConfig;
+//! use std::str::FromStr;
+//!
+//! let config = MetricsConfig::from_str("
+//! default_tags:
+//!     host: box-1
+//! collector:
+//!     udp: 0.0.0.0:8777
+//!     unix_datagram: /tmp/metrics-collector.sock
+//!     format: line_protocol
+//! exporter:
+//!     type: udp
+//!     config:
+//!         host: tsdb
+//!         port: 8089
+//!         encoder: line_protocol
+//! ").unwrap();
+//! Collector::new(config).unwrap().run().unwrap();
+//! ```
+
+use crate::config::{CollectorConfig, Format, MetricsConfig};
+use crate::decoder::{CounterPoint, HistogramPoint, Point};
+use crate::query::HistogramStats;
+use crate::{Error, MetricsAgent, OwnedTags, Result};
+use log::{info, warn};
+use metricus::{Id, Metrics};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+/// Largest datagram that can be received.
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+/// Number of flush intervals without any metric after which a datagram sender is assumed to be gone.
+const IDLE_FLUSH_INTERVALS: u32 = 10;
+
+/// Sender of the metrics, used to keep track of the counter totals of each process.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Source {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    /// Unix datagrams sent from an unbound socket cannot be told apart.
+    UnixDatagram(Option<PathBuf>),
+    /// Unix stream connection number.
+    UnixStream(u64),
+}
+
+impl Source {
+    /// Whether the end of the source is only noticed when it stops sending, rather than when it disconnects.
+    const fn is_datagram(&self) -> bool {
+        matches!(self, Source::Udp(_) | Source::UnixDatagram(_))
+    }
+}
+
+enum Message {
+    /// One or more lines received from the source.
+    Received(Source, String),
+    /// Connection has been closed, so the source will not send any more metrics.
+    Closed(Source),
+}
+
+/// Measurement name and tags.
+type Series = (String, OwnedTags);
+
+pub struct Collector {
+    agent: MetricsAgent,
+    rx: Receiver<Message>,
+    counters: HashMap<Series, Id>,
+    histograms: HashMap<Series, Id>,
+    /// Last counter total received from each source.
+    totals: HashMap<(Source, Series), u64>,
+    /// Last counter total of the series whose source is gone, from which the next source of the series carries on.
+    detached: HashMap<Series, u64>,
+    /// Time the datagram sources last sent metrics.
+    last_seen: HashMap<Source, Instant>,
+    /// Time after which a datagram source that has not sent anything is forgotten.
+    idle_timeout: Duration,
+    /// Encoder used by the senders.
+    format: Format,
+}
+
+impl Collector {
+    /// Binds the configured sockets and starts the agent which exports the collected metrics.
+    pub fn new(config: MetricsConfig) -> Result<Self> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        listen(&config.collector, tx)?;
+        let idle_timeout = config.flush_interval * IDLE_FLUSH_INTERVALS;
+        let format = config.collector.format;
+        Ok(Self::with_agent(MetricsAgent::start_with_config(config)?, rx, idle_timeout, format))
+    }
+
+    fn with_agent(agent: MetricsAgent, rx: Receiver<Message>, idle_timeout: Duration, format: Format) -> Self {
+        Self {
+            agent,
+            rx,
+            counters: HashMap::new(),
+            histograms: HashMap::new(),
+            totals: HashMap::new(),
+            detached: HashMap::new(),
+            last_seen: HashMap::new(),
+            idle_timeout,
+            format,
+        }
+    }
+
+    /// Processes the received metrics, forever.
+    pub fn run(mut self) -> Result<()> {
+        let mut next_expiry = Instant::now() + self.idle_timeout;
+        loop {
+            match self.rx.recv_timeout(self.idle_timeout) {
+                Ok(Message::Received(source, lines)) => {
+                    for line in lines.lines().filter(|line| !line.trim().is_empty()) {
+                        match self.format.decode(line) {
+                            Ok(point) => self.collect(&source, point),
+                            Err(e) => warn!("unable to parse metric [{line}]: {e}"),
+                        }
+                    }
+                    if source.is_datagram() {
+                        self.last_seen.insert(source, Instant::now());
+                    }
+                }
+                Ok(Message::Closed(source)) => self.forget(&source),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(e @ RecvTimeoutError::Disconnected) => return Err(Error::other(e)),
+            }
+            let now = Instant::now();
+            if now >= next_expiry {
+                self.expire_idle_sources(now);
+                next_expiry = now + self.idle_timeout;
+            }
+        }
+    }
+
+    /// Detaches the counter totals of the datagram sources that have not sent anything for a while.
+    fn expire_idle_sources(&mut self, now: Instant) {
+        let idle = self
+            .last_seen
+            .iter()
+            .filter(|(_, last_seen)| now.duration_since(**last_seen) >= self.idle_timeout)
+            .map(|(source, _)| source.clone())
+            .collect::<Vec<_>>();
+        for source in idle {
+            self.forget(&source);
+        }
+    }
+
+    /// Detaches the counter totals of a source that is gone, so that they are not counted again when it comes back.
+    fn forget(&mut self, source: &Source) {
+        self.last_seen.remove(source);
+        let gone = self
+            .totals
+            .keys()
+            .filter(|(from, _)| from == source)
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in gone {
+            if let Some(total) = self.totals.remove(&key) {
+                self.detached.insert(key.1, total);
+            }
+        }
+    }
+
+    fn collect(&mut self, source: &Source, point: Point) {
+        match point {
+            Point::Counter(CounterPoint { name, tags, value, .. }) => {
+                let series = (name, tags);
+                let id = match self.counters.get(&series) {
+                    Some(id) => *id,
+                    None => {
+                        let id = self.agent.new_counter(&series.0, &borrow_tags(&series.1));
+                        *self.counters.entry(series.clone()).or_insert(id)
+                    }
+                };
+                let last = match self.totals.insert((source.clone(), series.clone()), value) {
+                    Some(last) => Some(last),
+                    None => self.detached.remove(&series),
+                };
+                // the sender has restarted if its total went backwards
+                let delta = match last {
+                    Some(last) if value >= last => value - last,
+                    _ => value,
+                };
+                if delta > 0 {
+                    self.agent.increment_counter_by(id, delta);
+                }
+            }
+            Point::Histogram(HistogramPoint {
+                name,
+                tags,
+                window,
+                sample_rate,
+                stats,
+                ..
+            }) => {
+                if window.is_some() {
+                    return;
+                }
+                let series = (name, tags);
+                let id = match self.histograms.get(&series) {
+                    Some(id) => *id,
+                    None => {
+                        let tags = borrow_tags(&series.1);
+                        let id = self.agent.new_histogram_sampled(&series.0, &tags, sample_rate);
+                        *self.histograms.entry(series.clone()).or_insert(id)
+                    }
+                };
+                if stats.count > 0 {
+                    self.agent.merge_histogram(id, distribution(&stats));
+                }
+            }
+        }
+    }
+}
+
+fn borrow_tags(tags: &OwnedTags) -> Vec<(&str, &str)> {
+    tags.iter()
+        // the agent adds the type tag back when registering the metric
+        .filter(|(key, _)| key != "type")
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect()
+}
+
+/// Builds a distribution with the same count, min, max and percentiles as the given statistics.
+fn distribution(stats: &HistogramStats) -> hdrhistogram::Histogram<u64> {
+    let mut distribution = hdrhistogram::Histogram::<u64>::new(3).unwrap(); // will never fail
+    let mut recorded = 0;
+    for (value, quantile) in [
+        (stats.min, 0.0),
+        (stats.p50, 0.50),
+        (stats.p75, 0.75),
+        (stats.p90, 0.90),
+        (stats.p95, 0.95),
+        (stats.p99, 0.99),
+        (stats.p999, 0.999),
+        (stats.p9999, 0.9999),
+        (stats.max, 1.0),
+    ] {
+        // the last value is kept for the max, which the percentiles of small counts would otherwise take
+        let limit = match quantile < 1.0 {
+            true => stats.count - 1,
+            false => stats.count,
+        };
+        let cumulative = ((quantile * stats.count as f64).ceil() as u64).clamp(1, limit.max(1));
+        if cumulative > recorded {
+            // auto-resizing histogram accepts any value
+            let _ = distribution.record_n(value, cumulative - recorded);
+            recorded = cumulative;
+        }
+    }
+    distribution
+}
+
+/// Binds the configured sockets and spawns a thread per socket forwarding the received lines.
+fn listen(config: &CollectorConfig, tx: Sender<Message>) -> Result<()> {
+    let mut listening = false;
+    if let Some(address) = &config.udp {
+        let socket = UdpSocket::bind(address)?;
+        info!("collecting metrics on udp {}", socket.local_addr()?);
+        let tx = tx.clone();
+        spawn("collector-udp", move || {
+            let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+            loop {
+                let (len, from) = socket.recv_from(&mut buffer)?;
+                let lines = String::from_utf8_lossy(&buffer[..len]).into_owned();
+                if tx.send(Message::Received(Source::Udp(from), lines)).is_err() {
+                    return Ok(());
+                }
+            }
+        })?;
+        listening = true;
+    }
+    if let Some(address) = &config.tcp {
+        let listener = TcpListener::bind(address)?;
+        info!("collecting metrics on tcp {}", listener.local_addr()?);
+        let tx = tx.clone();
+        spawn("collector-tcp", move || {
+            for stream in listener.incoming() {
+                // a failed connection must not stop the listener
+                let (stream, peer) = match stream.and_then(|stream| stream.peer_addr().map(|peer| (stream, peer))) {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("unable to accept tcp connection: {e}");
+                        continue;
+                    }
+                };
+                let source = Source::Tcp(peer);
+                let tx = tx.clone();
+                if let Err(e) = spawn("collector-tcp-conn", move || forward_lines(stream, source, tx)) {
+                    warn!("unable to handle tcp connection from {peer}: {e}");
+                }
+            }
+            Ok(())
+        })?;
+        listening = true;
+    }
+    if let Some(path) = &config.unix_datagram {
+        let socket = UnixDatagram::bind(remove_stale_socket(path)?)?;
+        info!("collecting metrics on unix datagram socket {}", path.display());
+        let tx = tx.clone();
+        spawn("collector-unix-dgram", move || {
+            let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+            loop {
+                let (len, from) = socket.recv_from(&mut buffer)?;
+                let source = Source::UnixDatagram(from.as_pathname().map(Path::to_path_buf));
+                let lines = String::from_utf8_lossy(&buffer[..len]).into_owned();
+                if tx.send(Message::Received(source, lines)).is_err() {
+                    return Ok(());
+                }
+            }
+        })?;
+        listening = true;
+    }
+    if let Some(path) = &config.unix_stream {
+        let listener = UnixListener::bind(remove_stale_socket(path)?)?;
+        info!("collecting metrics on unix stream socket {}", path.display());
+        spawn("collector-unix-stream", move || {
+            for (connection, stream) in listener.incoming().enumerate() {
+                // a failed connection must not stop the listener
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("unable to accept unix stream connection: {e}");
+                        continue;
+                    }
+                };
+                let source = Source::UnixStream(connection as u64);
+                let tx = tx.clone();
+                if let Err(e) = spawn("collector-unix-conn", move || forward_lines(stream, source, tx)) {
+                    warn!("unable to handle unix stream connection: {e}");
+                }
+            }
+            Ok(())
+        })?;
+        listening = true;
+    }
+    match listening {
+        true => Ok(()),
+        false => Err(Error::other("no socket configured for the collector to listen on")),
+    }
+}
+
+fn spawn<F>(name: &str, f: F) -> std::io::Result<()>
+where
+    F: FnOnce() -> std::io::Result<()> + Send + 'static,
+{
+    std::thread::Builder::new().name(name.to_owned()).spawn(move || {
+        if let Err(e) = f() {
+            warn!("collector socket failed: {e}");
+        }
+    })?;
+    Ok(())
+}
+
+/// Forwards the lines received over the connection until it is closed.
+fn forward_lines(stream: impl Read, source: Source, tx: Sender<Message>) -> std::io::Result<()> {
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                // the totals of the source must be detached even when the connection failed
+                let _ = tx.send(Message::Closed(source));
+                return Err(e);
+            }
+        };
+        if tx.send(Message::Received(source.clone(), line)).is_err() {
+            return Ok(());
+        }
+    }
+    let _ = tx.send(Message::Closed(source));
+    Ok(())
+}
+
+/// Removes the socket file left behind by a previous run, if any, so that the path can be bound again.
+pub fn remove_stale_socket(path: &Path) -> std::io::Result<&Path> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_rebuild_distribution_from_stats() {
+        let stats = HistogramStats {
+            count: 1000,
+            min: 1,
+            max: 5000,
+            mean: 0.0,
+            p50: 100,
+            p75: 200,
+            p90: 400,
+            p95: 800,
+            p99: 1600,
+            p999: 3200,
+            p9999: 4000,
+        };
+        let distribution = distribution(&stats);
+        assert_eq!(1000, distribution.len());
+        assert_eq!(1, distribution.min());
+        assert!(distribution.equivalent(5000, distribution.max()));
+        for (quantile, value) in [
+            (0.5, 100),
+            (0.75, 200),
+            (0.9, 400),
+            (0.95, 800),
+            (0.99, 1600),
+            (0.999, 3200),
+        ] {
+            assert!(distribution.equivalent(value, distribution.value_at_quantile(quantile)), "{quantile}");
+        }
+    }
+
+    #[test]
+    fn should_rebuild_distribution_of_single_value() {
+        let stats = HistogramStats {
+            count: 1,
+            min: 42,
+            max: 42,
+            mean: 42.0,
+            p50: 42,
+            p75: 42,
+            p90: 42,
+            p95: 42,
+            p99: 42,
+            p999: 42,
+            p9999: 42,
+        };
+        let distribution = distribution(&stats);
+        assert_eq!(1, distribution.len());
+        assert_eq!(42, distribution.min());
+        assert_eq!(42, distribution.max());
+    }
+}
@@ -1,24 +1,58 @@
 use crate::OwnedTags;
-use crate::aggregator::Encoder;
+pub use crate::aggregator::Encoder;
 use duration_str::deserialize_duration;
 use metricus::PreAllocatedMetric;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use std::collections::HashMap;
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
 use std::vec;
 
 /// Metrics config to be passed to MetricsAgent during initialisation.
 #[serde_as]
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MetricsConfig {
     /// Interval at which metrics are written to the targets. This defaults to 10 seconds.
     #[serde(deserialize_with = "deserialize_duration")]
     #[serde(default = "get_default_flush_interval")]
     pub flush_interval: Duration,
+    /// Align flushes to multiples of the `flush_interval` since the unix epoch, so that all processes
+    /// flush on the same wall-clock boundaries (e.g. every 10s on :00/:10/:20). This defaults to false.
+    #[serde(default)]
+    pub align_flush_to_interval: bool,
+    /// Timestamp to stamp the exported metrics with. This defaults to the time at which flushing happened.
+    #[serde(default)]
+    pub timestamp_mode: TimestampMode,
+    /// Number of flush intervals without any updates after which a metric is no longer exported, which must be
+    /// at least 1. The aggregated state of an expired histogram is evicted and only its name and tags are kept until
+    /// it is deleted, whereas an expired counter keeps its running total. The metric is exported again as soon as it
+    /// receives an update, an evicted counter carrying on from its total. This defaults to no expiry.
+    #[serde(default)]
+    pub metric_ttl_intervals: Option<u32>,
+    /// Report creation and deletion of metrics as `metricus` counters tagged with the lifecycle `event`.
+    /// This defaults to false.
+    #[serde(default)]
+    pub report_metric_lifecycle: bool,
+    /// Limits on the number of series that can be created. This defaults to no limits.
+    #[serde(default)]
+    pub cardinality: CardinalityConfig,
+    /// Filtering and relabeling rules applied to all metrics at registration time, which also apply to the in-process
+    /// queries. A metric whose relabeled series collides with the series of another metric is rejected with a warning,
+    /// rather than merged into it. This defaults to no rules.
+    #[serde(default)]
+    pub rules: RulesConfig,
+    /// Filtering and relabeling rules applied by the aggregator on top of the global `rules`, to what is sent to the
+    /// exporter only. The agent has a single exporter, so these are its overrides of the global `rules`. As with the
+    /// global `rules`, a metric whose relabeled series collides with the series of another metric is rejected with a
+    /// warning, in which case it is still aggregated and queryable but not exported. This defaults to no rules.
+    #[serde(default)]
+    pub exporter_rules: RulesConfig,
+    /// Histogram modes determining which distributions are exported for each histogram.
+    #[serde(default)]
+    pub histograms: HistogramConfig,
     /// Default tags that will be added to all metrics.
     #[serde_as(as = "HashMap<_, _>")]
     #[serde(default)]
@@ -26,6 +60,20 @@ pub struct MetricsConfig {
     /// Event channel size between the metrics agent and aggregator. This defaults to 1 million.
     #[serde(default = "get_default_event_channel_size")]
     pub event_channel_size: usize,
+    /// Pre-aggregate the updates on each thread before sending them to the aggregator. This defaults to
+    /// sending every update as is.
+    #[serde(default)]
+    pub local_aggregation: Option<LocalAggregationConfig>,
+    /// Keep the counters in a fixed size arena of atomic slots that the aggregator reads when flushing, rather than
+    /// sending their increments through the event channel. This defaults to no arena.
+    #[serde(default)]
+    pub counter_arena: Option<CounterArenaConfig>,
+    /// Shared memory segment written by the `SharedMemoryMetrics` backend and read by the `metricus-sidecar`.
+    #[serde(default)]
+    pub shared_memory: SharedMemoryConfig,
+    /// Sockets the `metricus-collector` listens on for the metrics exported by other processes.
+    #[serde(default)]
+    pub collector: CollectorConfig,
     /// Metrics exporter type.
     #[serde(default)]
     pub exporter: ExporterSource,
@@ -39,6 +87,32 @@ pub struct MetricsConfig {
     pub aggregator_affinity_cpu_index: Option<usize>,
 }
 
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            flush_interval: get_default_flush_interval(),
+            align_flush_to_interval: false,
+            timestamp_mode: TimestampMode::default(),
+            metric_ttl_intervals: None,
+            report_metric_lifecycle: false,
+            cardinality: CardinalityConfig::default(),
+            rules: RulesConfig::default(),
+            exporter_rules: RulesConfig::default(),
+            histograms: HistogramConfig::default(),
+            default_tags: OwnedTags::default(),
+            event_channel_size: get_default_event_channel_size(),
+            local_aggregation: None,
+            counter_arena: None,
+            shared_memory: SharedMemoryConfig::default(),
+            collector: CollectorConfig::default(),
+            exporter: ExporterSource::default(),
+            pre_allocated_metrics: vec![],
+            aggregator_affinity_cpu_id: None,
+            aggregator_affinity_cpu_index: None,
+        }
+    }
+}
+
 impl MetricsConfig {
     pub fn from_file(path: impl AsRef<Path>) -> std::io::Result<MetricsConfig> {
         serde_yaml::from_reader(std::fs::File::open(path)?).map_err(std::io::Error::other)
@@ -78,9 +152,282 @@ const fn get_default_flush_interval() -> Duration {
     Duration::from_secs(10)
 }
 
+/// Determines which timestamp is attached to the exported metrics.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampMode {
+    /// Time at which the flush actually happened.
+    #[default]
+    FlushTime,
+    /// Start of the flush interval the metrics belong to.
+    IntervalStart,
+    /// End of the flush interval the metrics belong to.
+    IntervalEnd,
+}
+
+/// Per-thread pre-aggregation of the updates. Counter increments are summed up and histogram values are recorded
+/// into a thread local histogram, which are sent to the aggregator once either limit is hit or the thread calls
+/// [`metricus::flush_local`]. The limits are only checked on updates, so a thread that goes idle should flush
+/// explicitly.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct LocalAggregationConfig {
+    /// Number of updates after which the thread flushes. This defaults to 1024.
+    #[serde(default = "get_default_local_max_events")]
+    pub max_events: usize,
+    /// Time since the first buffered update after which the thread flushes. This defaults to 1 millisecond.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[serde(default = "get_default_local_max_delay")]
+    pub max_delay: Duration,
+}
+
+impl Default for LocalAggregationConfig {
+    fn default() -> Self {
+        Self {
+            max_events: get_default_local_max_events(),
+            max_delay: get_default_local_max_delay(),
+        }
+    }
+}
+
+const fn get_default_local_max_events() -> usize {
+    1024
+}
+
+const fn get_default_local_max_delay() -> Duration {
+    Duration::from_millis(1)
+}
+
+/// Arena of cache line padded atomic counters. Incrementing a counter in the arena is a single relaxed atomic add,
+/// with no event sent to the aggregator. The slot of a deleted counter is reused once its final value has been
+/// flushed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct CounterArenaConfig {
+    /// Number of counters the arena can hold.
+    pub capacity: usize,
+    /// What to do with the new counters once the arena is full. This defaults to sending them through the channel.
+    #[serde(default)]
+    pub when_full: ArenaFallback,
+}
+
+/// Determines what happens to the new counters once the counter arena is full.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArenaFallback {
+    /// Counter increments are sent through the event channel as if there was no arena.
+    #[default]
+    Channel,
+    /// Counter is rejected and all its increments are discarded.
+    Reject,
+}
+
+/// Layout and location of the shared memory segment. The writer and the sidecar must use the same path, the capacity
+/// is read from the segment by the sidecar.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SharedMemoryConfig {
+    /// Path of the memory-mapped file. This defaults to `/dev/shm/metricus`.
+    #[serde(default = "get_default_shared_memory_path")]
+    pub path: PathBuf,
+    /// Number of counters the segment can hold. This defaults to 4096.
+    #[serde(default = "get_default_shared_memory_counters")]
+    pub counters: usize,
+    /// Number of histograms the segment can hold, each taking 15KiB. This defaults to 256.
+    #[serde(default = "get_default_shared_memory_histograms")]
+    pub histograms: usize,
+    /// Interval at which the sidecar reads the segment. This defaults to 100 milliseconds.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[serde(default = "get_default_shared_memory_poll_interval")]
+    pub poll_interval: Duration,
+}
+
+impl Default for SharedMemoryConfig {
+    fn default() -> Self {
+        Self {
+            path: get_default_shared_memory_path(),
+            counters: get_default_shared_memory_counters(),
+            histograms: get_default_shared_memory_histograms(),
+            poll_interval: get_default_shared_memory_poll_interval(),
+        }
+    }
+}
+
+fn get_default_shared_memory_path() -> PathBuf {
+    PathBuf::from("/dev/shm/metricus")
+}
+
+const fn get_default_shared_memory_counters() -> usize {
+    4096
+}
+
+const fn get_default_shared_memory_histograms() -> usize {
+    256
+}
+
+const fn get_default_shared_memory_poll_interval() -> Duration {
+    Duration::from_millis(100)
+}
+
+/// Sockets on which the collector receives the metrics exported by other processes, encoded with either the line
+/// protocol or the json encoder. At least one of them must be set.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CollectorConfig {
+    /// Address of the udp socket, e.g. `0.0.0.0:8777`. This defaults to not listening on udp.
+    #[serde(default)]
+    pub udp: Option<String>,
+    /// Address of the tcp socket, e.g. `0.0.0.0:8777`. This defaults to not listening on tcp.
+    #[serde(default)]
+    pub tcp: Option<String>,
+    /// Path of the unix datagram socket. This defaults to not listening on a unix datagram socket.
+    #[serde(default)]
+    pub unix_datagram: Option<PathBuf>,
+    /// Path of the unix stream socket. This defaults to not listening on a unix stream socket.
+    #[serde(default)]
+    pub unix_stream: Option<PathBuf>,
+    /// Encoder used by the exporters of the senders. This defaults to line protocol.
+    #[serde(default)]
+    pub format: Format,
+}
+
+/// Limits on the number of series, i.e. distinct measurement name and tags combinations.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CardinalityConfig {
+    /// Maximum number of series per measurement name.
+    #[serde(default)]
+    pub max_series_per_measurement: Option<usize>,
+    /// Maximum number of series across all measurements.
+    #[serde(default)]
+    pub max_series: Option<usize>,
+    /// What to do with the new series once any of the limits has been hit.
+    #[serde(default)]
+    pub policy: CardinalityPolicy,
+}
+
+/// Determines what happens to the new series once the cardinality limit has been hit.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CardinalityPolicy {
+    /// Series is rejected and all its updates are discarded.
+    #[default]
+    Reject,
+    /// Series is folded into a single per measurement series tagged with `__overflow__=true`.
+    Overflow,
+}
+
+/// Determines which distributions are exported for the histograms. Each histogram uses the modes of the first
+/// matching rule, or the default modes if none of the rules match. The rules are matched against the measurement
+/// name and tags as exported, i.e. after both the global and the exporter rules have been applied.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistogramConfig {
+    /// This defaults to the interval mode only.
+    #[serde(default = "get_default_histogram_modes")]
+    pub default_modes: Vec<HistogramMode>,
+    #[serde(default)]
+    pub rules: Vec<HistogramModeRule>,
+}
+
+impl Default for HistogramConfig {
+    fn default() -> Self {
+        Self {
+            default_modes: get_default_histogram_modes(),
+            rules: vec![],
+        }
+    }
+}
+
+fn get_default_histogram_modes() -> Vec<HistogramMode> {
+    vec![HistogramMode::Interval]
+}
+
+/// Histogram modes applied to the histograms matching the (optional) `match` rule.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistogramModeRule {
+    #[serde(default, rename = "match")]
+    pub matcher: MatchRule,
+    pub modes: Vec<HistogramMode>,
+}
+
+/// Distribution exported for a histogram. Except for the interval mode, each exported point is tagged
+/// with the `window` it represents, e.g. `window=cumulative` or `window=5m`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum HistogramMode {
+    /// Values recorded since the last flush.
+    Interval,
+    /// Values recorded since the histogram has been created.
+    Cumulative,
+    /// Values recorded within the sliding window, made of one sub-histogram per flush interval.
+    Window {
+        #[serde(deserialize_with = "deserialize_duration")]
+        length: Duration,
+    },
+}
+
+/// Filtering and relabeling rules. A metric is kept if it matches any of the `allow` rules (or there are
+/// none) and does not match any of the `deny` rules. The `relabel` rules are then applied in order to the kept
+/// metrics. Rules are matched against the measurement name and tags including the `type` and default tags.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RulesConfig {
+    #[serde(default)]
+    pub allow: Vec<MatchRule>,
+    #[serde(default)]
+    pub deny: Vec<MatchRule>,
+    #[serde(default)]
+    pub relabel: Vec<RelabelRule>,
+}
+
+/// Matches metrics by measurement name and tag values. All the specified patterns must match.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MatchRule {
+    #[serde(default)]
+    pub name: Option<Pattern>,
+    /// Tag keys that must be present with the value matching the pattern.
+    #[serde_as(as = "HashMap<_, _>")]
+    #[serde(default)]
+    pub tags: Vec<(String, Pattern)>,
+}
+
+/// Pattern used to match measurement names and tag values, expressed as either `{ glob: "..." }` or `{ regex: "..." }`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Pattern {
+    /// Glob pattern where `*` matches any sequence of characters and `?` matches any single character.
+    Glob { glob: String },
+    /// Regular expression that must match the whole value.
+    Regex { regex: String },
+}
+
+/// Relabeling action applied to the metrics matching the (optional) `match` rule.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RelabelRule {
+    #[serde(default, rename = "match")]
+    pub matcher: MatchRule,
+    #[serde(flatten)]
+    pub action: RelabelAction,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
+#[serde(tag = "action")]
+pub enum RelabelAction {
+    /// Renames the measurement.
+    Rename { to: String },
+    /// Removes the tag with the given key.
+    DropTag { key: String },
+    /// Adds the tag, replacing the value of any existing tag with the same key.
+    AddTag { key: String, value: String },
+    /// Replaces the values of the tag with the given key according to the mapping.
+    MapTagValue {
+        key: String,
+        #[serde(default)]
+        values: HashMap<String, String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
 pub enum Format {
+    #[default]
     LineProtocol,
     Json,
 }
@@ -0,0 +1,264 @@
+//! Parses the lines written by the line protocol and json encoders back into typed counter and histogram points.
+//!
+//! In the line protocol, the backslash escapes the commas, spaces, equal signs and backslashes in the measurement name
+//! and tags, a backslash before any other character being part of the name or tag, and the integer fields may carry
+//! the `u` (or `i`) suffix. Since the window of a histogram is written as its last tag, a histogram whose last tag is
+//! `window` is always decoded as a windowed distribution.
+//!
+//! ## Examples
+//!
+//! ```
+//! use metricus_agent::config::Format;
+//! use metricus_agent::decoder::Point;
+//!
+//! let point = Format::LineProtocol.decode(r"orders,venue=xnas,desk=us\ equities,type=counter value=42u 1700000000000000000").unwrap();
+//! let Point::Counter(counter) = point else { panic!() };
+//! assert_eq!("orders", counter.name);
+//! assert_eq!(("desk".to_owned(), "us equities".to_owned()), counter.tags[1]);
+//! assert_eq!(42, counter.value);
+//! ```
+
+use crate::aggregator::{MEASUREMENT_SPECIAL_CHARS, TAG_SPECIAL_CHARS};
+use crate::config::Format;
+use crate::query::HistogramStats;
+use crate::{Error, OwnedTags, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Metric as exported at a given flush.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Point {
+    Counter(CounterPoint),
+    Histogram(HistogramPoint),
+}
+
+impl Point {
+    pub fn name(&self) -> &str {
+        match self {
+            Point::Counter(counter) => &counter.name,
+            Point::Histogram(histogram) => &histogram.name,
+        }
+    }
+
+    pub fn tags(&self) -> &OwnedTags {
+        match self {
+            Point::Counter(counter) => &counter.tags,
+            Point::Histogram(histogram) => &histogram.tags,
+        }
+    }
+
+    /// Time of the flush (nanoseconds since the unix epoch).
+    pub const fn timestamp(&self) -> u64 {
+        match self {
+            Point::Counter(counter) => counter.timestamp,
+            Point::Histogram(histogram) => histogram.timestamp,
+        }
+    }
+}
+
+/// Running total of the counter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CounterPoint {
+    pub name: String,
+    pub tags: OwnedTags,
+    pub value: u64,
+    pub timestamp: u64,
+}
+
+/// Distribution of a histogram for one of its windows, the interval window having no label.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramPoint {
+    pub name: String,
+    pub tags: OwnedTags,
+    pub window: Option<String>,
+    /// Only 1 in `sample_rate` values has been recorded.
+    pub sample_rate: u32,
+    pub stats: HistogramStats,
+    pub timestamp: u64,
+}
+
+impl Format {
+    /// Decodes a line written by the encoder.
+    pub fn decode(&self, line: &str) -> Result<Point> {
+        match self {
+            Format::LineProtocol => decode_line_protocol(line),
+            Format::Json => decode_json(line),
+        }
+    }
+}
+
+/// Decodes a line written by the json encoder.
+pub fn decode_json(line: &str) -> Result<Point> {
+    #[derive(Deserialize)]
+    struct JsonPoint {
+        name: String,
+        tags: OwnedTags,
+        timestamp: u64,
+        value: Option<u64>,
+        window: Option<String>,
+        sample_rate: Option<f64>,
+        #[serde(flatten)]
+        stats: Option<HistogramStats>,
+    }
+
+    let point: JsonPoint = serde_json::from_str(line).map_err(Error::other)?;
+    match (point.value, point.stats) {
+        (Some(value), _) => Ok(Point::Counter(CounterPoint {
+            name: point.name,
+            tags: point.tags,
+            value,
+            timestamp: point.timestamp,
+        })),
+        (None, Some(stats)) => Ok(Point::Histogram(HistogramPoint {
+            name: point.name,
+            tags: point.tags,
+            window: point.window,
+            sample_rate: to_sample_rate(point.sample_rate),
+            stats,
+            timestamp: point.timestamp,
+        })),
+        (None, None) => Err(Error::other("neither a counter nor a histogram")),
+    }
+}
+
+/// Decodes a line written by the line protocol encoder, i.e. `measurement[,key=value...] field=value[,...] timestamp`.
+pub fn decode_line_protocol(line: &str) -> Result<Point> {
+    let line = line.trim_end_matches(['\n', '\r']);
+    let mut sections = split_unescaped(line, b' ', TAG_SPECIAL_CHARS).filter(|section| !section.is_empty());
+    let (Some(series), Some(fields), Some(timestamp)) = (sections.next(), sections.next(), sections.next()) else {
+        return Err(Error::other("expected measurement, fields and timestamp"));
+    };
+    if sections.next().is_some() {
+        return Err(Error::other("unexpected data after the timestamp"));
+    }
+
+    let mut series = split_unescaped(series, b',', TAG_SPECIAL_CHARS);
+    let name = unescape(series.next().unwrap_or_default(), MEASUREMENT_SPECIAL_CHARS);
+    if name.is_empty() {
+        return Err(Error::other("missing measurement"));
+    }
+    let mut tags = series
+        .map(|tag| {
+            let mut parts = split_unescaped(tag, b'=', TAG_SPECIAL_CHARS);
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(key), Some(value), None) => {
+                    Ok((unescape(key, TAG_SPECIAL_CHARS), unescape(value, TAG_SPECIAL_CHARS)))
+                }
+                _ => Err(Error::other(format!("invalid tag {tag}"))),
+            }
+        })
+        .collect::<Result<OwnedTags>>()?;
+    let fields = split_unescaped(fields, b',', TAG_SPECIAL_CHARS)
+        .map(|field| {
+            field
+                .split_once('=')
+                .ok_or_else(|| Error::other(format!("invalid field {field}")))
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+    let timestamp = timestamp
+        .parse()
+        .map_err(|e| Error::other(format!("invalid timestamp: {e}")))?;
+    let field = |key: &str| {
+        fields
+            .get(key)
+            .copied()
+            .ok_or_else(|| Error::other(format!("missing field {key}")))
+    };
+    let uint = |key: &str| parse_uint(key, field(key)?);
+
+    if fields.contains_key("value") {
+        return Ok(Point::Counter(CounterPoint {
+            name,
+            tags,
+            value: uint("value")?,
+            timestamp,
+        }));
+    }
+    let window = match tags.last() {
+        Some((key, _)) if key == "window" => tags.pop().map(|(_, window)| window),
+        _ => None,
+    };
+    let sample_rate = fields
+        .get("sample_rate")
+        .map(|rate| {
+            rate.parse()
+                .map_err(|e| Error::other(format!("invalid field sample_rate: {e}")))
+        })
+        .transpose()?;
+    Ok(Point::Histogram(HistogramPoint {
+        name,
+        tags,
+        window,
+        sample_rate: to_sample_rate(sample_rate),
+        stats: HistogramStats {
+            count: uint("count")?,
+            min: uint("min")?,
+            max: uint("max")?,
+            mean: field("mean")?
+                .parse()
+                .map_err(|e| Error::other(format!("invalid field mean: {e}")))?,
+            p50: uint("p50")?,
+            p75: uint("p75")?,
+            p90: uint("p90")?,
+            p95: uint("p95")?,
+            p99: uint("p99")?,
+            p999: uint("p999")?,
+            p9999: uint("p9999")?,
+        },
+        timestamp,
+    }))
+}
+
+/// Parses the integer field, with or without the `u` or `i` suffix.
+fn parse_uint(key: &str, value: &str) -> Result<u64> {
+    let digits = value.strip_suffix(['u', 'i']).unwrap_or(value);
+    digits
+        .parse()
+        .map_err(|e| Error::other(format!("invalid field {key}: {e}")))
+}
+
+/// Splits on the separator, unless it is escaped by a backslash.
+fn split_unescaped<'a>(value: &'a str, separator: u8, special_chars: &'a [u8]) -> impl Iterator<Item = &'a str> {
+    let bytes = value.as_bytes();
+    let mut start = Some(0);
+    std::iter::from_fn(move || {
+        let from = start?;
+        let mut index = from;
+        while index < bytes.len() {
+            match bytes[index] {
+                b'\\' if bytes.get(index + 1).is_some_and(|c| special_chars.contains(c)) => index += 2,
+                c if c == separator => {
+                    start = Some(index + 1);
+                    return Some(&value[from..index]);
+                }
+                _ => index += 1,
+            }
+        }
+        start = None;
+        Some(&value[from..])
+    })
+}
+
+/// Removes the backslashes escaping the special characters, any other backslash is kept as is.
+fn unescape(value: &str, special_chars: &[u8]) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            Some(&next) if c == '\\' && next.is_ascii() && special_chars.contains(&(next as u8)) => {
+                unescaped.push(next);
+                chars.next();
+            }
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Converts the exported fraction of the values recorded back into the 1 in N sample rate.
+fn to_sample_rate(fraction: Option<f64>) -> u32 {
+    match fraction {
+        Some(fraction) if fraction > 0.0 && fraction < 1.0 => (1.0 / fraction).round() as u32,
+        _ => 1,
+    }
+}
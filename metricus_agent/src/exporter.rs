@@ -1,4 +1,4 @@
-use crate::aggregator::{Counter, Counters, Encoder, Histogram, Histograms};
+use crate::aggregator::{Counter, Counters, Encoder, Histogram, Histograms, Metric};
 use crate::config::{ExporterSource, FileConfig, UdpConfig, UnixSocketConfig};
 use log::warn;
 use metricus::Id;
@@ -79,14 +79,15 @@ impl TryFrom<UdpConfig> for UdpExporter {
 impl UdpExporter {
     fn publish_metrics<T, F>(&mut self, items: &HashMap<Id, T>, timestamp: u64, encode: F) -> std::io::Result<()>
     where
+        T: Metric,
         F: Fn(&Encoder, &T, u64, &mut Vec<u8>) -> std::io::Result<()>,
     {
-        if items.is_empty() {
-            return Ok(());
+        for item in items.values().filter(|item| item.meta_data().is_exported()) {
+            encode(&self.encoder, item, timestamp, &mut self.buffer)?;
         }
 
-        for item in items.values() {
-            encode(&self.encoder, item, timestamp, &mut self.buffer)?;
+        if self.buffer.is_empty() {
+            return Ok(());
         }
 
         // we can ignore connection refused in case the udp listener is temporarily unavailable
@@ -137,14 +138,15 @@ impl TryFrom<UnixSocketConfig> for UnixDatagramExporter {
 impl UnixDatagramExporter {
     fn publish_metrics<T, F>(&mut self, items: &HashMap<Id, T>, timestamp: u64, encode: F) -> std::io::Result<()>
     where
+        T: Metric,
         F: Fn(&Encoder, &T, u64, &mut Vec<u8>) -> std::io::Result<()>,
     {
-        if items.is_empty() {
-            return Ok(());
+        for item in items.values().filter(|item| item.meta_data().is_exported()) {
+            encode(&self.encoder, item, timestamp, &mut self.buffer)?;
         }
 
-        for item in items.values() {
-            encode(&self.encoder, item, timestamp, &mut self.buffer)?;
+        if self.buffer.is_empty() {
+            return Ok(());
         }
 
         // we can ignore file not found in case the listener unix socket is temporarily unavailable
@@ -210,7 +212,7 @@ impl TryFrom<UnixSocketConfig> for StreamExporter<UnixStream> {
 
 impl<S: Write> StreamExporter<S> {
     fn publish_counters(&mut self, counters: &HashMap<Id, Counter>, timestamp: u64) -> std::io::Result<()> {
-        for counter in counters.values() {
+        for counter in counters.values().filter(|counter| counter.meta_data().is_exported()) {
             self.encoder.encode_counter(counter, timestamp, &mut self.writer)?;
         }
         self.writer.flush()?;
@@ -218,7 +220,10 @@ impl<S: Write> StreamExporter<S> {
     }
 
     fn publish_histograms(&mut self, histograms: &HashMap<Id, Histogram>, timestamp: u64) -> std::io::Result<()> {
-        for histogram in histograms.values() {
+        for histogram in histograms
+            .values()
+            .filter(|histogram| histogram.meta_data().is_exported())
+        {
             self.encoder.encode_histogram(histogram, timestamp, &mut self.writer)?;
         }
         self.writer.flush()?;
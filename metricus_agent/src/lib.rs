@@ -2,13 +2,29 @@
 
 mod affinity;
 mod aggregator;
+mod arena;
+#[cfg(unix)]
+pub mod collector;
 pub mod config;
+pub mod decoder;
 mod error;
 mod exporter;
+mod local;
+pub mod query;
+mod rules;
+#[cfg(unix)]
+pub mod shm;
+#[cfg(unix)]
+pub mod sidecar;
 
 use crate::aggregator::MetricsAggregator;
-use crate::config::MetricsConfig;
-use metricus::{Id, Metrics, PreAllocatedMetric, Tag, Tags, set_metrics};
+use crate::arena::CounterArena;
+use crate::config::{ArenaFallback, CardinalityConfig, CardinalityPolicy, LocalAggregationConfig, MetricsConfig};
+use crate::local::Buffered;
+use crate::query::MetricsQuery;
+use crate::rules::{HistogramModes, Rules};
+use log::warn;
+use metricus::{DYNAMIC_IDS, Id, Metrics, PreAllocatedMetric, RESERVED_IDS, Tag, Tags, set_metrics};
 #[cfg(feature = "rtrb")]
 use rtrb::Producer;
 #[cfg(not(feature = "rtrb"))]
@@ -17,6 +33,18 @@ use std::sync::mpsc::SyncSender;
 // re-exports
 pub use error::{Error, Result};
 use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+
+/// Id assigned to the series rejected due to the cardinality limits. Updates to this id are discarded.
+const SINK_ID: Id = RESERVED_IDS.end();
+/// Tag identifying the series into which the series over the cardinality limits are folded.
+const OVERFLOW_TAG: Tag = ("__overflow__", "true");
+
+/// Number of registrations rejected due to the cardinality limits by measurement, counted by the agent and
+/// reported by the aggregator when flushing. Rejections are not sent as control events so that they cannot crowd
+/// out the creation and deletion of metrics.
+type CardinalityHits = Arc<Mutex<HashMap<String, u64>>>;
 
 type OwnedTag = (String, String);
 type OwnedTags = Vec<OwnedTag>;
@@ -51,8 +79,19 @@ pub struct MetricsAgent {
     #[cfg(not(feature = "rtrb"))]
     tx_cnc: SyncSender<ControlEvent>,
     default_tags: OwnedTags,
+    /// Next id to be assigned dynamically, always within [DYNAMIC_IDS].
     next_id: Id,
     metric_key_to_id: HashMap<MetricKey, Id>,
+    registrations: HashMap<Id, Registration>,
+    /// Names of the pre-allocated metrics by their (reserved) id.
+    pre_allocated_ids: HashMap<Id, String>,
+    cardinality: CardinalityConfig,
+    series_count: usize,
+    series_per_measurement: HashMap<String, usize>,
+    rules: Rules,
+    local_aggregation: Option<LocalAggregationConfig>,
+    counter_arena: Option<Arc<CounterArena>>,
+    cardinality_hits: CardinalityHits,
 }
 
 impl MetricsAgent {
@@ -63,6 +102,14 @@ impl MetricsAgent {
 
     /// Init agent with user supplied config.
     pub fn init_with_config(config: MetricsConfig) -> Result<()> {
+        set_metrics(Self::start_with_config(config)?);
+        Ok(())
+    }
+
+    /// Start the aggregator with user supplied config and return the agent without installing it as the
+    /// metrics backend. This allows the agent to be composed with other backends (see [`metricus::layer`])
+    /// before being passed to [`set_metrics`].
+    pub fn start_with_config(config: MetricsConfig) -> Result<MetricsAgent> {
         #[cfg(feature = "rtrb")]
         let (tx_upd, rx_upd) = rtrb::RingBuffer::new(config.event_channel_size);
         #[cfg(feature = "rtrb")]
@@ -72,50 +119,243 @@ impl MetricsAgent {
         #[cfg(not(feature = "rtrb"))]
         let (tx_cnc, rx_cnc) = std::sync::mpsc::sync_channel(1024);
 
+        // validate exporter rules and histogram modes before the aggregator is started
+        if config.metric_ttl_intervals == Some(0) {
+            return Err(Error::other("metric_ttl_intervals must be at least 1"));
+        }
+        Rules::try_from(config.exporter_rules.clone())?;
+        HistogramModes::new(config.histograms.clone(), config.flush_interval)?;
+
+        let counter_arena = config
+            .counter_arena
+            .as_ref()
+            .map(CounterArena::new)
+            .transpose()?
+            .map(Arc::new);
+
+        let mut agent = MetricsAgent::new(tx_upd, tx_cnc, &config)?;
+        agent.counter_arena = counter_arena.clone();
+        for metric in config.pre_allocated_metrics.iter().cloned() {
+            agent.register_metric_with_id(metric)?;
+        }
+
         // launch aggregator on background thread
-        let _ = MetricsAggregator::start_on_thread(rx_upd, rx_cnc, config.clone());
+        let (tx_query, rx_query) = std::sync::mpsc::channel();
+        let cardinality_hits = agent.cardinality_hits.clone();
+        let _ = MetricsAggregator::start_on_thread(rx_upd, rx_cnc, rx_query, counter_arena, cardinality_hits, config);
+        MetricsQuery::register(tx_query);
 
-        let mut agent = MetricsAgent::new(tx_upd, tx_cnc, config.default_tags);
-        for metric in config.pre_allocated_metrics {
-            agent.register_metric_with_id(metric);
-        }
+        Ok(agent)
+    }
 
-        set_metrics(agent);
-        Ok(())
+    /// Returns a handle to query the current metric values in-process. Fails if the agent has not been initialised.
+    pub fn query() -> Result<MetricsQuery> {
+        MetricsQuery::get()
     }
 
     #[cfg(feature = "rtrb")]
-    fn new(tx_upd: Producer<UpdateEvent>, tx_cnc: Producer<ControlEvent>, default_tags: OwnedTags) -> Self {
-        Self {
+    fn new(tx_upd: Producer<UpdateEvent>, tx_cnc: Producer<ControlEvent>, config: &MetricsConfig) -> Result<Self> {
+        Ok(Self {
             tx_upd,
             tx_cnc,
-            default_tags,
+            default_tags: config.default_tags.clone(),
             next_id: 0,
             metric_key_to_id: Default::default(),
-        }
+            registrations: Default::default(),
+            pre_allocated_ids: Default::default(),
+            cardinality: config.cardinality.clone(),
+            series_count: 0,
+            series_per_measurement: Default::default(),
+            rules: Rules::try_from(config.rules.clone())?,
+            local_aggregation: config.local_aggregation,
+            counter_arena: None,
+            cardinality_hits: Default::default(),
+        })
     }
 
     #[cfg(not(feature = "rtrb"))]
-    fn new(tx_upd: SyncSender<UpdateEvent>, tx_cnc: SyncSender<ControlEvent>, default_tags: OwnedTags) -> Self {
-        Self {
+    fn new(tx_upd: SyncSender<UpdateEvent>, tx_cnc: SyncSender<ControlEvent>, config: &MetricsConfig) -> Result<Self> {
+        Ok(Self {
             tx_upd,
             tx_cnc,
-            default_tags,
+            default_tags: config.default_tags.clone(),
             next_id: 0,
             metric_key_to_id: Default::default(),
-        }
+            registrations: Default::default(),
+            pre_allocated_ids: Default::default(),
+            cardinality: config.cardinality.clone(),
+            series_count: 0,
+            series_per_measurement: Default::default(),
+            rules: Rules::try_from(config.rules.clone())?,
+            local_aggregation: config.local_aggregation,
+            counter_arena: None,
+            cardinality_hits: Default::default(),
+        })
     }
 
+    /// Registers a metric handle and returns the metric id. Metrics with the same name and tags share
+    /// the same id and the metric is only created on its first registration. Once the cardinality limits
+    /// are hit, new series are either rejected (and assigned the [SINK_ID]) or folded into the overflow series.
+    /// The sample rate only applies to histograms and is set by their first registration, a warning is logged if a
+    /// later registration asks for another rate. A metric relabeled by the rules onto the series of another metric is
+    /// rejected, with a warning, rather than merged into it.
     #[inline]
-    fn assign_next_id(&mut self, name: &str, tags: OwnedTags) -> Id {
-        *self
+    fn register(&mut self, kind: MetricKind, name: &str, tags: OwnedTags, sample_rate: u32) -> Id {
+        let Some((exported_name, exported_tags)) = self.rules.apply(name, &tags) else {
+            return SINK_ID;
+        };
+        // the original series is only needed to tell relabeled metrics apart
+        let origin = self.rules.relabels().then(|| MetricKey::new(name, tags.clone()));
+        let key = MetricKey::new(&exported_name, exported_tags);
+        let collides = self
             .metric_key_to_id
-            .entry(MetricKey::new(name, tags))
-            .or_insert_with(|| {
-                let id = self.next_id;
-                self.next_id += 1;
-                id
-            })
+            .get(&key)
+            .and_then(|id| self.registrations.get(id))
+            .is_some_and(|existing| existing.origin != origin);
+        if collides {
+            warn!(
+                "rules relabel {name} {tags:?} onto the series {} {:?} of another metric, it will not be registered",
+                key.name, key.tags
+            );
+            return SINK_ID;
+        }
+        if let Some(id) = self.retain_existing(&key, sample_rate) {
+            return id;
+        }
+        let name = exported_name.as_str();
+
+        let overflow = !self.is_within_cardinality_limits(name);
+        let key = if overflow {
+            *self
+                .cardinality_hits
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .entry(name.to_owned())
+                .or_default() += 1;
+            match self.cardinality.policy {
+                CardinalityPolicy::Reject => return SINK_ID,
+                CardinalityPolicy::Overflow => {
+                    let mut tags = vec![OVERFLOW_TAG.to_owned_tag()];
+                    match kind {
+                        MetricKind::Counter => self.enrich_with_counter_tags(&mut tags),
+                        MetricKind::Histogram => self.enrich_with_histogram_tags(&mut tags),
+                    }
+                    let key = MetricKey::new(name, tags);
+                    if let Some(id) = self.retain_existing(&key, sample_rate) {
+                        return id;
+                    }
+                    key
+                }
+            }
+        } else {
+            *self.series_per_measurement.entry(key.name.clone()).or_default() += 1;
+            self.series_count += 1;
+            key
+        };
+
+        let Some(id) = self.allocate_id(kind) else {
+            if !overflow {
+                self.release_series(&key.name);
+            }
+            return SINK_ID;
+        };
+        self.metric_key_to_id.insert(key.clone(), id);
+        let event = match kind {
+            MetricKind::Counter => ControlEvent::CounterCreate(id, key.name.clone(), key.tags.clone()),
+            MetricKind::Histogram => ControlEvent::HistogramCreate(id, key.name.clone(), key.tags.clone(), sample_rate),
+        };
+        self.registrations.insert(
+            id,
+            Registration {
+                key,
+                origin: if overflow { None } else { origin },
+                ref_count: 1,
+                overflow,
+                sample_rate,
+            },
+        );
+        self.send_control_event(event);
+        id
+    }
+
+    /// Assigns the id of a new metric. Counters are placed in the arena if there is one, and fall back according
+    /// to the arena config when it is full, in which case `None` is returned if the counter is rejected. `None` is
+    /// also returned once the dynamic ids have been exhausted.
+    fn allocate_id(&mut self, kind: MetricKind) -> Option<Id> {
+        if let (MetricKind::Counter, Some(arena)) = (kind, &self.counter_arena) {
+            match arena.allocate() {
+                Some(id) => return Some(id),
+                None if arena.when_full() == ArenaFallback::Reject => return None,
+                None => {}
+            }
+        }
+        // the counters in the arena take the ids at the end of the dynamic id space
+        let end = match &self.counter_arena {
+            Some(arena) => arena.base() - 1,
+            None => DYNAMIC_IDS.end(),
+        };
+        if self.next_id > end {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        Some(id)
+    }
+
+    #[inline]
+    fn retain_existing(&mut self, key: &MetricKey, sample_rate: u32) -> Option<Id> {
+        let id = *self.metric_key_to_id.get(key)?;
+        if let Some(registration) = self.registrations.get_mut(&id) {
+            registration.ref_count += 1;
+            if registration.sample_rate != sample_rate {
+                warn!(
+                    "metric {} {:?} is already registered with sample rate {}, sample rate {sample_rate} is ignored",
+                    key.name, key.tags, registration.sample_rate
+                );
+            }
+        }
+        Some(id)
+    }
+
+    fn is_within_cardinality_limits(&self, name: &str) -> bool {
+        let within_max_series = self.cardinality.max_series.is_none_or(|max| self.series_count < max);
+        let within_max_series_per_measurement = self
+            .cardinality
+            .max_series_per_measurement
+            .is_none_or(|max| self.series_per_measurement.get(name).copied().unwrap_or_default() < max);
+        within_max_series && within_max_series_per_measurement
+    }
+
+    /// Releases a metric handle and returns true if it was the last one. Metrics not registered by
+    /// the agent (e.g. pre-allocated) are always considered as released.
+    #[inline]
+    fn unregister(&mut self, id: Id) -> bool {
+        match self.registrations.get_mut(&id) {
+            Some(registration) if registration.ref_count > 1 => {
+                registration.ref_count -= 1;
+                false
+            }
+            Some(_) => {
+                if let Some(registration) = self.registrations.remove(&id) {
+                    if !registration.overflow {
+                        self.release_series(&registration.key.name);
+                    }
+                    self.metric_key_to_id.remove(&registration.key);
+                }
+                true
+            }
+            None => id != SINK_ID,
+        }
+    }
+
+    fn release_series(&mut self, name: &str) {
+        self.series_count -= 1;
+        if let Some(count) = self.series_per_measurement.get_mut(name) {
+            *count -= 1;
+            if *count == 0 {
+                self.series_per_measurement.remove(name);
+            }
+        }
     }
 
     #[inline]
@@ -126,6 +366,12 @@ impl MetricsAgent {
         let _ = self.tx_cnc.try_send(event);
     }
 
+    /// Merges the values recorded elsewhere (e.g. by another process) into the histogram.
+    #[cfg(unix)]
+    fn merge_histogram(&mut self, id: Id, values: hdrhistogram::Histogram<u64>) {
+        self.send_update_event(UpdateEvent::HistogramMerge(id, Box::new(values)));
+    }
+
     #[inline]
     fn send_update_event(&mut self, event: UpdateEvent) {
         #[cfg(feature = "rtrb")]
@@ -148,17 +394,41 @@ impl MetricsAgent {
         tags.dedup();
     }
 
-    fn register_metric_with_id(&mut self, metric: PreAllocatedMetric) {
+    /// Registers pre-allocated metric. Its id must come from the [RESERVED_IDS] range and must not
+    /// collide with any other pre-allocated metric.
+    fn register_metric_with_id(&mut self, metric: PreAllocatedMetric) -> Result<()> {
+        let id = metric.id();
+        if !RESERVED_IDS.contains(id) || id == SINK_ID {
+            return Err(Error::other(format!(
+                "pre-allocated metric '{}' uses id {id} outside of the reserved id range [{}, {}]",
+                metric.name(),
+                RESERVED_IDS.start(),
+                RESERVED_IDS.end()
+            )));
+        }
+        if let Some(existing) = self.pre_allocated_ids.get(&id) {
+            return Err(Error::other(format!(
+                "pre-allocated metric '{}' uses id {id} already assigned to pre-allocated metric '{existing}'",
+                metric.name()
+            )));
+        }
+        self.pre_allocated_ids.insert(id, metric.name().to_owned());
+
         match metric {
             PreAllocatedMetric::Counter { name, id, mut tags } => {
                 self.enrich_with_counter_tags(&mut tags);
-                self.send_control_event(ControlEvent::CounterCreate(id, name, tags))
+                if let Some((name, tags)) = self.rules.apply(&name, &tags) {
+                    self.send_control_event(ControlEvent::CounterCreate(id, name, tags))
+                }
             }
             PreAllocatedMetric::Histogram { name, id, mut tags } => {
                 self.enrich_with_histogram_tags(&mut tags);
-                self.send_control_event(ControlEvent::HistogramCreate(id, name, tags))
+                if let Some((name, tags)) = self.rules.apply(&name, &tags) {
+                    self.send_control_event(ControlEvent::HistogramCreate(id, name, tags, 1))
+                }
             }
         }
+        Ok(())
     }
 }
 
@@ -170,35 +440,78 @@ impl Metrics for MetricsAgent {
     fn new_counter(&mut self, name: &str, tags: Tags) -> Id {
         let mut tags = tags.to_owned_tags();
         self.enrich_with_counter_tags(&mut tags);
-        let id = self.assign_next_id(name, tags.clone());
-        self.send_control_event(ControlEvent::CounterCreate(id, name.to_owned(), tags));
-        id
+        self.register(MetricKind::Counter, name, tags, 1)
     }
 
     fn delete_counter(&mut self, id: Id) {
-        self.send_control_event(ControlEvent::CounterDelete(id));
+        // only delete the counter once the last handle has been dropped
+        if self.unregister(id) {
+            // the updates buffered by this thread must reach the aggregator before the metric is deleted
+            self.flush();
+            self.send_control_event(ControlEvent::CounterDelete(id));
+        }
     }
 
     #[inline]
     fn increment_counter_by(&mut self, id: Id, delta: u64) {
-        self.send_update_event(UpdateEvent::CounterIncrement(id, delta));
+        if self
+            .counter_arena
+            .as_ref()
+            .is_some_and(|arena| arena.increment(id, delta))
+        {
+            return;
+        }
+        match self
+            .local_aggregation
+            .as_ref()
+            .map(|config| local::increment(id, delta, config))
+        {
+            None | Some(Buffered::Unavailable) => self.send_update_event(UpdateEvent::CounterIncrement(id, delta)),
+            Some(Buffered::Full) => self.flush(),
+            Some(Buffered::Pending) => {}
+        }
+    }
+
+    fn counter_slot(&mut self, id: Id) -> Option<&'static AtomicU64> {
+        self.counter_arena.as_ref()?.slot(id)
     }
 
     fn new_histogram(&mut self, name: &str, tags: Tags) -> Id {
+        self.new_histogram_sampled(name, tags, 1)
+    }
+
+    fn new_histogram_sampled(&mut self, name: &str, tags: Tags, sample_rate: u32) -> Id {
         let mut tags = tags.to_owned_tags();
         self.enrich_with_histogram_tags(&mut tags);
-        let id = self.assign_next_id(name, tags.clone());
-        self.send_control_event(ControlEvent::HistogramCreate(id, name.to_owned(), tags));
-        id
+        self.register(MetricKind::Histogram, name, tags, sample_rate)
     }
 
     fn delete_histogram(&mut self, id: Id) {
-        self.send_control_event(ControlEvent::HistogramDelete(id));
+        // only delete the histogram once the last handle has been dropped
+        if self.unregister(id) {
+            // the updates buffered by this thread must reach the aggregator before the metric is deleted
+            self.flush();
+            self.send_control_event(ControlEvent::HistogramDelete(id));
+        }
     }
 
     #[inline]
     fn record(&mut self, id: Id, value: u64) {
-        self.send_update_event(UpdateEvent::HistogramRecord(id, value));
+        match self
+            .local_aggregation
+            .as_ref()
+            .map(|config| local::record(id, value, config))
+        {
+            None | Some(Buffered::Unavailable) => self.send_update_event(UpdateEvent::HistogramRecord(id, value)),
+            Some(Buffered::Full) => self.flush(),
+            Some(Buffered::Pending) => {}
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.local_aggregation.is_some() {
+            local::flush(|event| self.send_update_event(event));
+        }
     }
 }
 
@@ -206,14 +519,34 @@ impl Metrics for MetricsAgent {
 enum ControlEvent {
     CounterCreate(Id, String, OwnedTags),
     CounterDelete(Id),
-    HistogramCreate(Id, String, OwnedTags),
+    /// Histogram id, name, tags and sample rate.
+    HistogramCreate(Id, String, OwnedTags, u32),
     HistogramDelete(Id),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MetricKind {
+    Counter,
+    Histogram,
+}
+
 #[derive(Debug)]
 enum UpdateEvent {
     CounterIncrement(Id, u64),
     HistogramRecord(Id, u64),
+    /// Values recorded into the histogram by a thread with local aggregation.
+    HistogramMerge(Id, Box<hdrhistogram::Histogram<u64>>),
+}
+
+struct Registration {
+    key: MetricKey,
+    /// Series of the metric before it was relabeled by the rules, only kept when the rules relabel metrics.
+    origin: Option<MetricKey>,
+    ref_count: usize,
+    /// Overflow series do not count towards the cardinality limits.
+    overflow: bool,
+    /// Sample rate of the first registration, always `1` for counters.
+    sample_rate: u32,
 }
 
 #[derive(Eq, PartialEq, Hash, Clone)]
@@ -0,0 +1,137 @@
+//! Per-thread pre-aggregation of the updates, see [`LocalAggregationConfig`].
+
+use crate::UpdateEvent;
+use crate::config::LocalAggregationConfig;
+use metricus::Id;
+use metricus::clock::Timestamp;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+thread_local! {
+    static BUFFER: RefCell<LocalBuffer> = RefCell::new(LocalBuffer::default());
+}
+
+/// Buffers left behind by the threads that exited before flushing, sent on the next flush of any thread.
+static ORPHANS: Mutex<Vec<LocalBuffer>> = Mutex::new(Vec::new());
+
+/// Histograms merged by the aggregator and reset, handed back to the threads so that they do not allocate a new
+/// histogram per id on every flush. They stay boxed as they are sent to the aggregator boxed.
+#[allow(clippy::vec_box)]
+static RECYCLED: Mutex<Vec<Box<hdrhistogram::Histogram<u64>>>> = Mutex::new(Vec::new());
+
+/// Maximum number of histograms kept for reuse.
+const MAX_RECYCLED: usize = 1024;
+
+/// Number of buffered updates between two checks of the flush deadline, so that the clock is not read on every update.
+const DEADLINE_CHECK_INTERVAL: usize = 64;
+
+/// Outcome of buffering an update.
+pub(crate) enum Buffered {
+    /// Update has been buffered.
+    Pending,
+    /// Update has been buffered and one of the limits has been hit, so the thread should flush.
+    Full,
+    /// Thread local buffer is not available (e.g. during thread shutdown), the update should be sent as is.
+    Unavailable,
+}
+
+#[derive(Default)]
+struct LocalBuffer {
+    counters: HashMap<Id, u64>,
+    histograms: HashMap<Id, Box<hdrhistogram::Histogram<u64>>>,
+    events: usize,
+    /// Time of the first update since the last flush.
+    started: Option<Timestamp>,
+}
+
+impl LocalBuffer {
+    #[inline]
+    fn updated(&mut self, config: &LocalAggregationConfig) -> Buffered {
+        self.events += 1;
+        let started = self.started.get_or_insert_with(Timestamp::now);
+        let check_deadline = self.events % DEADLINE_CHECK_INTERVAL == 0;
+        if self.events >= config.max_events
+            || (check_deadline && started.elapsed_ns() >= config.max_delay.as_nanos() as u64)
+        {
+            Buffered::Full
+        } else {
+            Buffered::Pending
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.events == 0
+    }
+
+    fn drain(&mut self, mut send: impl FnMut(UpdateEvent)) {
+        for (id, delta) in self.counters.drain() {
+            send(UpdateEvent::CounterIncrement(id, delta));
+        }
+        for (id, histogram) in self.histograms.drain() {
+            send(UpdateEvent::HistogramMerge(id, histogram));
+        }
+        self.events = 0;
+        self.started = None;
+    }
+}
+
+impl Drop for LocalBuffer {
+    fn drop(&mut self) {
+        if !self.is_empty() {
+            let mut orphans = ORPHANS.lock().unwrap_or_else(|e| e.into_inner());
+            orphans.push(std::mem::take(self));
+        }
+    }
+}
+
+/// Adds the increment to the counter total of the calling thread.
+#[inline]
+pub(crate) fn increment(id: Id, delta: u64, config: &LocalAggregationConfig) -> Buffered {
+    BUFFER
+        .try_with(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            *buffer.counters.entry(id).or_default() += delta;
+            buffer.updated(config)
+        })
+        .unwrap_or(Buffered::Unavailable)
+}
+
+/// Records the value into the histogram of the calling thread.
+#[inline]
+pub(crate) fn record(id: Id, value: u64, config: &LocalAggregationConfig) -> Buffered {
+    BUFFER
+        .try_with(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            let histogram = buffer.histograms.entry(id).or_insert_with(new_histogram);
+            // auto-resizing histogram accepts any value
+            let _ = histogram.record(value);
+            buffer.updated(config)
+        })
+        .unwrap_or(Buffered::Unavailable)
+}
+
+fn new_histogram() -> Box<hdrhistogram::Histogram<u64>> {
+    let recycled = RECYCLED.try_lock().ok().and_then(|mut recycled| recycled.pop());
+    recycled.unwrap_or_else(|| Box::new(hdrhistogram::Histogram::new(3).unwrap())) // will never fail
+}
+
+/// Hands the histogram back to the threads once it has been merged by the aggregator.
+pub(crate) fn recycle(mut histogram: Box<hdrhistogram::Histogram<u64>>) {
+    histogram.reset();
+    if let Ok(mut recycled) = RECYCLED.try_lock() {
+        if recycled.len() < MAX_RECYCLED {
+            recycled.push(histogram);
+        }
+    }
+}
+
+/// Sends the updates buffered by the calling thread, as well as the ones left behind by the threads that exited.
+pub(crate) fn flush(mut send: impl FnMut(UpdateEvent)) {
+    let _ = BUFFER.try_with(|buffer| buffer.borrow_mut().drain(&mut send));
+    if let Ok(mut orphans) = ORPHANS.try_lock() {
+        for mut orphan in orphans.drain(..) {
+            orphan.drain(&mut send);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! In-process access to the current metric values held by the aggregator.
+
+use crate::{Error, OwnedTags, Result, ToOwnedTags};
+use metricus::{Id, Tags};
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{Receiver, Sender, SyncSender};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+/// How long to wait for the aggregator to reply to the query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+static METRICS_QUERY: LazyLock<Mutex<Option<MetricsQuery>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Handle used to query the current metric values from the aggregator. The snapshot is taken on the
+/// aggregator thread, so it is consistent across all the metrics and does not affect the export cycle.
+///
+/// ## Examples
+///
+/// ```no_run
+/// use metricus_agent::MetricsAgent;
+///
+/// MetricsAgent::init().unwrap();
+///
+/// let query = MetricsAgent::query().unwrap();
+/// let snapshot = query.find("order_to_ack", &[("venue", "xnas")]).unwrap();
+/// for histogram in snapshot.histograms {
+///     println!("{} p99={}", histogram.name, histogram.stats.p99);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MetricsQuery {
+    tx: Sender<QueryRequest>,
+}
+
+impl MetricsQuery {
+    pub(crate) fn register(tx: Sender<QueryRequest>) {
+        *METRICS_QUERY.lock().unwrap() = Some(MetricsQuery { tx });
+    }
+
+    pub(crate) fn get() -> Result<MetricsQuery> {
+        METRICS_QUERY
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| Error::other("metrics agent has not been initialised"))
+    }
+
+    /// Takes a snapshot of all counters and histograms.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        self.execute(Selector::default())
+    }
+
+    /// Takes a snapshot of the counters and histograms with the given measurement name and containing all
+    /// the given tags.
+    pub fn find(&self, name: &str, tags: Tags) -> Result<Snapshot> {
+        self.execute(Selector {
+            name: Some(name.to_owned()),
+            tags: tags.to_owned_tags(),
+        })
+    }
+
+    fn execute(&self, selector: Selector) -> Result<Snapshot> {
+        let (tx_reply, rx_reply) = std::sync::mpsc::sync_channel(1);
+        self.tx
+            .send(QueryRequest { selector, tx_reply })
+            .map_err(|_| Error::other("metrics aggregator is not running"))?;
+        rx_reply.recv_timeout(QUERY_TIMEOUT).map_err(Error::other)
+    }
+}
+
+pub(crate) type QueryReceiver = Receiver<QueryRequest>;
+
+pub(crate) struct QueryRequest {
+    pub(crate) selector: Selector,
+    pub(crate) tx_reply: SyncSender<Snapshot>,
+}
+
+/// Selects metrics by measurement name and a subset of their tags.
+#[derive(Debug, Default)]
+pub(crate) struct Selector {
+    name: Option<String>,
+    tags: OwnedTags,
+}
+
+impl Selector {
+    pub(crate) fn matches(&self, name: &str, tags: &OwnedTags) -> bool {
+        self.name.as_ref().is_none_or(|expected| expected == name) && self.tags.iter().all(|tag| tags.contains(tag))
+    }
+}
+
+/// Point in time view of the metrics held by the aggregator.
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    /// Time at which the snapshot has been taken (nanoseconds since the unix epoch).
+    pub timestamp: u64,
+    pub counters: Vec<CounterSnapshot>,
+    pub histograms: Vec<HistogramSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CounterSnapshot {
+    pub id: Id,
+    pub name: String,
+    pub tags: OwnedTags,
+    pub value: u64,
+}
+
+/// Distribution of a histogram for one of its windows. The interval window (with no label) covers the values
+/// recorded since the last flush, whereas the other windows reflect their state as of the last flush.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramSnapshot {
+    pub id: Id,
+    pub name: String,
+    pub tags: OwnedTags,
+    pub window: Option<String>,
+    /// Only 1 in `sample_rate` values has been recorded.
+    pub sample_rate: u32,
+    pub stats: HistogramStats,
+}
+
+/// Summary statistics of the histogram.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct HistogramStats {
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub p50: u64,
+    pub p75: u64,
+    pub p90: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub p9999: u64,
+}
+
+impl From<&hdrhistogram::Histogram<u64>> for HistogramStats {
+    fn from(inner: &hdrhistogram::Histogram<u64>) -> Self {
+        Self {
+            count: inner.len(),
+            min: inner.min(),
+            max: inner.max(),
+            mean: inner.mean(),
+            p50: inner.value_at_quantile(0.50),
+            p75: inner.value_at_quantile(0.75),
+            p90: inner.value_at_quantile(0.90),
+            p95: inner.value_at_quantile(0.95),
+            p99: inner.value_at_quantile(0.99),
+            p999: inner.value_at_quantile(0.999),
+            p9999: inner.value_at_quantile(0.9999),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selector(name: Option<&str>, tags: Tags) -> Selector {
+        Selector {
+            name: name.map(str::to_owned),
+            tags: tags.to_owned_tags(),
+        }
+    }
+
+    #[test]
+    fn should_match_name_and_subset_of_tags() {
+        let tags = [("venue", "xnas"), ("side", "buy")].to_owned_tags();
+        assert!(selector(None, &[]).matches("orders", &tags));
+        assert!(selector(Some("orders"), &[]).matches("orders", &tags));
+        assert!(selector(Some("orders"), &[("side", "buy")]).matches("orders", &tags));
+        assert!(!selector(Some("fills"), &[]).matches("orders", &tags));
+        assert!(!selector(Some("orders"), &[("side", "sell")]).matches("orders", &tags));
+        assert!(!selector(None, &[("account", "1")]).matches("orders", &tags));
+    }
+
+    #[test]
+    fn should_fail_once_aggregator_has_stopped() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let query = MetricsQuery { tx };
+        drop(rx);
+        assert!(query.snapshot().is_err());
+    }
+
+    #[test]
+    fn should_time_out_when_aggregator_does_not_reply() {
+        let (tx, rx) = std::sync::mpsc::channel::<QueryRequest>();
+        let query = MetricsQuery { tx };
+        // the request is received but never answered
+        assert!(query.find("orders", &[]).is_err());
+        assert_eq!(Some("orders"), rx.try_recv().unwrap().selector.name.as_deref());
+    }
+}
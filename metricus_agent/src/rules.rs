@@ -0,0 +1,284 @@
+use crate::config::{HistogramConfig, HistogramMode, MatchRule, Pattern, RelabelAction, RulesConfig};
+use crate::{Error, OwnedTag, OwnedTags};
+use regex::Regex;
+use std::time::Duration;
+
+/// Compiled filtering and relabeling rules. These are applied once when the metric is registered,
+/// so they have no impact on the cost of updating the metric.
+#[derive(Debug, Default)]
+pub struct Rules {
+    allow: Vec<Matcher>,
+    deny: Vec<Matcher>,
+    relabel: Vec<(Matcher, RelabelAction)>,
+}
+
+impl TryFrom<RulesConfig> for Rules {
+    type Error = Error;
+
+    fn try_from(config: RulesConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            allow: config
+                .allow
+                .into_iter()
+                .map(Matcher::try_from)
+                .collect::<Result<_, _>>()?,
+            deny: config
+                .deny
+                .into_iter()
+                .map(Matcher::try_from)
+                .collect::<Result<_, _>>()?,
+            relabel: config
+                .relabel
+                .into_iter()
+                .map(|rule| Ok((Matcher::try_from(rule.matcher)?, rule.action)))
+                .collect::<Result<_, Error>>()?,
+        })
+    }
+}
+
+impl Rules {
+    /// Whether any of the rules relabel metrics, which may map different metrics onto the same series.
+    pub fn relabels(&self) -> bool {
+        !self.relabel.is_empty()
+    }
+
+    /// Returns the relabeled measurement name and tags, or `None` if the metric has been filtered out.
+    pub fn apply(&self, name: &str, tags: &[OwnedTag]) -> Option<(String, OwnedTags)> {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|matcher| matcher.matches(name, tags));
+        if !allowed || self.deny.iter().any(|matcher| matcher.matches(name, tags)) {
+            return None;
+        }
+
+        let mut name = name.to_owned();
+        let mut tags = tags.to_vec();
+        for (matcher, action) in self.relabel.iter() {
+            if !matcher.matches(&name, &tags) {
+                continue;
+            }
+            match action {
+                RelabelAction::Rename { to } => name = to.clone(),
+                RelabelAction::DropTag { key } => tags.retain(|tag| &tag.0 != key),
+                RelabelAction::AddTag { key, value } => {
+                    tags.retain(|tag| &tag.0 != key);
+                    tags.push((key.clone(), value.clone()));
+                }
+                RelabelAction::MapTagValue { key, values } => {
+                    for tag in tags.iter_mut().filter(|tag| &tag.0 == key) {
+                        if let Some(value) = values.get(&tag.1) {
+                            tag.1 = value.clone();
+                        }
+                    }
+                }
+            }
+        }
+        tags.sort();
+        tags.dedup();
+        Some((name, tags))
+    }
+}
+
+/// Compiled histogram mode rules.
+#[derive(Debug)]
+pub struct HistogramModes {
+    default_modes: Vec<HistogramMode>,
+    rules: Vec<(Matcher, Vec<HistogramMode>)>,
+    flush_interval: Duration,
+}
+
+impl HistogramModes {
+    pub fn new(config: HistogramConfig, flush_interval: Duration) -> Result<Self, Error> {
+        Ok(Self {
+            default_modes: config.default_modes,
+            rules: config
+                .rules
+                .into_iter()
+                .map(|rule| Ok((Matcher::try_from(rule.matcher)?, rule.modes)))
+                .collect::<Result<_, Error>>()?,
+            flush_interval,
+        })
+    }
+
+    /// Returns the modes of the first matching rule or the default modes.
+    pub fn get(&self, name: &str, tags: &[OwnedTag]) -> &[HistogramMode] {
+        self.rules
+            .iter()
+            .find(|(matcher, _)| matcher.matches(name, tags))
+            .map(|(_, modes)| modes.as_slice())
+            .unwrap_or(&self.default_modes)
+    }
+
+    pub const fn flush_interval(&self) -> Duration {
+        self.flush_interval
+    }
+}
+
+#[derive(Debug)]
+struct Matcher {
+    name: Option<Regex>,
+    tags: Vec<(String, Regex)>,
+}
+
+impl TryFrom<MatchRule> for Matcher {
+    type Error = Error;
+
+    fn try_from(rule: MatchRule) -> Result<Self, Self::Error> {
+        Ok(Self {
+            name: rule.name.map(compile).transpose()?,
+            tags: rule
+                .tags
+                .into_iter()
+                .map(|(key, pattern)| Ok((key, compile(pattern)?)))
+                .collect::<Result<_, Error>>()?,
+        })
+    }
+}
+
+impl Matcher {
+    fn matches(&self, name: &str, tags: &[OwnedTag]) -> bool {
+        self.name.as_ref().is_none_or(|regex| regex.is_match(name))
+            && self
+                .tags
+                .iter()
+                .all(|(key, regex)| tags.iter().any(|tag| &tag.0 == key && regex.is_match(&tag.1)))
+    }
+}
+
+fn compile(pattern: Pattern) -> Result<Regex, Error> {
+    let regex = match pattern {
+        Pattern::Glob { glob } => {
+            let mut regex = String::with_capacity(glob.len() + 2);
+            regex.push('^');
+            for c in glob.chars() {
+                match c {
+                    '*' => regex.push_str(".*"),
+                    '?' => regex.push('.'),
+                    c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+                }
+            }
+            regex.push('$');
+            regex
+        }
+        Pattern::Regex { regex } => format!("^(?:{regex})$"),
+    };
+    Regex::new(&regex).map_err(|e| Error::other(format!("invalid pattern: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(config: &str) -> Rules {
+        Rules::try_from(serde_yaml::from_str::<RulesConfig>(config).unwrap()).unwrap()
+    }
+
+    fn tags(tags: &[(&str, &str)]) -> OwnedTags {
+        tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn should_match_glob_and_regex_patterns() {
+        let rules = rules(
+            r"
+allow:
+    - name: { glob: 'orders.*' }
+    - name: { regex: 'fills|cancels' }
+      tags:
+          venue: { glob: 'x??s' }
+",
+        );
+        assert!(rules.apply("orders.sent", &[]).is_some());
+        // the glob must match the whole name, and its special characters are taken literally
+        assert!(rules.apply("orders", &[]).is_none());
+        assert!(rules.apply("orders_sent", &[]).is_none());
+        assert!(rules.apply("fills", &tags(&[("venue", "xnas")])).is_some());
+        // the regex must match the whole name
+        assert!(rules.apply("fills_total", &tags(&[("venue", "xnas")])).is_none());
+        // all the tag patterns must match
+        assert!(rules.apply("cancels", &tags(&[("venue", "arcx")])).is_none());
+        assert!(rules.apply("cancels", &[]).is_none());
+    }
+
+    #[test]
+    fn should_deny_over_allow() {
+        let rules = rules(
+            r"
+allow:
+    - name: { glob: 'orders*' }
+deny:
+    - tags:
+          venue: { glob: 'test*' }
+",
+        );
+        assert!(rules.apply("orders", &tags(&[("venue", "xnas")])).is_some());
+        assert!(rules.apply("orders", &tags(&[("venue", "test1")])).is_none());
+        assert!(rules.apply("fills", &tags(&[("venue", "xnas")])).is_none());
+
+        // everything is allowed without any allow rule
+        let rules = self::rules("deny: [{ name: { glob: 'debug_*' } }]");
+        assert!(rules.apply("orders", &[]).is_some());
+        assert!(rules.apply("debug_orders", &[]).is_none());
+    }
+
+    #[test]
+    fn should_relabel_in_order() {
+        let rules = rules(
+            r"
+relabel:
+    - match: { name: { glob: 'orders' } }
+      action: rename
+      to: order_count
+    - match: { name: { glob: 'order_count' } }
+      action: map_tag_value
+      key: venue
+      values: { xnas: nasdaq }
+    - action: drop_tag
+      key: host
+    - action: add_tag
+      key: env
+      value: prod
+",
+        );
+        assert!(rules.relabels());
+        let (name, relabeled) = rules
+            .apply("orders", &tags(&[("venue", "xnas"), ("host", "box-1"), ("env", "dev")]))
+            .unwrap();
+        assert_eq!("order_count", name);
+        // the tags are sorted so that the same series always has the same tags
+        assert_eq!(tags(&[("env", "prod"), ("venue", "nasdaq")]), relabeled);
+
+        let (name, relabeled) = rules.apply("fills", &tags(&[("venue", "xnas")])).unwrap();
+        assert_eq!("fills", name);
+        assert_eq!(tags(&[("env", "prod"), ("venue", "xnas")]), relabeled);
+    }
+
+    #[test]
+    fn should_select_histogram_modes_of_first_matching_rule() {
+        let config = serde_yaml::from_str::<HistogramConfig>(
+            r"
+default_modes: [{ type: interval }]
+rules:
+    - match: { name: { glob: 'order_*' } }
+      modes: [{ type: cumulative }]
+    - match: { tags: { venue: { glob: '*' } } }
+      modes: [{ type: window, length: 5m }]
+",
+        )
+        .unwrap();
+        let modes = HistogramModes::new(config, Duration::from_secs(1)).unwrap();
+
+        assert_eq!([HistogramMode::Cumulative], modes.get("order_to_ack", &tags(&[("venue", "xnas")])));
+        assert_eq!(
+            [HistogramMode::Window {
+                length: Duration::from_secs(300)
+            }],
+            modes.get("fill_latency", &tags(&[("venue", "xnas")]))
+        );
+        assert_eq!([HistogramMode::Interval], modes.get("fill_latency", &[]));
+    }
+
+    #[test]
+    fn should_reject_invalid_regex() {
+        let config = serde_yaml::from_str::<RulesConfig>("allow: [{ name: { regex: '(' } }]").unwrap();
+        assert!(Rules::try_from(config).is_err());
+    }
+}
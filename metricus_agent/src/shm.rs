@@ -0,0 +1,514 @@
+//! Metrics written into a memory-mapped file (under `/dev/shm` by default) rather than sent to an in-process
+//! aggregator, so that the aggregation and the exporter I/O run in a separate `metricus-sidecar` process.
+//!
+//! The segment starts with a header, followed by the counter and histogram registration directories and their
+//! value slots. Each directory entry holds the state of the entry and the name and tags of the metric. Counters
+//! have a cache line padded atomic value, and histograms an array of atomic log-linear buckets. Values are only
+//! ever added to by the writer, the sidecar computes the updates from the difference between two reads.
+//!
+//! The writer claims a free entry, fills it in and publishes it as active. Once the last handle to the metric is
+//! dropped, the entry is marked as deleted and the sidecar frees it after flushing its final value.
+//!
+//! ## Examples
+//!
+//! ```no_run
+//! use metricus_agent::config::SharedMemoryConfig;
+//! use metricus_agent::shm::SharedMemoryMetrics;
+//!
+//! SharedMemoryMetrics::init_with_config(&SharedMemoryConfig::default()).unwrap();
+//! ```
+
+use crate::config::SharedMemoryConfig;
+use crate::{Error, Result, SINK_ID};
+use log::warn;
+use memmap2::MmapMut;
+use metricus::{Id, MetricKind, Metrics, Tags, set_metrics};
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+const MAGIC: u64 = u64::from_le_bytes(*b"METRICUS");
+const VERSION: u32 = 1;
+
+const HEADER_SIZE: usize = 64;
+const ENTRY_SIZE: usize = 256;
+const DESCRIPTOR_SIZE: usize = ENTRY_SIZE - 16;
+const COUNTER_SLOT_SIZE: usize = 64;
+
+/// Values below `2^SUB_BUCKET_BITS` have their own bucket, larger values are split into `2^SUB_BUCKET_BITS`
+/// buckets per power of two, i.e. with a relative error of about 3%.
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+/// Number of buckets of each histogram.
+pub(crate) const BUCKETS: usize = SUB_BUCKETS * (64 - SUB_BUCKET_BITS as usize + 1);
+const HISTOGRAM_SLOT_SIZE: usize = BUCKETS * size_of::<u64>();
+
+/// Entry is available to the writer.
+pub(crate) const FREE: u32 = 0;
+/// Entry is being filled in by the writer.
+const CLAIMED: u32 = 1;
+/// Entry describes a live metric.
+pub(crate) const ACTIVE: u32 = 2;
+/// Metric has been deleted by the writer and the entry waits to be freed by the sidecar.
+pub(crate) const DELETED: u32 = 3;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    version: u32,
+    counters: u32,
+    histograms: u32,
+    buckets: u32,
+    /// Process writing into the segment.
+    owner: u32,
+    _reserved: [u8; HEADER_SIZE - 28],
+}
+
+#[repr(C)]
+pub(crate) struct Entry {
+    state: AtomicU32,
+    sample_rate: AtomicU32,
+    len: AtomicU32,
+    _reserved: u32,
+    /// Name followed by the `\0key\0value` of each tag.
+    descriptor: UnsafeCell<[u8; DESCRIPTOR_SIZE]>,
+}
+
+impl Entry {
+    #[inline]
+    pub(crate) fn state(&self) -> u32 {
+        self.state.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn sample_rate(&self) -> u32 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+
+    /// Name and tags of the metric, only valid once the entry is active.
+    pub(crate) fn decode(&self) -> Option<(String, Vec<(String, String)>)> {
+        let len = (self.len.load(Ordering::Relaxed) as usize).min(DESCRIPTOR_SIZE);
+        let descriptor = unsafe { &(&*self.descriptor.get())[..len] };
+        let descriptor = std::str::from_utf8(descriptor).ok()?;
+        let mut parts = descriptor.split('\0');
+        let name = parts.next()?.to_owned();
+        let mut tags = Vec::new();
+        while let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+            tags.push((key.to_owned(), value.to_owned()));
+        }
+        Some((name, tags))
+    }
+
+    /// Marks the entry of a deleted metric as free once the sidecar has flushed its final value.
+    pub(crate) fn free(&self) {
+        self.state.store(FREE, Ordering::Release);
+    }
+}
+
+/// Memory-mapped segment shared by the writer and the sidecar.
+pub(crate) struct Segment {
+    mmap: MmapMut,
+    counters: usize,
+    histograms: usize,
+}
+
+// the segment is only accessed through atomics, or through the descriptors guarded by the entry state
+unsafe impl Send for Segment {}
+
+impl Segment {
+    const fn size(counters: usize, histograms: usize) -> usize {
+        HEADER_SIZE
+            + (counters + histograms) * ENTRY_SIZE
+            + counters * COUNTER_SLOT_SIZE
+            + histograms * HISTOGRAM_SLOT_SIZE
+    }
+
+    /// Creates a new segment, replacing any existing file so that a sidecar still mapping the previous segment
+    /// notices the change. The segment of another process that is still running is never replaced.
+    fn create(config: &SharedMemoryConfig) -> Result<Self> {
+        if config.counters > u32::MAX as usize || config.histograms > u32::MAX as usize {
+            return Err(Error::other("shared memory capacity must fit in 32 bits"));
+        }
+        if let Some(owner) = Self::live_owner(&config.path) {
+            return Err(Error::other(format!(
+                "{} is in use by process {owner}, configure a different path",
+                config.path.display()
+            )));
+        }
+        match std::fs::remove_file(&config.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&config.path)?;
+        file.set_len(Self::size(config.counters, config.histograms) as u64)?;
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+        let header = Header {
+            magic: MAGIC,
+            version: VERSION,
+            counters: config.counters as u32,
+            histograms: config.histograms as u32,
+            buckets: BUCKETS as u32,
+            owner: std::process::id(),
+            _reserved: [0; HEADER_SIZE - 28],
+        };
+        unsafe { std::ptr::write(mmap.as_mut_ptr() as *mut Header, header) };
+        Ok(Self {
+            mmap,
+            counters: config.counters,
+            histograms: config.histograms,
+        })
+    }
+
+    /// Process that created the segment at the path, if it is still running and is not the current process.
+    fn live_owner(path: &Path) -> Option<u32> {
+        let (segment, _) = Self::open(path).ok()?;
+        let owner = segment.at::<Header>(0).owner;
+        let live = owner != 0 && owner != std::process::id() && Path::new("/proc").join(owner.to_string()).exists();
+        live.then_some(owner)
+    }
+
+    /// Maps an existing segment created by the writer.
+    pub(crate) fn open(path: &Path) -> Result<(Self, File)> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mmap = unsafe { MmapMut::map_mut(&file)? };
+        if mmap.len() < HEADER_SIZE {
+            return Err(Error::other(format!("{} is not a metricus segment", path.display())));
+        }
+        let header = unsafe { &*(mmap.as_ptr() as *const Header) };
+        if header.magic != MAGIC || header.version != VERSION || header.buckets as usize != BUCKETS {
+            return Err(Error::other(format!("{} is not a metricus segment", path.display())));
+        }
+        let (counters, histograms) = (header.counters as usize, header.histograms as usize);
+        if mmap.len() < Self::size(counters, histograms) {
+            return Err(Error::other(format!("{} is truncated", path.display())));
+        }
+        Ok((
+            Self {
+                mmap,
+                counters,
+                histograms,
+            },
+            file,
+        ))
+    }
+
+    pub(crate) const fn counters(&self) -> usize {
+        self.counters
+    }
+
+    pub(crate) const fn histograms(&self) -> usize {
+        self.histograms
+    }
+
+    #[inline]
+    fn at<T>(&self, offset: usize) -> &T {
+        unsafe { &*(self.mmap.as_ptr().add(offset) as *const T) }
+    }
+
+    pub(crate) fn counter_entry(&self, index: usize) -> &Entry {
+        self.at(HEADER_SIZE + index * ENTRY_SIZE)
+    }
+
+    pub(crate) fn histogram_entry(&self, index: usize) -> &Entry {
+        self.at(HEADER_SIZE + (self.counters + index) * ENTRY_SIZE)
+    }
+
+    #[inline]
+    pub(crate) fn counter(&self, index: usize) -> &AtomicU64 {
+        let offset = HEADER_SIZE + (self.counters + self.histograms) * ENTRY_SIZE;
+        self.at(offset + index * COUNTER_SLOT_SIZE)
+    }
+
+    #[inline]
+    pub(crate) fn buckets(&self, index: usize) -> &[AtomicU64; BUCKETS] {
+        let offset = HEADER_SIZE + (self.counters + self.histograms) * ENTRY_SIZE + self.counters * COUNTER_SLOT_SIZE;
+        self.at(offset + index * HISTOGRAM_SLOT_SIZE)
+    }
+
+    /// Resets the values of the entry so that it can be reused.
+    pub(crate) fn reset(&self, kind: MetricKind, index: usize) {
+        match kind {
+            MetricKind::Counter => self.counter(index).store(0, Ordering::Relaxed),
+            MetricKind::Histogram => self
+                .buckets(index)
+                .iter()
+                .for_each(|bucket| bucket.store(0, Ordering::Relaxed)),
+        }
+    }
+
+    /// Claims a free entry, fills it in and publishes it. Returns the index of the entry.
+    fn claim(&self, kind: MetricKind, descriptor: &[u8], sample_rate: u32) -> Option<usize> {
+        let (capacity, entry): (usize, fn(&Self, usize) -> &Entry) = match kind {
+            MetricKind::Counter => (self.counters, Self::counter_entry),
+            MetricKind::Histogram => (self.histograms, Self::histogram_entry),
+        };
+        let index = (0..capacity).find(|index| {
+            entry(self, *index)
+                .state
+                .compare_exchange(FREE, CLAIMED, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        })?;
+        self.reset(kind, index);
+        let entry = entry(self, index);
+        unsafe { (&mut *entry.descriptor.get())[..descriptor.len()].copy_from_slice(descriptor) };
+        entry.len.store(descriptor.len() as u32, Ordering::Relaxed);
+        entry.sample_rate.store(sample_rate, Ordering::Relaxed);
+        entry.state.store(ACTIVE, Ordering::Release);
+        Some(index)
+    }
+}
+
+/// Bucket of the value.
+#[inline]
+pub(crate) const fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let shift = 63 - value.leading_zeros() - SUB_BUCKET_BITS;
+    SUB_BUCKETS + shift as usize * SUB_BUCKETS + (value >> shift) as usize - SUB_BUCKETS
+}
+
+/// Value in the middle of the bucket.
+pub(crate) const fn bucket_value(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let shift = (index - SUB_BUCKETS) / SUB_BUCKETS;
+    let sub_bucket = (index - SUB_BUCKETS) % SUB_BUCKETS;
+    let lowest = ((SUB_BUCKETS + sub_bucket) as u64) << shift;
+    lowest + ((1 << shift) - 1) / 2
+}
+
+/// Backend writing into the shared memory segment. Counters with the same name and tags share the same entry.
+/// Metrics are rejected once their directory is full, or if their name and tags do not fit into an entry.
+/// Pre-allocated metrics are not supported.
+pub struct SharedMemoryMetrics {
+    segment: Segment,
+    registrations: HashMap<(MetricKind, Vec<u8>), Id>,
+    /// Key and number of handles by id.
+    handles: HashMap<Id, ((MetricKind, Vec<u8>), usize)>,
+}
+
+impl SharedMemoryMetrics {
+    /// Creates the segment and installs the backend.
+    pub fn init_with_config(config: &SharedMemoryConfig) -> Result<()> {
+        set_metrics(Self::create(config)?);
+        Ok(())
+    }
+
+    /// Creates the segment, replacing any existing file at the same path unless another running process writes into it.
+    pub fn create(config: &SharedMemoryConfig) -> Result<Self> {
+        Ok(Self {
+            segment: Segment::create(config)?,
+            registrations: HashMap::new(),
+            handles: HashMap::new(),
+        })
+    }
+
+    fn register(&mut self, kind: MetricKind, name: &str, tags: Tags, sample_rate: u32) -> Id {
+        let descriptor = encode_descriptor(name, tags);
+        let key = (kind, descriptor);
+        if let Some(id) = self.registrations.get(&key) {
+            if let Some((_, handles)) = self.handles.get_mut(id) {
+                *handles += 1;
+            }
+            return *id;
+        }
+        if key.1.len() > DESCRIPTOR_SIZE {
+            warn!("metric '{name}' rejected as its name and tags exceed {DESCRIPTOR_SIZE} bytes");
+            return SINK_ID;
+        }
+        let Some(index) = self.segment.claim(kind, &key.1, sample_rate) else {
+            warn!("metric '{name}' rejected as the shared memory {kind:?} directory is full");
+            return SINK_ID;
+        };
+        let id = match kind {
+            MetricKind::Counter => index as Id,
+            MetricKind::Histogram => (self.segment.counters + index) as Id,
+        };
+        self.registrations.insert(key.clone(), id);
+        self.handles.insert(id, (key, 1));
+        id
+    }
+
+    fn unregister(&mut self, id: Id) {
+        let Some((_, handles)) = self.handles.get_mut(&id) else {
+            return;
+        };
+        *handles -= 1;
+        if *handles == 0 {
+            if let Some((key, _)) = self.handles.remove(&id) {
+                self.registrations.remove(&key);
+                let entry = match key.0 {
+                    MetricKind::Counter => self.segment.counter_entry(id as usize),
+                    MetricKind::Histogram => self.segment.histogram_entry(id as usize - self.segment.counters),
+                };
+                entry.state.store(DELETED, Ordering::Release);
+            }
+        }
+    }
+}
+
+fn encode_descriptor(name: &str, tags: Tags) -> Vec<u8> {
+    let mut descriptor = name.as_bytes().to_vec();
+    for (key, value) in tags {
+        descriptor.push(0);
+        descriptor.extend_from_slice(key.as_bytes());
+        descriptor.push(0);
+        descriptor.extend_from_slice(value.as_bytes());
+    }
+    descriptor
+}
+
+impl Metrics for SharedMemoryMetrics {
+    fn name(&self) -> &'static str {
+        "shared-memory"
+    }
+
+    fn new_counter(&mut self, name: &str, tags: Tags) -> Id {
+        self.register(MetricKind::Counter, name, tags, 1)
+    }
+
+    fn delete_counter(&mut self, id: Id) {
+        self.unregister(id)
+    }
+
+    #[inline]
+    fn increment_counter_by(&mut self, id: Id, delta: u64) {
+        if (id as usize) < self.segment.counters {
+            self.segment.counter(id as usize).fetch_add(delta, Ordering::Relaxed);
+        }
+    }
+
+    fn new_histogram(&mut self, name: &str, tags: Tags) -> Id {
+        self.register(MetricKind::Histogram, name, tags, 1)
+    }
+
+    fn new_histogram_sampled(&mut self, name: &str, tags: Tags, sample_rate: u32) -> Id {
+        self.register(MetricKind::Histogram, name, tags, sample_rate)
+    }
+
+    fn delete_histogram(&mut self, id: Id) {
+        self.unregister(id)
+    }
+
+    #[inline]
+    fn record(&mut self, id: Id, value: u64) {
+        if let Some(index) = (id as usize).checked_sub(self.segment.counters) {
+            if index < self.segment.histograms {
+                self.segment.buckets(index)[bucket_index(value)].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Segment path unique to the test, removed when dropped.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("metricus-{name}-{}", std::process::id())))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn config(path: &TempPath) -> SharedMemoryConfig {
+        SharedMemoryConfig {
+            path: path.0.clone(),
+            counters: 2,
+            histograms: 1,
+            ..SharedMemoryConfig::default()
+        }
+    }
+
+    #[test]
+    fn should_map_values_to_buckets() {
+        assert_eq!(0, bucket_index(0));
+        assert_eq!(31, bucket_index(31));
+        assert_eq!(32, bucket_index(32));
+        assert_eq!(BUCKETS - 1, bucket_index(u64::MAX));
+
+        assert_eq!(0, bucket_value(0));
+        assert_eq!(31, bucket_value(31));
+        assert_eq!(32, bucket_value(32));
+        let max = bucket_value(BUCKETS - 1);
+        assert!((63 << 58..u64::MAX).contains(&max), "{max}");
+        assert_eq!(BUCKETS - 1, bucket_index(max));
+    }
+
+    #[test]
+    fn should_bound_bucket_error() {
+        for value in [33, 100, 1_000, 123_456, 1 << 40, u64::MAX / 3] {
+            let estimate = bucket_value(bucket_index(value));
+            assert!(value.abs_diff(estimate) as f64 / value as f64 <= 1.0 / SUB_BUCKETS as f64, "{value}");
+        }
+    }
+
+    #[test]
+    fn should_claim_delete_and_free_entries() {
+        let path = TempPath::new("protocol");
+        let mut metrics = SharedMemoryMetrics::create(&config(&path)).unwrap();
+        let (segment, _) = Segment::open(&path.0).unwrap();
+
+        let first = metrics.new_counter("first", &[("key", "value")]);
+        let second = metrics.new_counter("second", &[]);
+        assert_eq!(first, metrics.new_counter("first", &[("key", "value")]));
+        assert_eq!(SINK_ID, metrics.new_counter("third", &[]));
+        assert_eq!(ACTIVE, segment.counter_entry(first as usize).state());
+        assert_eq!(
+            Some(("first".to_owned(), vec![("key".to_owned(), "value".to_owned())])),
+            segment.counter_entry(first as usize).decode()
+        );
+        metrics.increment_counter_by(first, 5);
+        assert_eq!(5, segment.counter(first as usize).load(Ordering::Relaxed));
+
+        // the entry is only deleted with its last handle
+        metrics.delete_counter(first);
+        assert_eq!(ACTIVE, segment.counter_entry(first as usize).state());
+        metrics.delete_counter(first);
+        assert_eq!(DELETED, segment.counter_entry(first as usize).state());
+        assert_eq!(SINK_ID, metrics.new_counter("third", &[]));
+
+        // the entry is reused with a reset value once freed by the sidecar
+        segment.reset(MetricKind::Counter, first as usize);
+        segment.counter_entry(first as usize).free();
+        let third = metrics.new_counter("third", &[]);
+        assert_eq!(first, third);
+        assert_eq!(0, segment.counter(third as usize).load(Ordering::Relaxed));
+        assert_eq!(Some(("third".to_owned(), vec![])), segment.counter_entry(third as usize).decode());
+        assert_eq!(ACTIVE, segment.counter_entry(second as usize).state());
+
+        let histogram = metrics.new_histogram_sampled("histogram", &[], 10);
+        assert_eq!(10, segment.histogram_entry(0).sample_rate());
+        metrics.record(histogram, 100);
+        assert_eq!(1, segment.buckets(0)[bucket_index(100)].load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn should_not_replace_segment_of_live_process() {
+        let path = TempPath::new("owner");
+        SharedMemoryMetrics::create(&config(&path)).unwrap();
+        // the segment of the current process can be replaced
+        SharedMemoryMetrics::create(&config(&path)).unwrap();
+
+        // pid 1 is always running
+        let (mut segment, _) = Segment::open(&path.0).unwrap();
+        unsafe { (*(segment.mmap.as_mut_ptr() as *mut Header)).owner = 1 };
+        assert!(SharedMemoryMetrics::create(&config(&path)).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Reads the metrics written into the shared memory segment by [`SharedMemoryMetrics`](crate::shm::SharedMemoryMetrics)
+//! and feeds them into an agent, which aggregates and exports them as configured.
+//!
+//! ## Examples
+//!
+//! ```no_run
+//! use metricus_agent::config::MetricsConfig;
+//! use metricus_agent::sidecar::Sidecar;
+//!
+//! Sidecar::new(MetricsConfig::from_file("config.yml").unwrap()).unwrap().run().unwrap();
+//! ```
+
+use crate::config::{MetricsConfig, SharedMemoryConfig};
+use crate::shm::{ACTIVE, BUCKETS, DELETED, Entry, Segment, bucket_value};
+use crate::{MetricsAgent, Result};
+use log::{info, warn};
+use metricus::{Id, MetricKind, Metrics};
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::sync::atomic::Ordering;
+
+/// Metric registered with the agent and the last value read from its entry.
+struct Tracked<T> {
+    id: Id,
+    last: T,
+}
+
+pub struct Sidecar {
+    config: SharedMemoryConfig,
+    agent: MetricsAgent,
+    /// Segment currently mapped along with the inode of its file, used to detect that the writer has restarted.
+    segment: Option<(Segment, u64)>,
+    counters: HashMap<usize, Tracked<u64>>,
+    histograms: HashMap<usize, Tracked<Box<[u64; BUCKETS]>>>,
+}
+
+impl Sidecar {
+    /// Starts the agent which aggregates and exports the metrics read from the segment.
+    pub fn new(config: MetricsConfig) -> Result<Self> {
+        Ok(Self {
+            config: config.shared_memory.clone(),
+            agent: MetricsAgent::start_with_config(config)?,
+            segment: None,
+            counters: HashMap::new(),
+            histograms: HashMap::new(),
+        })
+    }
+
+    /// Polls the segment at the configured interval, forever.
+    pub fn run(mut self) -> Result<()> {
+        loop {
+            self.poll()?;
+            std::thread::sleep(self.config.poll_interval);
+        }
+    }
+
+    /// Reads the segment once, mapping it first if needed.
+    pub fn poll(&mut self) -> Result<()> {
+        let inode = match std::fs::metadata(&self.config.path) {
+            Ok(metadata) => Some(metadata.ino()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if self.segment.as_ref().map(|(_, inode)| *inode) != inode {
+            self.remap(inode)?;
+        }
+        if let Some((segment, inode)) = self.segment.take() {
+            self.read_counters(&segment);
+            self.read_histograms(&segment);
+            self.segment = Some((segment, inode));
+        }
+        Ok(())
+    }
+
+    /// Deletes the metrics of the previous segment and maps the new one, if any.
+    fn remap(&mut self, inode: Option<u64>) -> Result<()> {
+        if let Some((segment, _)) = self.segment.take() {
+            // flush the final values before the metrics are deleted
+            self.read_counters(&segment);
+            self.read_histograms(&segment);
+        }
+        for (_, counter) in self.counters.drain() {
+            self.agent.delete_counter(counter.id);
+        }
+        for (_, histogram) in self.histograms.drain() {
+            self.agent.delete_histogram(histogram.id);
+        }
+        if inode.is_some() {
+            match Segment::open(&self.config.path) {
+                Ok((segment, file)) => {
+                    info!("mapped metrics segment {}", self.config.path.display());
+                    self.segment = Some((segment, file.metadata()?.ino()));
+                }
+                // the writer might still be creating the segment
+                Err(e) => warn!("unable to map metrics segment: {e}"),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_counters(&mut self, segment: &Segment) {
+        for index in 0..segment.counters() {
+            let entry = segment.counter_entry(index);
+            let state = entry.state();
+            if state != ACTIVE && state != DELETED {
+                continue;
+            }
+            if !self.counters.contains_key(&index) {
+                if let Some((name, tags)) = entry.decode() {
+                    let tags = tags.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect::<Vec<_>>();
+                    let id = self.agent.new_counter(&name, &tags);
+                    self.counters.insert(index, Tracked { id, last: 0 });
+                }
+            }
+            if let Some(counter) = self.counters.get_mut(&index) {
+                let value = segment.counter(index).load(Ordering::Relaxed);
+                if value > counter.last {
+                    self.agent.increment_counter_by(counter.id, value - counter.last);
+                    counter.last = value;
+                }
+            }
+            if state == DELETED {
+                if let Some(counter) = self.counters.remove(&index) {
+                    self.agent.delete_counter(counter.id);
+                }
+                free(segment, entry, MetricKind::Counter, index);
+            }
+        }
+    }
+
+    fn read_histograms(&mut self, segment: &Segment) {
+        for index in 0..segment.histograms() {
+            let entry = segment.histogram_entry(index);
+            let state = entry.state();
+            if state != ACTIVE && state != DELETED {
+                continue;
+            }
+            if !self.histograms.contains_key(&index) {
+                if let Some((name, tags)) = entry.decode() {
+                    let tags = tags.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect::<Vec<_>>();
+                    let id = self
+                        .agent
+                        .new_histogram_sampled(&name, &tags, entry.sample_rate().max(1));
+                    let last = Box::new([0; BUCKETS]);
+                    self.histograms.insert(index, Tracked { id, last });
+                }
+            }
+            if let Some(histogram) = self.histograms.get_mut(&index) {
+                let mut values = hdrhistogram::Histogram::<u64>::new(3).unwrap(); // will never fail
+                for (bucket, (value, last)) in segment.buckets(index).iter().zip(histogram.last.iter_mut()).enumerate()
+                {
+                    let value = value.load(Ordering::Relaxed);
+                    if value > *last {
+                        // auto-resizing histogram accepts any value
+                        let _ = values.record_n(bucket_value(bucket), value - *last);
+                        *last = value;
+                    }
+                }
+                if !values.is_empty() {
+                    self.agent.merge_histogram(histogram.id, values);
+                }
+            }
+            if state == DELETED {
+                if let Some(histogram) = self.histograms.remove(&index) {
+                    self.agent.delete_histogram(histogram.id);
+                }
+                free(segment, entry, MetricKind::Histogram, index);
+            }
+        }
+    }
+}
+
+/// Frees the entry of a deleted metric so that the writer can reuse it.
+fn free(segment: &Segment, entry: &Entry, kind: MetricKind, index: usize) {
+    segment.reset(kind, index);
+    entry.free();
+}
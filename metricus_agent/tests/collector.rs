@@ -0,0 +1,88 @@
+mod common;
+
+use common::wait_for;
+use metricus_agent::collector::Collector;
+use metricus_agent::config::{CollectorConfig, MetricsConfig};
+use metricus_agent::query::Snapshot;
+use std::io::Write;
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Socket path unique to the test, removed when dropped.
+struct TempPath(PathBuf);
+
+impl TempPath {
+    fn new(name: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("metricus-{name}-{}", std::process::id())))
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn sum(snapshot: &Snapshot) -> u64 {
+    snapshot.counters.iter().map(|counter| counter.value).sum()
+}
+
+/// Waits for the merged counters of all the senders to reach the expected total.
+fn total(expected: u64) -> u64 {
+    sum(&wait_for("requests", |snapshot| sum(snapshot) == expected))
+}
+
+fn line(sender: &str, value: u64) -> String {
+    format!("requests,sender={sender},type=counter value={value}u 0\n")
+}
+
+#[test]
+fn should_merge_counter_totals_across_reconnects_and_idle_senders() {
+    let stream_path = TempPath::new("collector-stream");
+    let datagram_path = TempPath::new("collector-dgram");
+    let sender_path = TempPath::new("collector-sender");
+    let config = MetricsConfig {
+        // the datagram senders are detached after ten flush intervals without any metric
+        flush_interval: Duration::from_millis(20),
+        collector: CollectorConfig {
+            unix_stream: Some(stream_path.0.clone()),
+            unix_datagram: Some(datagram_path.0.clone()),
+            ..CollectorConfig::default()
+        },
+        ..MetricsConfig::default()
+    };
+    let collector = Collector::new(config).unwrap();
+    std::thread::spawn(move || collector.run());
+    let datagram = UnixDatagram::bind(&sender_path.0).unwrap();
+    datagram.connect(&datagram_path.0).unwrap();
+
+    let mut stream = UnixStream::connect(&stream_path.0).unwrap();
+    stream.write_all(line("stream", 10).as_bytes()).unwrap();
+    assert_eq!(10, total(10));
+    datagram.send(line("datagram", 5).as_bytes()).unwrap();
+    assert_eq!(15, total(15));
+    stream.write_all(line("stream", 15).as_bytes()).unwrap();
+    assert_eq!(20, total(20));
+    drop(stream);
+
+    // a failed connection must neither stop the listener nor count anything
+    let mut invalid = UnixStream::connect(&stream_path.0).unwrap();
+    invalid.write_all(b"\xff\xfe\n").unwrap();
+    drop(invalid);
+
+    // the collector notices the closed connection asynchronously
+    std::thread::sleep(Duration::from_millis(50));
+    // the reconnected sender carries on from its last total
+    let mut stream = UnixStream::connect(&stream_path.0).unwrap();
+    stream.write_all(line("stream", 18).as_bytes()).unwrap();
+    assert_eq!(23, total(23));
+
+    // an idle datagram sender carries on from its last total too, once detached
+    std::thread::sleep(Duration::from_millis(500));
+    datagram.send(line("datagram", 8).as_bytes()).unwrap();
+    assert_eq!(26, total(26));
+    // the total going backwards means that the sender has restarted
+    datagram.send(line("datagram", 2).as_bytes()).unwrap();
+    assert_eq!(28, total(28));
+}
@@ -0,0 +1,18 @@
+//! Helpers shared by the integration tests.
+
+use metricus_agent::MetricsAgent;
+use metricus_agent::query::Snapshot;
+use std::time::{Duration, Instant};
+
+/// Queries the aggregator until the snapshot satisfies the predicate, as the updates are processed asynchronously.
+pub fn wait_for(name: &str, predicate: impl Fn(&Snapshot) -> bool) -> Snapshot {
+    let query = MetricsAgent::query().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let snapshot = query.find(name, &[]).unwrap();
+        if predicate(&snapshot) || Instant::now() > deadline {
+            return snapshot;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::wait_for;
+use metricus::Metrics;
+use metricus_agent::MetricsAgent;
+use metricus_agent::config::{ArenaFallback, CounterArenaConfig, MetricsConfig};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+fn counter_value(name: &str, expected: u64) -> Option<u64> {
+    let snapshot = wait_for(name, |snapshot| {
+        snapshot
+            .counters
+            .first()
+            .is_some_and(|counter| counter.value == expected)
+    });
+    snapshot.counters.first().map(|counter| counter.value)
+}
+
+fn start(when_full: ArenaFallback, flush_interval: Duration) -> MetricsAgent {
+    let config = MetricsConfig {
+        flush_interval,
+        counter_arena: Some(CounterArenaConfig { capacity: 1, when_full }),
+        ..MetricsConfig::default()
+    };
+    MetricsAgent::start_with_config(config).unwrap()
+}
+
+// the agents share the global query handle, so the scenarios run one after the other
+#[test]
+fn should_fall_back_when_arena_is_full_and_reuse_released_slots() {
+    // the counters that do not fit in the arena are sent through the channel
+    let mut agent = start(ArenaFallback::Channel, Duration::from_secs(3600));
+    let first = agent.new_counter("channel_first", &[]);
+    let second = agent.new_counter("channel_second", &[]);
+    assert!(agent.counter_slot(first).is_some());
+    assert!(agent.counter_slot(second).is_none());
+    agent.increment_counter_by(first, 3);
+    agent.counter_slot(first).unwrap().fetch_add(2, Ordering::Relaxed);
+    agent.increment_counter_by(second, 7);
+    assert_eq!(Some(5), counter_value("channel_first", 5));
+    assert_eq!(Some(7), counter_value("channel_second", 7));
+
+    // the counters that do not fit in the arena are dropped
+    let mut agent = start(ArenaFallback::Reject, Duration::from_millis(20));
+    let first = agent.new_counter("reject_first", &[]);
+    let second = agent.new_counter("reject_second", &[]);
+    assert!(agent.counter_slot(first).is_some());
+    assert!(agent.counter_slot(second).is_none());
+    agent.increment_counter_by(first, 3);
+    agent.increment_counter_by(second, 7);
+    assert_eq!(Some(3), counter_value("reject_first", 3));
+    // the rejected counter is never registered with the aggregator
+    let snapshot = MetricsAgent::query().unwrap().find("reject_second", &[]).unwrap();
+    assert!(snapshot.counters.is_empty());
+
+    // the slot of a deleted counter is handed out again once its final value has been flushed
+    agent.delete_counter(first);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let third = loop {
+        let id = agent.new_counter("reject_third", &[]);
+        if agent.counter_slot(id).is_some() || Instant::now() > deadline {
+            break id;
+        }
+        agent.delete_counter(id);
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(first, third);
+    agent.increment_counter_by(third, 4);
+    assert_eq!(Some(4), counter_value("reject_third", 4));
+}
@@ -0,0 +1,130 @@
+use metricus_agent::config::{Encoder, Format};
+use metricus_agent::decoder::{CounterPoint, HistogramPoint, Point, decode_json, decode_line_protocol};
+use metricus_agent::query::HistogramStats;
+use proptest::prelude::*;
+
+/// Any printable text, including the characters escaped by the line protocol. Newlines cannot be encoded.
+fn text() -> impl Strategy<Value = String> {
+    "[^\n\r]{1,16}"
+}
+
+fn tags() -> impl Strategy<Value = Vec<(String, String)>> {
+    // a trailing `window` tag would be decoded as the histogram window
+    prop::collection::vec((text(), text()), 0..4)
+        .prop_filter("window tag", |tags| tags.last().is_none_or(|(key, _)| key != "window"))
+}
+
+fn stats() -> impl Strategy<Value = HistogramStats> {
+    (any::<[u64; 10]>(), any::<f64>().prop_filter("finite", |mean| mean.is_finite())).prop_map(|(values, mean)| {
+        HistogramStats {
+            count: values[0],
+            min: values[1],
+            max: values[2],
+            mean,
+            p50: values[3],
+            p75: values[4],
+            p90: values[5],
+            p95: values[6],
+            p99: values[7],
+            p999: values[8],
+            p9999: values[9],
+        }
+    })
+}
+
+fn counter() -> impl Strategy<Value = Point> {
+    (text(), tags(), any::<u64>(), any::<u64>()).prop_map(|(name, tags, value, timestamp)| {
+        Point::Counter(CounterPoint {
+            name,
+            tags,
+            value,
+            timestamp,
+        })
+    })
+}
+
+fn histogram() -> impl Strategy<Value = Point> {
+    (text(), tags(), prop::option::of(text()), 1..=u32::MAX, stats(), any::<u64>()).prop_map(
+        |(name, tags, window, sample_rate, stats, timestamp)| {
+            Point::Histogram(HistogramPoint {
+                name,
+                tags,
+                window,
+                sample_rate,
+                stats,
+                timestamp,
+            })
+        },
+    )
+}
+
+fn encode(encoder: Encoder, point: &Point) -> String {
+    let mut encoded = Vec::new();
+    encoder.encode_point(point, &mut encoded).unwrap();
+    String::from_utf8(encoded).unwrap()
+}
+
+proptest! {
+    #[test]
+    fn should_decode_line_protocol(point in prop_oneof![counter(), histogram()]) {
+        let encoded = encode(Encoder::LineProtocol, &point);
+        prop_assert_eq!(1, encoded.lines().count());
+        prop_assert_eq!(&point, &decode_line_protocol(&encoded).unwrap());
+        prop_assert_eq!(&point, &Format::LineProtocol.decode(&encoded).unwrap());
+    }
+
+    #[test]
+    fn should_decode_json(point in prop_oneof![counter(), histogram()]) {
+        let encoded = encode(Encoder::Json, &point);
+        prop_assert_eq!(1, encoded.lines().count());
+        prop_assert_eq!(&point, &decode_json(&encoded).unwrap());
+        prop_assert_eq!(&point, &Format::Json.decode(&encoded).unwrap());
+    }
+}
+
+#[test]
+fn should_unescape_line_protocol() {
+    let point = decode_line_protocol(r"my\ measure\,ment\\,k\=1=v\,1\ a\b\\,type=counter value=1u 2").unwrap();
+    assert_eq!(
+        Point::Counter(CounterPoint {
+            name: r"my measure,ment\".to_owned(),
+            tags: vec![
+                ("k=1".to_owned(), r"v,1 a\b\".to_owned()),
+                ("type".to_owned(), "counter".to_owned())
+            ],
+            value: 1,
+            timestamp: 2,
+        }),
+        point
+    );
+}
+
+#[test]
+fn should_decode_integers_without_suffix() {
+    let Point::Histogram(histogram) = decode_line_protocol(
+        "latency,type=histogram,window=5m count=3,min=1u,max=3i,mean=2.0,p50=2,p75=3,p90=3,p95=3,p99=3,p999=3,\
+         p9999=3,sample_rate=0.01 1700000000000000000",
+    )
+    .unwrap() else {
+        panic!("expected histogram");
+    };
+    assert_eq!(Some("5m"), histogram.window.as_deref());
+    assert_eq!(100, histogram.sample_rate);
+    assert_eq!(3, histogram.stats.count);
+    assert_eq!(3, histogram.stats.max);
+}
+
+#[test]
+fn should_reject_malformed_lines() {
+    assert!(decode_line_protocol("counters value=1u").is_err());
+    assert!(decode_line_protocol("counters value=-1 1").is_err());
+    assert!(decode_line_protocol("counters,tag value=1u 1").is_err());
+    assert!(decode_line_protocol("latency count=1u 1").is_err());
+    assert!(decode_json(r#"{"name":"counters","tags":[],"timestamp":1}"#).is_err());
+    // the lines are decoded with the configured format only
+    assert!(
+        Format::LineProtocol
+            .decode(r#"{"name":"counters","tags":[],"timestamp":1,"value":1}"#)
+            .is_err()
+    );
+}
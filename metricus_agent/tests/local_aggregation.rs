@@ -0,0 +1,43 @@
+mod common;
+
+use common::wait_for;
+use metricus::Metrics;
+use metricus_agent::MetricsAgent;
+use metricus_agent::config::{LocalAggregationConfig, MetricsConfig};
+use std::time::Duration;
+
+#[test]
+fn should_match_unbuffered_totals_with_local_aggregation() {
+    let config = MetricsConfig {
+        // the interval window must not be reset while the test is running
+        flush_interval: Duration::from_secs(3600),
+        local_aggregation: Some(LocalAggregationConfig {
+            max_events: 100,
+            max_delay: Duration::from_secs(3600),
+        }),
+        ..MetricsConfig::default()
+    };
+    let mut agent = MetricsAgent::start_with_config(config).unwrap();
+
+    let counter = agent.new_counter("local_counter", &[]);
+    let histogram = agent.new_histogram("local_histogram", &[]);
+    let mut total = 0;
+    for value in 1..=10_050 {
+        agent.increment_counter_by(counter, value % 7);
+        agent.record(histogram, value);
+        total += value % 7;
+    }
+    // the last updates are still buffered until the thread flushes
+    agent.flush();
+
+    let snapshot =
+        wait_for("local_counter", |snapshot| snapshot.counters.first().is_some_and(|counter| counter.value == total));
+    assert_eq!(total, snapshot.counters[0].value);
+    let snapshot = wait_for("local_histogram", |snapshot| {
+        snapshot
+            .histograms
+            .first()
+            .is_some_and(|histogram| histogram.stats.count == 10_050)
+    });
+    assert_eq!(10_050, snapshot.histograms[0].stats.count);
+}
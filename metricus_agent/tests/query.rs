@@ -0,0 +1,74 @@
+use metricus::{Counter, CounterOps, Histogram, HistogramOps};
+use metricus_agent::MetricsAgent;
+use metricus_agent::config::MetricsConfig;
+use metricus_agent::query::Snapshot;
+use std::time::{Duration, Instant};
+
+fn names(snapshot: &Snapshot) -> Vec<&str> {
+    let mut names = snapshot
+        .counters
+        .iter()
+        .map(|counter| counter.name.as_str())
+        .chain(snapshot.histograms.iter().map(|histogram| histogram.name.as_str()))
+        .collect::<Vec<_>>();
+    names.sort_unstable();
+    names
+}
+
+/// Queries the aggregator until the snapshot satisfies the predicate, as the updates are processed asynchronously.
+fn wait_for(name: &str, predicate: impl Fn(&Snapshot) -> bool) -> Snapshot {
+    let query = MetricsAgent::query().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let snapshot = query.find(name, &[]).unwrap();
+        if predicate(&snapshot) || Instant::now() > deadline {
+            return snapshot;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn should_query_metrics_by_name_and_tags() {
+    assert!(MetricsAgent::query().is_err());
+
+    let config = MetricsConfig {
+        flush_interval: Duration::from_secs(3600),
+        ..MetricsConfig::default()
+    };
+    MetricsAgent::init_with_config(config).unwrap();
+    let xnas = Counter::new("orders", &[("venue", "xnas")]);
+    let arcx = Counter::new("orders", &[("venue", "arcx")]);
+    let latency = Histogram::new("order_to_ack", &[("venue", "xnas")]);
+    xnas.increment_by(2);
+    arcx.increment_by(3);
+    for value in [10, 20, 30] {
+        latency.record(value);
+    }
+
+    // the interval window is visible before the first flush
+    let snapshot = wait_for("order_to_ack", |snapshot| {
+        snapshot
+            .histograms
+            .first()
+            .is_some_and(|histogram| histogram.stats.count == 3)
+    });
+    let histogram = &snapshot.histograms[0];
+    assert_eq!(None, histogram.window);
+    assert_eq!(
+        (3, 10, 30, 20.0),
+        (histogram.stats.count, histogram.stats.min, histogram.stats.max, histogram.stats.mean)
+    );
+
+    let query = MetricsAgent::query().unwrap();
+    let snapshot = query.find("orders", &[("venue", "arcx")]).unwrap();
+    assert_eq!(1, snapshot.counters.len());
+    assert_eq!(3, snapshot.counters[0].value);
+    assert!(snapshot.histograms.is_empty());
+
+    let snapshot = query.find("orders", &[]).unwrap();
+    assert_eq!(vec!["orders", "orders"], names(&snapshot));
+    let snapshot = query.snapshot().unwrap();
+    assert!(snapshot.timestamp > 0);
+    assert_eq!(vec!["order_to_ack", "orders", "orders"], names(&snapshot));
+}
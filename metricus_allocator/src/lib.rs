@@ -1,6 +1,7 @@
 #![doc = include_str!("../README.md")]
 
-use metricus::{Counter, CounterOps, Id, PreAllocatedMetric};
+use log::warn;
+use metricus::{Counter, CounterOps, Id, IdRange, IdRangeError, PreAllocatedMetric, reserve_ids};
 use std::alloc::{GlobalAlloc, Layout};
 use std::cell::Cell;
 use std::sync::LazyLock;
@@ -77,14 +78,30 @@ unsafe impl GlobalAlloc for CountingAllocator {
 }
 
 impl CountingAllocator {
-    /// Default counters to be used with the `CountingAllocator`.
+    /// Range of reserved ids used by the allocator counters.
+    pub const ID_RANGE: IdRange = IdRange::new(ALLOC_COUNTER_ID, DEALLOC_BYTES_COUNTER_ID);
+
+    /// Default counters to be used with the `CountingAllocator`. Their ids are claimed with [reserve_ids], and no
+    /// counters are returned (with a warning) if another library has already reserved ids within [Self::ID_RANGE],
+    /// so that the allocations are not reported as the metrics of the other library. Use [Self::try_metrics] to
+    /// handle the conflict instead.
     pub fn metrics() -> Vec<PreAllocatedMetric> {
-        vec![
+        Self::try_metrics().unwrap_or_else(|e| {
+            warn!("allocator metrics are disabled: {e}");
+            Vec::new()
+        })
+    }
+
+    /// Default counters to be used with the `CountingAllocator`, failing if another library has already reserved
+    /// ids within [Self::ID_RANGE].
+    pub fn try_metrics() -> Result<Vec<PreAllocatedMetric>, IdRangeError> {
+        reserve_ids("metricus_allocator", Self::ID_RANGE)?;
+        Ok(vec![
             PreAllocatedMetric::counter("global_allocator", ALLOC_COUNTER_ID, &[("fn_name", "alloc")]),
             PreAllocatedMetric::counter("global_allocator", ALLOC_BYTES_COUNTER_ID, &[("fn_name", "alloc_bytes")]),
             PreAllocatedMetric::counter("global_allocator", DEALLOC_COUNTER_ID, &[("fn_name", "dealloc")]),
             PreAllocatedMetric::counter("global_allocator", DEALLOC_BYTES_COUNTER_ID, &[("fn_name", "dealloc_bytes")]),
-        ]
+        ])
     }
 }
 
@@ -148,3 +165,23 @@ struct Counters {
     dealloc_count: Counter,
     dealloc_bytes: Counter,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_not_report_allocations_when_ids_are_taken() {
+        let start = CountingAllocator::ID_RANGE.start();
+        reserve_ids("other", IdRange::new(start, start)).unwrap();
+
+        assert!(matches!(
+            CountingAllocator::try_metrics(),
+            Err(IdRangeError::Overlapping {
+                existing_owner: "other",
+                ..
+            })
+        ));
+        assert!(CountingAllocator::metrics().is_empty());
+    }
+}
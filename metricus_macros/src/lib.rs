@@ -145,7 +145,8 @@ pub fn counter(attr: TokenStream, item: TokenStream) -> TokenStream {
 /// registration until the backend has been registered.
 ///
 /// This macro accepts either `u64` value that represents counter `id` or the name of a const function that returns the id
-/// of the counter to be created.
+/// of the counter to be created. The id must come from the `metricus::RESERVED_IDS` range, excluding its last id which
+/// backends assign to the metrics they drop, which is checked at compile time for integer literals.
 ///
 /// ## Examples
 ///
@@ -154,7 +155,8 @@ pub fn counter(attr: TokenStream, item: TokenStream) -> TokenStream {
 /// ```ignore
 /// use metricus_macros::counter_with_id;
 ///
-/// #[counter_with_id(id = 100)]
+/// // RESERVED_IDS.start() + 100
+/// #[counter_with_id(id = 18446744069414584420)]
 /// fn my_function() {
 ///     // function body
 /// }
@@ -163,10 +165,11 @@ pub fn counter(attr: TokenStream, item: TokenStream) -> TokenStream {
 /// Using const expression as id.
 ///
 /// ```ignore
+/// use metricus::{Id, RESERVED_IDS};
 /// use metricus_macros::counter_with_id;
 ///
-/// const fn get_counter_id() -> CounterId {
-///     100
+/// const fn get_counter_id() -> Id {
+///     RESERVED_IDS.start() + 100
 /// }
 ///
 /// #[counter_with_id(id = "get_counter_id")]
@@ -206,12 +209,24 @@ pub fn counter_with_id(attr: TokenStream, item: TokenStream) -> TokenStream {
     }
 
     // Ensure counter_id field is provided
-    let counter_id = match counter_id_value {
-        Some(id_int) => quote! { #id_int },
+    let (counter_id, id_check) = match counter_id_value {
+        Some(id_int) => {
+            if let Err(e) = id_int.base10_parse::<u64>() {
+                return TokenStream::from(e.to_compile_error());
+            }
+            // the range is only known to the metricus crate, so it is checked once the generated code is compiled
+            let check = quote! {
+                const _: () = assert!(
+                    metricus::RESERVED_IDS.contains(#id_int) && #id_int != metricus::RESERVED_IDS.end(),
+                    "counter_with_id: id must be within metricus::RESERVED_IDS, excluding its last id"
+                );
+            };
+            (quote! { #id_int }, check)
+        }
         None => match counter_id_fn {
             Some(f) => {
                 let getter_fn = Ident::new(f.as_str(), Span::call_site());
-                quote! { #getter_fn() }
+                (quote! { #getter_fn() }, quote! {})
             }
             None => {
                 return TokenStream::from(
@@ -235,6 +250,7 @@ pub fn counter_with_id(attr: TokenStream, item: TokenStream) -> TokenStream {
         #(#attrs)*
         #fn_vis #fn_async #fn_unsafe fn #fn_name #fn_generics (#fn_args) #fn_output #fn_where_clause {
 
+            #id_check
             static mut COUNTER: core::cell::LazyCell<core::cell::UnsafeCell<metricus::Counter>> = core::cell::LazyCell::new(|| core::cell::UnsafeCell::new(metricus::Counter::new_with_id(#counter_id)));
             #[allow(static_mut_refs)]
             unsafe { metricus::CounterOps::increment(&COUNTER); }
@@ -276,6 +292,44 @@ pub fn counter_with_id(attr: TokenStream, item: TokenStream) -> TokenStream {
 ///     // function body
 /// }
 /// ```
+///
+/// By default, a span on an `async fn` measures the whole call including the time the future spent parked.
+/// The `async_mode` option records the wall time (`"wall"`), the time spent being polled (`"busy"`), or both
+/// (`"both"`) into histograms tagged with `timing = "wall"` or `timing = "busy"`.
+///
+/// ```ignore
+/// use metrics_macros::span;
+///
+/// #[span(measurement = "latencies", async_mode = "both")]
+/// async fn my_async_function() {
+///     // function body
+/// }
+/// ```
+///
+/// The `split_on_result` option records the duration of a function returning a `Result` into histograms tagged
+/// with `outcome = "ok"` or `outcome = "err"`, so that failures do not skew the latency of successful calls.
+///
+/// ```ignore
+/// use metrics_macros::span;
+///
+/// #[span(measurement = "latencies", split_on_result)]
+/// fn my_fallible_function() -> Result<(), String> {
+///     // function body
+///     Ok(())
+/// }
+/// ```
+///
+/// The `sample_rate` option records only 1 in N calls, for functions too hot to record every call. It can be
+/// combined with the other options.
+///
+/// ```ignore
+/// use metrics_macros::span;
+///
+/// #[span(measurement = "latencies", sample_rate = 100)]
+/// fn my_hot_function() {
+///     // function body
+/// }
+/// ```
 #[proc_macro_attribute]
 pub fn span(attr: TokenStream, item: TokenStream) -> TokenStream {
     let args = parse_macro_input!(attr as AttributeArgs);
@@ -284,6 +338,9 @@ pub fn span(attr: TokenStream, item: TokenStream) -> TokenStream {
 
     // Initialize variables to hold parsed values
     let mut measurement = None;
+    let mut async_mode = None;
+    let mut split_on_result = false;
+    let mut sample_rate = None;
     let mut tags = Vec::new();
 
     // auto include method name
@@ -304,6 +361,50 @@ pub fn span(attr: TokenStream, item: TokenStream) -> TokenStream {
             })) if path.is_ident("measurement") => {
                 measurement = Some(value.value());
             }
+            NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                ref path,
+                lit: Lit::Str(ref value),
+                ..
+            })) if path.is_ident("async_mode") => {
+                if !matches!(value.value().as_str(), "wall" | "busy" | "both") {
+                    return TokenStream::from(
+                        syn::Error::new_spanned(value, "Expected one of 'wall', 'busy' or 'both' for async_mode")
+                            .to_compile_error(),
+                    );
+                }
+                if input_fn.sig.asyncness.is_none() {
+                    return TokenStream::from(
+                        syn::Error::new_spanned(&input_fn.sig, "The 'async_mode' option requires an async fn")
+                            .to_compile_error(),
+                    );
+                }
+                async_mode = Some(value.value());
+            }
+            NestedMeta::Meta(Meta::Path(ref path)) if path.is_ident("split_on_result") => {
+                if input_fn.sig.asyncness.is_some() || !returns_result(&input_fn.sig.output) {
+                    return TokenStream::from(
+                        syn::Error::new_spanned(
+                            &input_fn.sig,
+                            "#[span(split_on_result)] requires a non-async fn returning a Result",
+                        )
+                        .to_compile_error(),
+                    );
+                }
+                split_on_result = true;
+            }
+            NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                ref path,
+                lit: Lit::Int(ref value),
+                ..
+            })) if path.is_ident("sample_rate") => match value.base10_parse::<u32>() {
+                Ok(rate) if rate > 0 => sample_rate = Some(rate),
+                _ => {
+                    return TokenStream::from(
+                        syn::Error::new_spanned(value, "Expected a positive integer for sample_rate")
+                            .to_compile_error(),
+                    );
+                }
+            },
             NestedMeta::Meta(Meta::List(MetaList {
                 ref path, ref nested, ..
             })) if path.is_ident("tags") => {
@@ -329,6 +430,16 @@ pub fn span(attr: TokenStream, item: TokenStream) -> TokenStream {
     // Ensure consistent ordering of tags
     tags.sort_unstable_by(|(k1, _), (k2, _)| k1.cmp(k2));
 
+    // tags of the wall and busy histograms used with async_mode
+    let timing_tags = |timing: &str| {
+        let mut tags = tags.clone();
+        tags.push(("timing".to_string(), timing.to_string()));
+        tags.sort_unstable_by(|(k1, _), (k2, _)| k1.cmp(k2));
+        tags.into_iter().map(|(k, v)| quote! { (#k, #v) }).collect::<Vec<_>>()
+    };
+    let wall_tags = timing_tags("wall");
+    let busy_tags = timing_tags("busy");
+
     let tags: Vec<(&str, &str)> = tags.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
     let tags = tags.into_iter().map(|(k, v)| {
         // Directly quote each tuple
@@ -347,6 +458,12 @@ pub fn span(attr: TokenStream, item: TokenStream) -> TokenStream {
 
     let measurement = measurement.as_str();
 
+    // sampled histograms are created with their sample rate as the last argument
+    let (histogram_new, sample_rate) = match sample_rate {
+        Some(rate) => (quote! { with_sample_rate }, quote! { , #rate }),
+        None => (quote! { new }, quote! {}),
+    };
+
     // Reconstruct the original function and inject the histogram span
     let fn_body = &input_fn.block.stmts;
     let fn_vis = &input_fn.vis;
@@ -358,11 +475,59 @@ pub fn span(attr: TokenStream, item: TokenStream) -> TokenStream {
     let fn_where_clause = &input_fn.sig.generics.where_clause;
     let attrs = &input_fn.attrs;
 
+    if split_on_result {
+        let generated = quote! {
+            #(#attrs)*
+            #fn_vis #fn_async #fn_unsafe fn #fn_name #fn_generics (#fn_args) #fn_output #fn_where_clause {
+
+                static mut HISTOGRAM: core::cell::LazyCell<core::cell::UnsafeCell<metricus::OutcomeHistogram>> = core::cell::LazyCell::new(|| core::cell::UnsafeCell::new(metricus::OutcomeHistogram::#histogram_new(#measurement, &[ #(#tags),* ] #sample_rate)));
+                #[allow(static_mut_refs)]
+                let span = unsafe { &*HISTOGRAM.get() }.span();
+                // the closure catches the early returns, its return type is inferred from the function so that the
+                // result may hold an `impl Trait`
+                #[allow(clippy::redundant_closure_call)]
+                let result = (|| { #( #fn_body )* })();
+                span.finish(&result);
+                result
+            }
+        };
+        return generated.into();
+    }
+
+    if let Some(async_mode) = async_mode {
+        let histogram = |name: &str, tags: &[proc_macro2::TokenStream]| {
+            let static_name = Ident::new(&name.to_uppercase(), Span::call_site());
+            let name = Ident::new(name, Span::call_site());
+            quote! {
+                static mut #static_name: core::cell::LazyCell<core::cell::UnsafeCell<metricus::Histogram>> = core::cell::LazyCell::new(|| core::cell::UnsafeCell::new(metricus::Histogram::#histogram_new(#measurement, &[ #(#tags),* ] #sample_rate)));
+                #[allow(static_mut_refs)]
+                let #name: &'static metricus::Histogram = unsafe { &*#static_name.get() };
+            }
+        };
+        let (wall, wall_histogram) = match async_mode.as_str() {
+            "busy" => (quote! { None }, quote! {}),
+            _ => (quote! { Some(wall_histogram) }, histogram("wall_histogram", &wall_tags)),
+        };
+        let (busy, busy_histogram) = match async_mode.as_str() {
+            "wall" => (quote! { None }, quote! {}),
+            _ => (quote! { Some(busy_histogram) }, histogram("busy_histogram", &busy_tags)),
+        };
+        let generated = quote! {
+            #(#attrs)*
+            #fn_vis #fn_async #fn_unsafe fn #fn_name #fn_generics (#fn_args) #fn_output #fn_where_clause {
+                #wall_histogram
+                #busy_histogram
+                metricus::TimedFuture::new(async move { #( #fn_body )* }, #wall, #busy).await
+            }
+        };
+        return generated.into();
+    }
+
     let generated = quote! {
         #(#attrs)*
         #fn_vis #fn_async #fn_unsafe fn #fn_name #fn_generics (#fn_args) #fn_output #fn_where_clause {
 
-            static mut HISTOGRAM: core::cell::LazyCell<core::cell::UnsafeCell<metricus::Histogram>> = core::cell::LazyCell::new(|| core::cell::UnsafeCell::new(metricus::Histogram::new(#measurement, &[ #(#tags),* ])));
+            static mut HISTOGRAM: core::cell::LazyCell<core::cell::UnsafeCell<metricus::Histogram>> = core::cell::LazyCell::new(|| core::cell::UnsafeCell::new(metricus::Histogram::#histogram_new(#measurement, &[ #(#tags),* ] #sample_rate)));
             #[allow(static_mut_refs)]
             let _span = unsafe { metricus::HistogramOps::span(&HISTOGRAM) };
 
@@ -372,3 +537,18 @@ pub fn span(attr: TokenStream, item: TokenStream) -> TokenStream {
 
     generated.into()
 }
+
+/// Whether the function returns a `Result`, including aliases such as `io::Result`.
+fn returns_result(output: &syn::ReturnType) -> bool {
+    match output {
+        syn::ReturnType::Type(_, ty) => match ty.as_ref() {
+            syn::Type::Path(path) => path
+                .path
+                .segments
+                .last()
+                .is_some_and(|segment| segment.ident == "Result"),
+            _ => false,
+        },
+        syn::ReturnType::Default => false,
+    }
+}
@@ -0,0 +1,18 @@
+//! Receives the metrics exported by other processes on this host, merges them and re-exports them.
+//!
+//! Usage: `metricus-collector <config.yml>`
+
+use anyhow::Context;
+use metricus_agent::collector::Collector;
+use metricus_agent::config::MetricsConfig;
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    let path = std::env::args()
+        .nth(1)
+        .context("usage: metricus-collector <config.yml>")?;
+    let config = MetricsConfig::from_file(&path).with_context(|| format!("unable to read config from {path}"))?;
+    Collector::new(config)?.run()?;
+    Ok(())
+}
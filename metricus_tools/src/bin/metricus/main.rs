@@ -0,0 +1,32 @@
+//! Command line tools to inspect the exported metrics.
+//!
+//! Usage: `metricus tail --udp 127.0.0.1:8777`
+
+mod tail;
+
+use clap::{Parser, Subcommand};
+
+#[derive(Parser)]
+#[command(
+    name = "metricus",
+    version,
+    about = "Inspect the metrics exported by the metricus agent"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the live metrics received from an exporter in a table.
+    Tail(tail::TailArgs),
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    match Cli::parse().command {
+        Command::Tail(args) => tail::run(args),
+    }
+}
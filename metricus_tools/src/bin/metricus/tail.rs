@@ -0,0 +1,424 @@
+//! Receives the metrics from an exporter and periodically shows the latest counter values and rates along with the
+//! histogram percentiles in a table.
+
+use anyhow::Context;
+use clap::{ArgGroup, Args, ValueEnum};
+use log::warn;
+use metricus_agent::collector::remove_stale_socket;
+use metricus_agent::decoder::{CounterPoint, HistogramPoint, Point, decode_json, decode_line_protocol};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::net::UdpSocket;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+/// Largest datagram that can be received.
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+/// How often the file is checked for new lines once its end has been reached.
+const FILE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("source").required(true).args(["udp", "file", "unix"])))]
+pub struct TailArgs {
+    /// Address of the udp socket to listen on, e.g. `127.0.0.1:8777`.
+    #[arg(long)]
+    udp: Option<String>,
+    /// File written by the file exporter, which is followed as it grows.
+    #[arg(long)]
+    file: Option<PathBuf>,
+    /// Path of the unix datagram socket to listen on.
+    #[arg(long)]
+    unix: Option<PathBuf>,
+    /// Encoder used by the exporter.
+    #[arg(long, value_enum, default_value_t = Format::LineProtocol)]
+    format: Format,
+    /// Only show the metrics whose name matches the regex.
+    #[arg(long)]
+    name: Option<Regex>,
+    /// Only show the metrics with the given `key=value` tag, can be repeated.
+    #[arg(long = "tag", value_parser = parse_tag)]
+    tags: Vec<(String, String)>,
+    /// Interval at which the table is refreshed, e.g. `500ms`.
+    #[arg(long, default_value = "1s", value_parser = parse_duration)]
+    refresh: Duration,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    #[value(alias = "lp")]
+    LineProtocol,
+    Json,
+}
+
+impl Format {
+    fn decode(&self, line: &str) -> metricus_agent::Result<Point> {
+        match self {
+            Format::LineProtocol => decode_line_protocol(line),
+            Format::Json => decode_json(line),
+        }
+    }
+}
+
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    duration_str::parse(duration)
+}
+
+fn parse_tag(tag: &str) -> Result<(String, String), String> {
+    tag.split_once('=')
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .ok_or_else(|| format!("expected key=value, got {tag}"))
+}
+
+pub fn run(args: TailArgs) -> anyhow::Result<()> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let source = if let Some(address) = &args.udp {
+        let socket = UdpSocket::bind(address).with_context(|| format!("unable to listen on udp {address}"))?;
+        spawn_source(tx, move |tx| receive_datagrams(|buffer| socket.recv(buffer), tx));
+        format!("udp {address}")
+    } else if let Some(path) = &args.unix {
+        let socket = UnixDatagram::bind(remove_stale_socket(path)?)
+            .with_context(|| format!("unable to listen on unix datagram socket {}", path.display()))?;
+        spawn_source(tx, move |tx| receive_datagrams(|buffer| socket.recv(buffer), tx));
+        format!("unix datagram socket {}", path.display())
+    } else if let Some(path) = &args.file {
+        let file = File::open(path).with_context(|| format!("unable to open {}", path.display()))?;
+        let source = format!("file {}", path.display());
+        let path = path.clone();
+        spawn_source(tx, move |tx| follow_file(&path, file, tx));
+        source
+    } else {
+        unreachable!("source is required");
+    };
+
+    let mut table = Table::default();
+    let mut next_refresh = Instant::now() + args.refresh;
+    loop {
+        match rx.recv_timeout(next_refresh.saturating_duration_since(Instant::now())) {
+            Ok(lines) => {
+                for line in lines?.lines().filter(|line| !line.trim().is_empty()) {
+                    match args.format.decode(line) {
+                        Ok(point) if args.matches(&point) => table.update(point),
+                        Ok(_) => {}
+                        Err(e) => warn!("unable to decode [{line}]: {e}"),
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        if Instant::now() >= next_refresh {
+            if table.updated {
+                table.render(&source, &mut std::io::stdout().lock())?;
+            }
+            next_refresh = Instant::now() + args.refresh;
+        }
+    }
+}
+
+impl TailArgs {
+    fn matches(&self, point: &Point) -> bool {
+        self.name.as_ref().is_none_or(|name| name.is_match(point.name()))
+            && self.tags.iter().all(|tag| point.tags().contains(tag))
+    }
+}
+
+/// Reads the source on a background thread, which forwards the received lines.
+fn spawn_source<F>(tx: Sender<std::io::Result<String>>, read: F)
+where
+    F: FnOnce(&Sender<std::io::Result<String>>) -> std::io::Result<()> + Send + 'static,
+{
+    std::thread::spawn(move || {
+        if let Err(e) = read(&tx) {
+            let _ = tx.send(Err(e));
+        }
+    });
+}
+
+fn receive_datagrams(
+    mut recv: impl FnMut(&mut [u8]) -> std::io::Result<usize>,
+    tx: &Sender<std::io::Result<String>>,
+) -> std::io::Result<()> {
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        let len = recv(&mut buffer)?;
+        if tx
+            .send(Ok(String::from_utf8_lossy(&buffer[..len]).into_owned()))
+            .is_err()
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// Forwards the complete lines of the file as it grows, starting over when it is truncated (i.e. the exporter
+/// has been restarted).
+fn follow_file(path: &Path, file: File, tx: &Sender<std::io::Result<String>>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(file);
+    let mut position = 0;
+    let mut line = String::new();
+    loop {
+        let read = reader.read_line(&mut line)?;
+        position += read as u64;
+        if line.ends_with('\n') {
+            if tx.send(Ok(std::mem::take(&mut line))).is_err() {
+                return Ok(());
+            }
+        } else if read == 0 {
+            std::thread::sleep(FILE_POLL_INTERVAL);
+            if std::fs::metadata(path)?.len() < position {
+                reader = BufReader::new(File::open(path)?);
+                position = 0;
+                line.clear();
+            }
+        }
+    }
+}
+
+/// Measurement name and tags, excluding the `type` tag which is implied by the table.
+type Series = (String, String);
+
+fn series(name: &str, tags: &[(String, String)]) -> Series {
+    let tags = tags
+        .iter()
+        .filter(|(key, _)| key != "type")
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join(",");
+    (name.to_owned(), tags)
+}
+
+struct CounterRow {
+    value: u64,
+    timestamp: u64,
+    /// Increase per second between the last two flushes, unknown until the second flush or after a restart.
+    rate: Option<f64>,
+}
+
+#[derive(Default)]
+struct Table {
+    counters: BTreeMap<Series, CounterRow>,
+    /// Latest point of each histogram window.
+    histograms: BTreeMap<(Series, Option<String>), HistogramPoint>,
+    /// Table has changed since it was last shown.
+    updated: bool,
+}
+
+impl Table {
+    fn update(&mut self, point: Point) {
+        match point {
+            Point::Counter(CounterPoint {
+                name,
+                tags,
+                value,
+                timestamp,
+            }) => {
+                let series = series(&name, &tags);
+                let rate = self.counters.get(&series).and_then(|last| {
+                    let elapsed = timestamp.checked_sub(last.timestamp).filter(|elapsed| *elapsed > 0)?;
+                    let delta = value.checked_sub(last.value)?;
+                    Some(delta as f64 * 1e9 / elapsed as f64)
+                });
+                let row = self.counters.entry(series).or_insert(CounterRow {
+                    value,
+                    timestamp,
+                    rate: None,
+                });
+                // several points of the same flush leave the rate unchanged
+                if timestamp != row.timestamp {
+                    row.rate = rate;
+                }
+                row.value = value;
+                row.timestamp = timestamp;
+            }
+            Point::Histogram(histogram) => {
+                let series = series(&histogram.name, &histogram.tags);
+                self.histograms.insert((series, histogram.window.clone()), histogram);
+            }
+        }
+        self.updated = true;
+    }
+
+    fn render(&mut self, source: &str, out: &mut impl Write) -> std::io::Result<()> {
+        if std::io::stdout().is_terminal() {
+            // clear the screen and move the cursor to the top left corner
+            write!(out, "\x1b[2J\x1b[H")?;
+        }
+        writeln!(out, "metricus tail: {source}")?;
+        writeln!(out)?;
+        let counters = self
+            .counters
+            .iter()
+            .map(|((name, tags), row)| {
+                vec![
+                    name.clone(),
+                    tags.clone(),
+                    row.value.to_string(),
+                    row.rate.map_or_else(|| "-".to_owned(), |rate| format!("{rate:.1}")),
+                ]
+            })
+            .collect::<Vec<_>>();
+        write_table(out, &["COUNTER", "TAGS", "VALUE", "RATE/S"], &counters)?;
+        writeln!(out)?;
+        let histograms = self
+            .histograms
+            .iter()
+            .map(|(((name, tags), window), histogram)| {
+                let stats = &histogram.stats;
+                // the count of a sampled histogram is scaled up to estimate the number of values
+                let count = match histogram.sample_rate {
+                    1 => stats.count.to_string(),
+                    sample_rate => format!("~{}", stats.count.saturating_mul(sample_rate as u64)),
+                };
+                vec![
+                    name.clone(),
+                    tags.clone(),
+                    window.clone().unwrap_or_else(|| "interval".to_owned()),
+                    count,
+                    stats.min.to_string(),
+                    stats.p50.to_string(),
+                    stats.p90.to_string(),
+                    stats.p99.to_string(),
+                    stats.p999.to_string(),
+                    stats.max.to_string(),
+                ]
+            })
+            .collect::<Vec<_>>();
+        write_table(
+            out,
+            &[
+                "HISTOGRAM",
+                "TAGS",
+                "WINDOW",
+                "COUNT",
+                "MIN",
+                "P50",
+                "P90",
+                "P99",
+                "P99.9",
+                "MAX",
+            ],
+            &histograms,
+        )?;
+        out.flush()?;
+        self.updated = false;
+        Ok(())
+    }
+}
+
+/// Writes the rows in aligned columns, the text columns (name, tags and window) aligned left and the numeric columns
+/// aligned right.
+fn write_table(out: &mut impl Write, headers: &[&str], rows: &[Vec<String>]) -> std::io::Result<()> {
+    let widths = headers
+        .iter()
+        .enumerate()
+        .map(|(column, header)| {
+            rows.iter()
+                .map(|row| row[column].chars().count())
+                .chain([header.len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+    let text_columns = headers
+        .iter()
+        .take_while(|header| !matches!(**header, "VALUE" | "COUNT"))
+        .count();
+    let headers = headers.iter().map(|header| header.to_string()).collect::<Vec<_>>();
+    for row in [&headers].into_iter().chain(rows) {
+        let mut line = String::new();
+        for (column, (cell, width)) in row.iter().zip(&widths).enumerate() {
+            if column > 0 {
+                line.push_str("  ");
+            }
+            match column < text_columns {
+                true => line.push_str(&format!("{cell:<width$}")),
+                false => line.push_str(&format!("{cell:>width$}")),
+            }
+        }
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter(value: u64, timestamp: u64) -> Point {
+        Point::Counter(CounterPoint {
+            name: "requests".to_owned(),
+            tags: vec![
+                ("venue".to_owned(), "xnas".to_owned()),
+                ("type".to_owned(), "counter".to_owned()),
+            ],
+            value,
+            timestamp,
+        })
+    }
+
+    fn rate(table: &Table) -> Option<f64> {
+        table.counters[&("requests".to_owned(), "venue=xnas".to_owned())].rate
+    }
+
+    fn args(name: Option<&str>, tags: &[&str]) -> TailArgs {
+        TailArgs {
+            udp: Some("127.0.0.1:0".to_owned()),
+            file: None,
+            unix: None,
+            format: Format::LineProtocol,
+            name: name.map(|name| Regex::new(name).unwrap()),
+            tags: tags.iter().map(|tag| parse_tag(tag).unwrap()).collect(),
+            refresh: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn should_compute_counter_rate_between_flushes() {
+        let mut table = Table::default();
+        table.update(counter(10, 1_000_000_000));
+        assert_eq!(None, rate(&table));
+
+        table.update(counter(30, 3_000_000_000));
+        assert_eq!(Some(10.0), rate(&table));
+        // another point of the same flush leaves the rate unchanged
+        table.update(counter(30, 3_000_000_000));
+        assert_eq!(Some(10.0), rate(&table));
+
+        // the total going backwards means that the exporter has restarted
+        table.update(counter(5, 4_000_000_000));
+        assert_eq!(None, rate(&table));
+        table.update(counter(7, 4_500_000_000));
+        assert_eq!(Some(4.0), rate(&table));
+    }
+
+    #[test]
+    fn should_filter_by_name_and_tags() {
+        let point = counter(1, 0);
+        assert!(args(None, &[]).matches(&point));
+        assert!(args(Some("^req"), &["venue=xnas"]).matches(&point));
+        assert!(!args(Some("^orders$"), &[]).matches(&point));
+        assert!(!args(None, &["venue=xnas", "desk=us"]).matches(&point));
+        assert!(!args(None, &["venue=arcx"]).matches(&point));
+        assert!(parse_tag("venue").is_err());
+    }
+
+    #[test]
+    fn should_align_text_columns_left_and_numbers_right() {
+        let mut out = Vec::new();
+        let rows = vec![
+            vec!["requests".to_owned(), "venue=xnas".to_owned(), "5".to_owned()],
+            vec!["orders".to_owned(), String::new(), "1234".to_owned()],
+        ];
+        write_table(&mut out, &["COUNTER", "TAGS", "VALUE"], &rows).unwrap();
+        assert_eq!(
+            "COUNTER   TAGS        VALUE\n\
+             requests  venue=xnas      5\n\
+             orders                 1234\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
@@ -0,0 +1,18 @@
+//! Aggregates and exports the metrics written into the shared memory segment by another process.
+//!
+//! Usage: `metricus-sidecar <config.yml>`
+
+use anyhow::Context;
+use metricus_agent::config::MetricsConfig;
+use metricus_agent::sidecar::Sidecar;
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    let path = std::env::args()
+        .nth(1)
+        .context("usage: metricus-sidecar <config.yml>")?;
+    let config = MetricsConfig::from_file(&path).with_context(|| format!("unable to read config from {path}"))?;
+    Sidecar::new(config)?.run()?;
+    Ok(())
+}
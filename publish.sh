@@ -6,3 +6,4 @@ cargo publish -p metricus_macros
 cargo publish -p metricus
 cargo publish -p metricus_allocator
 cargo publish -p metricus_agent
+cargo publish -p metricus_tools